@@ -1,6 +1,6 @@
-#![cfg(all(not(windows), feature = "static-detour"))]
+#![cfg(not(windows))]
 
-use retour::static_detour;
+use retour::{static_detour, Original};
 use std::ffi::CString;
 use std::os::raw::c_char;
 use std::os::raw::c_int;
@@ -13,9 +13,11 @@ static_detour! {
     static Opentour: unsafe extern "C" fn(*const c_char, c_int) -> c_int;
 }
 
-fn definitely_open(_: *const c_char, _: c_int) -> c_int {
+type FnOpen = unsafe extern "C" fn(*const c_char, c_int) -> c_int;
+
+fn definitely_open(original: Original<FnOpen>, _: *const c_char, _: c_int) -> c_int {
   let cstring = CString::new("/etc/timezone").unwrap();
-  let fd = unsafe { Opentour.call(cstring.as_ptr() as *const c_char, 0) };
+  let fd = unsafe { original.call(cstring.as_ptr() as *const c_char, 0) };
   assert!(fd > 0);
   fd
 }
@@ -23,7 +25,9 @@ fn definitely_open(_: *const c_char, _: c_int) -> c_int {
 #[ctor::ctor]
 fn main() {
   unsafe {
-    Opentour.initialize(open, definitely_open).unwrap();
+    Opentour
+      .initialize_with_original(open, definitely_open)
+      .unwrap();
     Opentour.enable().unwrap();
   }
 }
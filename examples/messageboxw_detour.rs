@@ -1,4 +1,4 @@
-#![cfg(all(windows, feature = "static-detour"))]
+#![cfg(windows)]
 //! A `MessageBoxW` detour example.
 //!
 //! Ensure the crate is compiled as a 'cdylib' library to allow C interop.
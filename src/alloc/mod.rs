@@ -1,33 +1,148 @@
 use crate::error::Result;
-use std::ops::{Deref, DerefMut};
+use crate::{suspend, util};
+use std::mem;
+use std::ops::{Deref, DerefMut, Range};
 use std::sync::{Arc, Mutex};
 
 mod proximity;
 mod search;
 
 /// A thread-safe memory pool for allocating chunks close to addresses.
-pub struct ThreadAllocator(Arc<Mutex<proximity::ProximityAllocator>>);
+pub struct ThreadAllocator {
+  allocator: Arc<Mutex<proximity::ProximityAllocator>>,
+  /// Released memory that may still be executed by other threads.
+  retired: Vec<Retired>,
+}
+
+/// Data used by retired code, which is released along with it.
+pub trait Attachment: Send {
+  /// Returns whether any other thread may still use the data, or execute
+  /// the code.
+  ///
+  /// This may be called whilst other threads are suspended, so it must
+  /// neither allocate nor lock.
+  fn is_in_use(&self) -> bool;
+}
+
+impl Attachment for Vec<Box<dyn Attachment>> {
+  fn is_in_use(&self) -> bool {
+    self.iter().any(|attachment| attachment.is_in_use())
+  }
+}
+
+/// Released memory, and any data used by its code.
+struct Retired {
+  memory: ExecutableMemory,
+  attachment: Option<Box<dyn Attachment>>,
+}
+
+impl Retired {
+  /// Returns whether the memory may still be executed, or its data used.
+  fn is_in_use(&self, suspension: Option<&suspend::Suspension>) -> bool {
+    let is_executing = suspension.map_or(false, |suspension| {
+      suspension.is_executing(&self.memory.range())
+    });
+
+    is_executing
+      || self
+        .attachment
+        .as_ref()
+        .map_or(false, |attachment| attachment.is_in_use())
+  }
+}
 
 // TODO: Decrease use of mutexes
 impl ThreadAllocator {
   /// Creates a new proximity memory allocator.
   pub fn new(max_distance: usize) -> Self {
-    ThreadAllocator(Arc::new(Mutex::new(proximity::ProximityAllocator {
-      max_distance,
-      pools: Vec::new(),
-    })))
+    ThreadAllocator {
+      allocator: Arc::new(Mutex::new(proximity::ProximityAllocator {
+        max_distance,
+        pools: Vec::new(),
+      })),
+      retired: Vec::new(),
+    }
   }
 
   /// Allocates read-, write- & executable memory close to `origin`.
   pub fn allocate(&self, origin: *const (), size: usize) -> Result<ExecutableMemory> {
-    let mut allocator = self.0.lock().unwrap();
+    let mut allocator = util::lock(&self.allocator);
     allocator
       .allocate(origin, size)
       .map(|data| ExecutableMemory {
-        allocator: self.0.clone(),
+        allocator: self.allocator.clone(),
         data,
       })
   }
+
+  /// Allocates read-, write- & executable memory anywhere.
+  pub fn allocate_anywhere(&self, size: usize) -> Result<ExecutableMemory> {
+    let mut allocator = util::lock(&self.allocator);
+    allocator
+      .allocate_anywhere(size)
+      .map(|data| ExecutableMemory {
+        allocator: self.allocator.clone(),
+        data,
+      })
+  }
+
+  /// Defers the release of memory that may still be executed by other
+  /// threads, until it is reclaimed.
+  pub fn retire(&mut self, memory: ExecutableMemory) {
+    self.retired.push(Retired {
+      memory,
+      attachment: None,
+    });
+  }
+
+  /// Defers the release of memory along with data used by its code, until
+  /// neither is in use.
+  pub fn retire_with(&mut self, memory: ExecutableMemory, attachment: Box<dyn Attachment>) {
+    self.retired.push(Retired {
+      memory,
+      attachment: Some(attachment),
+    });
+  }
+
+  /// Never releases memory, for code that threads may return into.
+  pub fn leak(&mut self, memory: ExecutableMemory) {
+    mem::forget(memory);
+  }
+
+  /// Releases all retired memory that is no longer being executed, nor has
+  /// its data in use.
+  ///
+  /// Memory is in use as long as its attachment reports so (e.g calls in
+  /// flight through a relay). If thread suspension is enabled, other threads
+  /// are also briefly suspended to sample their instruction pointers, which
+  /// covers a thread that has just jumped to a relay. If suspending fails,
+  /// all memory is kept until the next attempt.
+  pub fn reclaim(&mut self) {
+    if self.retired.is_empty() {
+      return;
+    }
+
+    // No memory may be allocated whilst other threads are suspended
+    let mut in_use = vec![false; self.retired.len()];
+
+    match suspend::suspend() {
+      Ok(Some(suspension)) => {
+        for (in_use, retired) in in_use.iter_mut().zip(&self.retired) {
+          *in_use = retired.is_in_use(Some(&suspension));
+        }
+        suspension.resume(&[]);
+      },
+      Ok(None) => {
+        for (in_use, retired) in in_use.iter_mut().zip(&self.retired) {
+          *in_use = retired.is_in_use(None);
+        }
+      },
+      Err(_) => return,
+    }
+
+    let mut in_use = in_use.into_iter();
+    self.retired.retain(|_| in_use.next().unwrap_or(true));
+  }
 }
 
 /// A handle for allocated proximity memory.
@@ -36,10 +151,18 @@ pub struct ExecutableMemory {
   data: proximity::Allocation,
 }
 
+impl ExecutableMemory {
+  /// Returns the address range of the memory.
+  pub fn range(&self) -> Range<usize> {
+    let start = self.as_ptr() as usize;
+    start..start + self.len()
+  }
+}
+
 impl Drop for ExecutableMemory {
   fn drop(&mut self) {
     // Release the associated memory map (if unique)
-    self.allocator.lock().unwrap().release(&self.data);
+    util::lock(&self.allocator).release(&self.data);
   }
 }
 
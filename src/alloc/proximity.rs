@@ -24,12 +24,24 @@ impl ProximityAllocator {
     // Check if an existing pool can handle the allocation request
     self.allocate_memory(&memory_range, size).or_else(|_| {
       // ... otherwise allocate a pool within the memory range
-      self.allocate_pool(&memory_range, origin, size).map(|pool| {
-        // Use the newly allocated pool for the request
-        let allocation = pool.alloc(size).unwrap();
-        self.pools.push(pool);
-        allocation
-      })
+      self
+        .allocate_pool(&memory_range, origin, size)
+        .and_then(|pool| {
+          // Use the newly allocated pool for the request
+          let allocation = pool.alloc(size).ok_or(Error::OutOfMemory)?;
+          self.pools.push(pool);
+          Ok(allocation)
+        })
+    })
+  }
+
+  /// Allocates a slice in any memory map, regardless of its location.
+  pub fn allocate_anywhere(&mut self, size: usize) -> Result<Allocation> {
+    self.allocate_memory(&(0..usize::MAX), size).or_else(|_| {
+      let pool = Self::allocate_mapped_pool(None, size).ok_or(Error::OutOfMemory)?;
+      let allocation = pool.alloc(size).ok_or(Error::OutOfMemory)?;
+      self.pools.push(pool);
+      Ok(allocation)
     })
   }
 
@@ -94,28 +106,29 @@ impl ProximityAllocator {
     after
       .chain(before)
       .filter_map(|result| match result {
-        Ok(address) => Self::allocate_fixed_pool(address, size).map(Ok),
+        Ok(address) => Self::allocate_mapped_pool(Some(address), size).map(Ok),
         Err(error) => Some(Err(error)),
       })
       .next()
       .unwrap_or(Err(Error::OutOfMemory))
   }
 
-  /// Tries to allocate fixed memory at the specified address.
-  fn allocate_fixed_pool(address: *const (), size: usize) -> Option<SlicePool<u8>> {
-    // Try to allocate memory at the specified address
-    mmap::MemoryMap::new(
-      size,
-      &[
-        mmap::MapOption::MapReadable,
-        mmap::MapOption::MapWritable,
-        mmap::MapOption::MapExecutable,
-        mmap::MapOption::MapAddr(address as *const _),
-      ],
-    )
-    .ok()
-    .map(SliceableMemoryMap)
-    .map(SlicePool::new)
+  /// Tries to allocate memory at the specified address, or anywhere if none
+  /// is specified.
+  fn allocate_mapped_pool(address: Option<*const ()>, size: usize) -> Option<SlicePool<u8>> {
+    let mut options = vec![
+      mmap::MapOption::MapReadable,
+      mmap::MapOption::MapWritable,
+      mmap::MapOption::MapExecutable,
+    ];
+    if let Some(address) = address {
+      options.push(mmap::MapOption::MapAddr(address as *const _));
+    }
+
+    mmap::MemoryMap::new(size, &options)
+      .ok()
+      .map(SliceableMemoryMap)
+      .map(SlicePool::new)
   }
 }
 
@@ -61,7 +61,7 @@ impl Iterator for FreeRegionIter {
           // Check whether the region is free, otherwise return the error
           let result = Some(match error {
             region::Error::UnmappedRegion => Ok(self.current as *const _),
-            inner => Err(Error::RegionFailure(inner)),
+            inner => Err(Error::from(inner)),
           });
 
           // Adjust the offset for repeated calls.
@@ -0,0 +1,425 @@
+use super::memory;
+use crate::detours::{Analysis, DetourInfo, DetourOptions, PatchStrategy, PrologInstruction};
+use crate::error::{Error, Result};
+use crate::{alloc, arch, util};
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+use std::mem::ManuallyDrop;
+use std::ops::Range;
+use std::panic::Location;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// All hooked targets, keyed by their address.
+///
+/// When locked along with `memory::POOL`, the pool must be locked first.
+pub static CHAINS: Lazy<Mutex<HashMap<usize, Chain>>> = Lazy::new(Default::default);
+
+/// A detour within a chain.
+pub struct Link {
+  /// The address of the trampoline's slot, which also identifies the link.
+  pub slot: usize,
+  /// The address that the target is redirected to (i.e the detour or relay).
+  pub destination: usize,
+  pub enabled: bool,
+  /// The address of the detour function.
+  pub detour: usize,
+  pub trampoline: Range<usize>,
+  pub relay: Option<Range<usize>>,
+  /// The number of calls in flight through the relay, if they are tracked.
+  pub counter: Option<Arc<AtomicUsize>>,
+  pub label: Option<String>,
+  pub location: &'static Location<'static>,
+}
+
+/// A pending modification of a chain.
+pub struct Update {
+  pub target: usize,
+  /// The new address stored in each link's slot.
+  slots: Vec<(usize, usize)>,
+  /// The new destination of the target and its code, if it has changed.
+  patch: Option<(Option<usize>, Vec<u8>)>,
+  /// Instruction pointer relocations for threads interrupted by the patch.
+  pub relocations: Vec<(usize, usize)>,
+}
+
+impl Update {
+  /// Returns whether the target's code is modified.
+  pub fn is_patch(&self) -> bool {
+    self.patch.is_some()
+  }
+}
+
+/// The ordered detours of a single target.
+///
+/// The target jumps to the most recently created enabled detour. The
+/// trampoline of each link jumps to the next enabled link below it, and
+/// eventually to the original function, so any link can be enabled,
+/// disabled or removed regardless of the others.
+pub struct Chain {
+  target: usize,
+  /// The relocated prolog of the target, shared by all links.
+  trampoline: ManuallyDrop<alloc::ExecutableMemory>,
+  patcher: arch::Patcher,
+  /// Prolog instruction addresses paired with their trampoline equivalents.
+  relocations: Vec<(usize, usize)>,
+  /// The decoded prolog of the target.
+  instructions: Vec<PrologInstruction>,
+  /// Whether the relocated prolog calls a function, which may return into
+  /// the trampoline at any time.
+  has_call: bool,
+  /// The address the target currently jumps to, if it is patched.
+  destination: Option<usize>,
+  /// The links in order of creation.
+  links: Vec<Link>,
+  /// The counters of all tracked links ever added, since a call through a
+  /// removed link may still reach the trampoline.
+  counters: Vec<Arc<AtomicUsize>>,
+}
+
+impl Chain {
+  /// Creates an unpatched chain for a target.
+  pub unsafe fn new(
+    pool: &mut alloc::ThreadAllocator,
+    target: *const (),
+    options: &DetourOptions,
+    reserved: &[Range<usize>],
+  ) -> Result<Self> {
+    let (mut instructions, prepared) = prepare(target, &options.strategies, reserved);
+    let (mut trampoline, patcher) = prepared?;
+    if options.scan_body {
+      arch::scan::check_body(target, trampoline.prolog_size())?;
+    }
+    let trampoline_code = match memory::allocate_pic(pool, trampoline.emitter(), target) {
+      Ok(code) => code,
+      // Without memory in range, RIP-relative operands are made absolute
+      Err(Error::OutOfMemory) | Err(Error::DisplacementOutOfRange { .. }) => {
+        let margin = arch::meta::prolog_margin(target, patcher.strategy());
+        let (far_instructions, far_trampoline) = arch::Trampoline::analyze_far(target, margin);
+        instructions = far_instructions;
+        trampoline = far_trampoline?;
+        memory::allocate_pic_anywhere(pool, trampoline.emitter())?
+      },
+      Err(error) => return Err(error),
+    };
+
+    // Threads interrupted within the prolog continue in the trampoline
+    let relocations = trampoline
+      .instructions()
+      .iter()
+      .filter(|(offset, _)| *offset > 0)
+      .map(|(offset, trampoline_offset)| {
+        (
+          target as usize + offset,
+          trampoline_code.as_ptr() as usize + trampoline_offset,
+        )
+      })
+      .collect();
+
+    Ok(Chain {
+      target: target as usize,
+      trampoline: ManuallyDrop::new(trampoline_code),
+      destination: None,
+      links: Vec::new(),
+      counters: Vec::new(),
+      has_call: trampoline.has_call(),
+      instructions,
+      relocations,
+      patcher,
+    })
+  }
+
+  /// Returns the analysis of the target's patch.
+  pub fn analysis(&self) -> Analysis {
+    report(
+      self.target as *const (),
+      self.instructions.clone(),
+      Ok(&self.patcher),
+    )
+  }
+
+  /// Checks that the target's body does not branch into its prolog.
+  pub unsafe fn scan_body(&self) -> Result<()> {
+    let prolog_size = self
+      .instructions
+      .iter()
+      .map(|instruction| instruction.bytes.len())
+      .sum();
+    arch::scan::check_body(self.target as *const (), prolog_size)
+  }
+
+  /// Returns all code modified by the target's patch.
+  pub fn areas(&self) -> Vec<Range<usize>> {
+    self.patcher.areas()
+  }
+
+  /// Returns whether the patch is a relative jump, which requires the relays
+  /// to be within its range.
+  pub fn is_relative(&self) -> bool {
+    self.patcher.is_relative()
+  }
+
+  /// Returns how the target's code is replaced.
+  pub fn write_strategy(&self) -> arch::WriteStrategy {
+    self.patcher.write_strategy()
+  }
+
+  /// Returns whether the chain has no links.
+  pub fn is_empty(&self) -> bool {
+    self.links.is_empty()
+  }
+
+  /// Adds a disabled link to the top of the chain.
+  pub fn push(&mut self, link: Link) {
+    debug_assert!(!link.enabled);
+    self.counters.extend(link.counter.clone());
+    self.links.push(link);
+    unsafe { self.relink() };
+  }
+
+  /// Removes a disabled link from the chain.
+  pub fn remove(&mut self, slot: usize) {
+    self.links.retain(|link| link.slot != slot);
+    unsafe { self.relink() };
+  }
+
+  /// Returns a link of the chain.
+  pub fn link_mut(&mut self, slot: usize) -> &mut Link {
+    self
+      .links
+      .iter_mut()
+      .find(|link| link.slot == slot)
+      .expect("link should be part of its chain")
+  }
+
+  /// Returns the new address of each link's slot, and the address that the
+  /// target should jump to (the trampoline, if no link is enabled).
+  fn slots(&self) -> (Vec<(usize, usize)>, usize) {
+    // Each slot leads to the closest enabled link below it
+    let mut next = self.trampoline.as_ptr() as usize;
+    let mut slots = Vec::with_capacity(self.links.len());
+    for link in &self.links {
+      slots.push((link.slot, next));
+      if link.enabled {
+        next = link.destination;
+      }
+    }
+    (slots, next)
+  }
+
+  /// Prepares the modifications required by the current state of the links.
+  pub fn plan(&self) -> Result<Update> {
+    let original = self.trampoline.as_ptr() as usize;
+    let (slots, next) = self.slots();
+
+    let destination = Some(next).filter(|&next| next != original);
+    let mut relocations = Vec::new();
+
+    let patch = if destination == self.destination {
+      None
+    } else if let Some(detour) = destination {
+      // Threads within the prolog are moved to the trampoline when patched
+      if self.destination.is_none() {
+        relocations.extend_from_slice(&self.relocations);
+      }
+      Some((destination, self.patcher.hook(detour as *const ())?))
+    } else {
+      relocations.extend(self.relocations.iter().map(|&(from, to)| (to, from)));
+
+      // A thread may be about to execute the long jump of a short jump
+      if let (Some(padding), Some(detour)) = (self.patcher.padding(), self.destination) {
+        relocations.push((padding.start, detour));
+      }
+      Some((None, self.patcher.original().to_vec()))
+    };
+
+    Ok(Update {
+      target: self.target,
+      slots,
+      patch,
+      relocations,
+    })
+  }
+
+  /// Applies a prepared update.
+  ///
+  /// Slots are updated before the target, so a newly enabled link can always
+  /// reach the next one once the target jumps to it. No memory is allocated
+  /// or freed, since other threads may be suspended.
+  pub unsafe fn apply(&mut self, update: &Update) {
+    for &(slot, address) in &update.slots {
+      (*(slot as *const AtomicUsize)).store(address, Ordering::SeqCst);
+    }
+
+    if let Some((destination, code)) = &update.patch {
+      self.patcher.write(code, *destination);
+      self.destination = *destination;
+    }
+  }
+
+  /// Releases the chain, returning its trampoline.
+  pub fn into_trampoline(mut self) -> alloc::ExecutableMemory {
+    unsafe { ManuallyDrop::take(&mut self.trampoline) }
+  }
+
+  /// Returns the counters of the tracked calls that may reach the trampoline.
+  pub fn counters(&self) -> Vec<Arc<AtomicUsize>> {
+    self.counters.clone()
+  }
+
+  /// Returns whether a thread may return into the trampoline, after leaving
+  /// it.
+  ///
+  /// Return addresses cannot be sampled, so such a trampoline is never
+  /// released.
+  pub fn is_returned_into(&self) -> bool {
+    self.has_call
+  }
+
+  /// Updates the slots after a disabled link has been added or removed.
+  unsafe fn relink(&mut self) {
+    for (slot, address) in self.slots().0 {
+      (*(slot as *const AtomicUsize)).store(address, Ordering::SeqCst);
+    }
+  }
+}
+
+/// Returns information about all live detours.
+pub fn live_detours() -> Vec<DetourInfo> {
+  let chains = util::lock(&CHAINS);
+  let mut chains = chains.values().collect::<Vec<_>>();
+  chains.sort_by_key(|chain| chain.target);
+
+  let mut detours = Vec::new();
+  for chain in &chains {
+    let areas = chain.areas();
+    let overlapping = chains
+      .iter()
+      .filter(|other| other.target != chain.target)
+      .filter(|other| {
+        other.areas().iter().any(|other| {
+          areas
+            .iter()
+            .any(|area| other.start < area.end && area.start < other.end)
+        })
+      })
+      .map(|other| other.target as *const ())
+      .collect::<Vec<_>>();
+
+    detours.extend(chain.links.iter().map(|link| DetourInfo {
+      target: chain.target as *const (),
+      detour: link.detour as *const (),
+      trampoline: link.trampoline.clone(),
+      original: chain.trampoline.range(),
+      relay: link.relay.clone(),
+      patch_area: chain.patcher.area(),
+      enabled: link.enabled,
+      label: link.label.clone(),
+      location: link.location,
+      overlapping: overlapping.clone(),
+    }));
+  }
+  detours
+}
+
+/// Analyses a target without modifying it.
+pub unsafe fn analyze(target: *const ()) -> Analysis {
+  let chains = util::lock(&CHAINS);
+  if let Some(chain) = chains.get(&(target as usize)) {
+    return chain.analysis();
+  }
+
+  match util::is_executable_address(target) {
+    Ok(true) => (),
+    Ok(false) => return report(target, Vec::new(), Err(Error::NotExecutable)),
+    Err(error) => return report(target, Vec::new(), Err(error)),
+  }
+
+  let strategies = DetourOptions::default().strategies;
+  let (instructions, prepared) = prepare(target, &strategies, &reserved(&chains));
+  match prepared {
+    Ok((_, patcher)) => report(target, instructions, Ok(&patcher)),
+    Err(error) => report(target, instructions, Err(error)),
+  }
+}
+
+/// Returns the code modified by the patches of all chains.
+pub fn reserved(chains: &HashMap<usize, Chain>) -> Vec<Range<usize>> {
+  chains.values().flat_map(Chain::areas).collect()
+}
+
+/// Decodes the prolog of a target and determines its patch area, using the
+/// first strategy that fits it.
+unsafe fn prepare(
+  target: *const (),
+  strategies: &[PatchStrategy],
+  reserved: &[Range<usize>],
+) -> (
+  Vec<PrologInstruction>,
+  Result<(arch::Trampoline, arch::Patcher)>,
+) {
+  let mut first = None;
+  for strategy in arch::Patcher::strategies(target, strategies) {
+    // Create a trampoline generator for the target function
+    let margin = arch::meta::prolog_margin(target, strategy);
+    let (instructions, trampoline) = arch::Trampoline::analyze(target, margin);
+
+    let prepared = trampoline.and_then(|trampoline| {
+      let patcher = arch::Patcher::new(target, trampoline.prolog_size(), strategy, reserved)?;
+      Ok((trampoline, patcher))
+    });
+    if prepared.is_ok() {
+      return (instructions, prepared);
+    }
+
+    // The failure of the preferred strategy is reported
+    first.get_or_insert((instructions, prepared));
+  }
+
+  first.unwrap_or_else(|| {
+    let error = Error::NoPatchArea {
+      target: target as usize,
+      available: 0,
+      required: 0,
+    };
+    (Vec::new(), Err(error))
+  })
+}
+
+/// Creates an analysis report from a decoded prolog and its patch.
+fn report(
+  target: *const (),
+  instructions: Vec<PrologInstruction>,
+  patcher: Result<&arch::Patcher>,
+) -> Analysis {
+  let prolog_size = instructions
+    .iter()
+    .map(|instruction| instruction.bytes.len())
+    .sum();
+  let (strategy, patch_area, padding, error) = match patcher {
+    Ok(patcher) => {
+      // The padding of a hot patch is part of its patch area
+      let padding = patcher
+        .padding()
+        .filter(|_| patcher.strategy() != PatchStrategy::HotPatch);
+      (
+        Some(patcher.strategy()),
+        Some(patcher.area()),
+        padding,
+        None,
+      )
+    },
+    Err(error) => (None, None, None, Some(error)),
+  };
+
+  Analysis {
+    relay: false,
+    target,
+    instructions,
+    prolog_size,
+    strategy,
+    patch_area,
+    padding,
+    error,
+  }
+}
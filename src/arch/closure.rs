@@ -0,0 +1,120 @@
+use super::memory;
+use crate::alloc::Attachment;
+use crate::error::Result;
+use crate::{alloc, arch, util};
+use std::cell::Cell;
+use std::mem::ManuallyDrop;
+use std::sync::atomic::{compiler_fence, Ordering};
+use std::{process, ptr};
+
+/// The maximum number of thunks a thread may have entered, without their
+/// shims having retrieved the contexts (i.e when interrupted by signal
+/// handlers).
+const DEPTH: usize = 16;
+
+/// The contexts of the thunks entered by a thread, that have yet to be
+/// retrieved by their shims.
+struct Contexts {
+  entries: Cell<[*const (); DEPTH]>,
+  len: Cell<usize>,
+}
+
+impl Contexts {
+  /// Returns an entry of the stack.
+  fn entry(&self, index: usize) -> &Cell<*const ()> {
+    let entries: &Cell<[*const ()]> = &self.entries;
+    &entries.as_slice_of_cells()[index]
+  }
+}
+
+thread_local! {
+  static CONTEXTS: Contexts = const {
+    Contexts {
+      entries: Cell::new([ptr::null(); DEPTH]),
+      len: Cell::new(0),
+    }
+  };
+}
+
+/// A per-closure thunk, which passes a context to a shared shim.
+///
+/// The thunk saves the argument registers, counts the call, pushes its
+/// context onto a stack of the current thread, and restores the registers
+/// before jumping to the shim. Since the shim pops the context, a signal
+/// handler entering another thunk in between does not affect it.
+pub struct ClosureThunk {
+  memory: ManuallyDrop<alloc::ExecutableMemory>,
+  /// The data used by the shim, which is released along with the thunk.
+  attachments: Vec<Box<dyn Attachment>>,
+}
+
+impl ClosureThunk {
+  /// Allocates a thunk passing `context` to `shim`, and incrementing
+  /// `counter` (a pointer-sized integer) before it leaves the thunk.
+  pub fn new(context: *const (), counter: *const (), shim: *const ()) -> Result<Self> {
+    let emitter = arch::meta::closure_builder(enter as *const (), context, counter, shim);
+    let mut pool = util::lock(&memory::POOL);
+    memory::allocate_pic_anywhere(&mut pool, &emitter).map(|memory| ClosureThunk {
+      memory: ManuallyDrop::new(memory),
+      attachments: Vec::new(),
+    })
+  }
+
+  /// Transfers data used by the shim (or calls that may still reach the
+  /// thunk), which is kept until neither the thunk is executed, nor any of
+  /// the data is in use.
+  pub fn attach(&mut self, attachment: Box<dyn Attachment>) {
+    self.attachments.push(attachment);
+  }
+
+  /// Returns the address of the thunk.
+  pub fn as_ptr(&self) -> *const () {
+    self.memory.as_ptr() as *const ()
+  }
+}
+
+impl Drop for ClosureThunk {
+  /// Releases the thunk and its data once no other thread is using them.
+  fn drop(&mut self) {
+    let mut pool = util::lock(&memory::POOL);
+    let memory = unsafe { ManuallyDrop::take(&mut self.memory) };
+    let attachments = std::mem::take(&mut self.attachments);
+    if attachments.is_empty() {
+      pool.retire(memory);
+    } else {
+      pool.retire_with(memory, Box::new(attachments));
+    }
+    pool.reclaim();
+  }
+}
+
+/// Removes and returns the context of the thunk most recently entered by the
+/// thread.
+pub fn take_context() -> *const () {
+  CONTEXTS.with(|contexts| {
+    let index = contexts.len.get() - 1;
+    let context = contexts.entry(index).get();
+
+    // The context is read before its entry is released
+    compiler_fence(Ordering::SeqCst);
+    contexts.len.set(index);
+    context
+  })
+}
+
+/// Pushes the context of a thunk being entered.
+extern "C" fn enter(context: *const ()) {
+  CONTEXTS.with(|contexts| {
+    // A thunk cannot unwind, and its shim would otherwise use another context
+    let index = contexts.len.get();
+    if index == DEPTH {
+      process::abort();
+    }
+
+    // The entry is reserved before it is written, so a signal handler
+    // entering another thunk in between uses the next one
+    contexts.len.set(index + 1);
+    compiler_fence(Ordering::SeqCst);
+    contexts.entry(index).set(context);
+  });
+}
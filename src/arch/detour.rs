@@ -1,64 +1,153 @@
-use super::memory;
+use super::{chain, memory, relay};
+use crate::detours::{Analysis, DetourOptions};
 use crate::error::{Error, Result};
-use crate::{alloc, arch, util};
-use std::cell::UnsafeCell;
+use crate::{alloc, arch, suspend, util};
+use std::collections::hash_map::{Entry, HashMap};
 use std::fmt;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::mem::ManuallyDrop;
+use std::ops::Range;
+use std::panic::Location;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+#[cfg(target_os = "linux")]
+use std::time::{Duration, Instant};
 
 /// An architecture-independent implementation of a base detour.
 ///
 /// This class is never instantiated by itself, it merely exposes an API
 /// available through it's descendants.
+///
+/// All detours of the same target form a chain. The trampoline of a detour
+/// leads to the next enabled detour created before it, or to the original
+/// function if there is none.
+///
+/// If its returns are tracked, the target and the links above it lead to the
+/// detour's relay, which counts the calls in flight until they return. The
+/// relay and trampoline are then only released once no tracked call of any
+/// link of the chain remains.
 pub struct Detour {
-  #[allow(dead_code)]
+  target: usize,
   relay: Option<alloc::ExecutableMemory>,
-  trampoline: alloc::ExecutableMemory,
-  patcher: UnsafeCell<arch::Patcher>,
+  trampoline: ManuallyDrop<alloc::ExecutableMemory>,
+  /// The number of calls in flight through the relay, if they are tracked.
+  counter: Option<Arc<AtomicUsize>>,
+  /// The trampoline's slot, holding the address of the next link.
+  slot: usize,
+  write_strategy: arch::WriteStrategy,
   enabled: AtomicBool,
 }
 
 impl Detour {
+  #[track_caller]
   pub unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
+    Self::with_options(target, detour, &DetourOptions::default())
+  }
+
+  #[track_caller]
+  pub unsafe fn with_options(
+    target: *const (),
+    detour: *const (),
+    options: &DetourOptions,
+  ) -> Result<Self> {
+    let location = Location::caller();
     if target == detour {
       Err(Error::SameAddress)?;
     }
 
     // Lock this so OS operations are not performed in parallell
-    let mut pool = memory::POOL.lock().unwrap();
+    let mut pool = util::lock(&memory::POOL);
+    let mut chains = util::lock(&chain::CHAINS);
 
     if !util::is_executable_address(target)? || !util::is_executable_address(detour)? {
       Err(Error::NotExecutable)?;
     }
 
-    // Create a trampoline generator for the target function
-    let margin = arch::meta::prolog_margin(target);
-    let trampoline = arch::Trampoline::new(target, margin)?;
+    // A target already detoured by this crate is extended, not re-hooked
+    let new_chain = match chains.get(&(target as usize)) {
+      Some(chain) => {
+        if options.scan_body {
+          chain.scan_body()?;
+        }
+        None
+      },
+      None => {
+        let reserved = chain::reserved(&chains);
+        Some(chain::Chain::new(&mut pool, target, options, &reserved)?)
+      },
+    };
+    let is_relative = new_chain
+      .as_ref()
+      .unwrap_or_else(|| &chains[&(target as usize)])
+      .is_relative();
 
-    // A relay is used in case a normal branch cannot reach the destination
-    let relay = if let Some(emitter) = arch::meta::relay_builder(target, detour)? {
-      Some(memory::allocate_pic(&mut pool, &emitter, target)?)
+    let counter = if options.track_returns {
+      Some(Arc::new(AtomicUsize::new(0)))
     } else {
       None
     };
+    let allocated = Self::allocate(&mut pool, target, detour, counter.as_deref(), is_relative);
+    let (relay, trampoline) = match allocated {
+      Ok(allocated) => allocated,
+      Err(error) => {
+        // The trampoline of an unused chain is released
+        if let Some(chain) = new_chain {
+          drop(chain.into_trampoline());
+        }
+        return Err(error);
+      },
+    };
+    let slot = arch::meta::link_slot(trampoline.as_ptr() as *const ());
 
     // If a relay is supplied, use it instead of the detour address
-    let detour = relay
+    let destination = relay
       .as_ref()
       .map(|code| code.as_ptr() as *const ())
       .unwrap_or(detour);
 
+    let chain = chains
+      .entry(target as usize)
+      .or_insert_with(|| new_chain.expect("chain should be created"));
+    chain.push(chain::Link {
+      slot,
+      destination: destination as usize,
+      enabled: false,
+      detour: detour as usize,
+      trampoline: trampoline.range(),
+      relay: relay.as_ref().map(alloc::ExecutableMemory::range),
+      counter: counter.clone(),
+      label: None,
+      location,
+    });
+
     Ok(Detour {
-      patcher: UnsafeCell::new(arch::Patcher::new(
-        target,
-        detour,
-        trampoline.prolog_size(),
-      )?),
-      trampoline: memory::allocate_pic(&mut pool, trampoline.emitter(), target)?,
+      target: target as usize,
+      write_strategy: chain.write_strategy(),
+      trampoline: ManuallyDrop::new(trampoline),
       enabled: AtomicBool::default(),
       relay,
+      counter,
+      slot,
     })
   }
 
+  /// Allocates the relay (if required) and trampoline of a detour.
+  unsafe fn allocate(
+    pool: &mut alloc::ThreadAllocator,
+    target: *const (),
+    detour: *const (),
+    counter: Option<&AtomicUsize>,
+    is_relative: bool,
+  ) -> Result<(Option<alloc::ExecutableMemory>, alloc::ExecutableMemory)> {
+    // The relay is also placed within reach of a relative jump
+    let relay = relay::allocate(pool, target, detour, counter, is_relative)?;
+
+    // The trampoline's destination is assigned once it is part of the chain
+    let emitter = arch::meta::link_builder(std::ptr::null());
+    let trampoline = memory::allocate_pic(pool, &emitter, target)
+      .or_else(|_| memory::allocate_pic_anywhere(pool, &emitter))?;
+    Ok((relay, trampoline))
+  }
+
   /// Enables the detour.
   pub unsafe fn enable(&self) -> Result<()> {
     self.toggle(true)
@@ -69,50 +158,264 @@ impl Detour {
     self.toggle(false)
   }
 
+  /// Disables the detour and waits until no tracked call is in flight through
+  /// its relay, nor is any other thread executing its trampoline.
+  #[cfg(target_os = "linux")]
+  pub unsafe fn disable_and_wait(&self, timeout: Duration) -> Result<()> {
+    self.disable()?;
+
+    let deadline = Instant::now() + timeout;
+    while self.is_executing()? {
+      if Instant::now() >= deadline {
+        Err(Error::Timeout)?;
+      }
+      std::thread::sleep(Duration::from_millis(1));
+    }
+    Ok(())
+  }
+
+  /// Returns whether any tracked call is in flight through the relay, or any
+  /// other thread is executing the trampoline or relay.
+  ///
+  /// Instruction pointers are only sampled if thread suspension is enabled,
+  /// which also covers a thread that has jumped to the relay, but has yet to
+  /// be counted.
+  #[cfg(target_os = "linux")]
+  fn is_executing(&self) -> Result<bool> {
+    let _guard = util::lock(&memory::POOL);
+    if self.is_in_flight() {
+      return Ok(true);
+    }
+
+    // No memory may be allocated whilst other threads are suspended
+    let ranges = std::iter::once(&*self.trampoline)
+      .chain(self.relay.as_ref())
+      .map(alloc::ExecutableMemory::range)
+      .collect::<Vec<_>>();
+    Ok(match suspend::suspend()? {
+      Some(suspension) => {
+        let is_executing = ranges.iter().any(|range| suspension.is_executing(range));
+        suspension.resume(&[]);
+        is_executing
+      },
+      None => false,
+    })
+  }
+
+  /// Returns whether any tracked call is in flight through the relay.
+  #[cfg(target_os = "linux")]
+  fn is_in_flight(&self) -> bool {
+    self
+      .counter
+      .as_ref()
+      .map_or(false, |counter| counter.load(Ordering::SeqCst) != 0)
+  }
+
+  /// Returns the tracked calls in flight through the relay, which may still
+  /// reach the detour.
+  pub fn in_flight(&self) -> Option<Box<dyn alloc::Attachment>> {
+    let counter = self.counter.clone()?;
+    Some(Box::new(relay::InFlight(vec![counter])))
+  }
+
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
     self.enabled.load(Ordering::SeqCst)
   }
 
+  /// Returns how the target's code is replaced when toggled.
+  pub fn write_strategy(&self) -> arch::WriteStrategy {
+    self.write_strategy
+  }
+
+  /// Returns the analysis of the applied patch.
+  pub fn analysis(&self) -> Analysis {
+    let chains = util::lock(&chain::CHAINS);
+    let mut analysis = chains[&self.target].analysis();
+    analysis.relay = self.relay.is_some();
+    analysis
+  }
+
+  /// Assigns a label to the detour, reported by `live_detours`.
+  pub fn set_label(&self, label: &str) {
+    let _guard = util::lock(&memory::POOL);
+    let mut chains = util::lock(&chain::CHAINS);
+    self.link(&mut chains).label = Some(label.to_owned());
+  }
+
   /// Returns a reference to the generated trampoline.
   pub fn trampoline(&self) -> &() {
-    unsafe {
-      (self.trampoline.as_ptr() as *const ())
-        .as_ref()
-        .expect("trampoline should not be null")
-    }
+    // The trampoline is allocated memory, so its address is never null
+    unsafe { &*(self.trampoline.as_ptr() as *const ()) }
   }
 
   /// Enables or disables the detour.
   unsafe fn toggle(&self, enabled: bool) -> Result<()> {
-    let _guard = memory::POOL.lock().unwrap();
+    Self::toggle_all(&[(self, enabled)])
+  }
+
+  /// Enables or disables several detours at once.
+  ///
+  /// The affected pages are made writable in a single pass before any code is
+  /// modified. If this fails, none of the detours are changed.
+  pub unsafe fn toggle_all(operations: &[(&Detour, bool)]) -> Result<()> {
+    let _guard = util::lock(&memory::POOL);
+    let mut chains = util::lock(&chain::CHAINS);
 
-    if self.enabled.load(Ordering::SeqCst) == enabled {
-      return Ok(());
+    // Skip any detours that are already in the requested state
+    let operations = operations
+      .iter()
+      .filter(|(detour, enabled)| detour.enabled.load(Ordering::SeqCst) != *enabled)
+      .collect::<Vec<_>>();
+
+    // Stage the new state of each link, and determine the affected chains
+    let mut targets = Vec::new();
+    for (detour, enabled) in &operations {
+      detour.link(&mut chains).enabled = *enabled;
+      if !targets.contains(&detour.target) {
+        targets.push(detour.target);
+      }
     }
 
-    // Runtime code is by default only read-execute
-    let _handle = {
-      let area = (*self.patcher.get()).area();
-      region::protect_with_handle(
-        area.as_ptr(),
-        area.len(),
-        region::Protection::READ_WRITE_EXECUTE,
-      )
-    }?;
-
-    // Copy either the detour or the original bytes of the function
-    (*self.patcher.get()).toggle(enabled);
-    self.enabled.store(enabled, Ordering::SeqCst);
+    let prepared = targets
+      .iter()
+      .map(|target| chains[target].plan())
+      .collect::<Result<Vec<_>>>()
+      .and_then(|updates| {
+        // Threads are relocated to or from the trampoline, depending on the change
+        let relocations = updates
+          .iter()
+          .flat_map(|update| update.relocations.iter().copied())
+          .collect::<Vec<_>>();
+
+        // Runtime code is by default only read-execute
+        let handles = Self::unprotect(
+          updates
+            .iter()
+            .filter(|update| update.is_patch())
+            .flat_map(|update| chains[&update.target].areas()),
+        )?;
+
+        // No memory may be allocated whilst other threads are suspended
+        let suspension = suspend::suspend()?;
+        Ok((updates, relocations, handles, suspension))
+      });
+
+    let (updates, relocations, _handles, suspension) = match prepared {
+      Ok(prepared) => prepared,
+      Err(error) => {
+        // Revert the staged states, leaving every detour unchanged
+        for (detour, enabled) in &operations {
+          detour.link(&mut chains).enabled = !*enabled;
+        }
+        return Err(error);
+      },
+    };
+
+    // Nor may any memory be freed, until the threads are resumed
+    for update in &updates {
+      chains
+        .get_mut(&update.target)
+        .expect("chain should exist")
+        .apply(update);
+    }
+
+    for (detour, enabled) in &operations {
+      detour.enabled.store(*enabled, Ordering::SeqCst);
+    }
+
+    if let Some(suspension) = suspension {
+      suspension.resume(&relocations);
+    }
     Ok(())
   }
+
+  /// Returns the detour's link within its chain.
+  fn link<'a>(&self, chains: &'a mut HashMap<usize, chain::Chain>) -> &'a mut chain::Link {
+    chains
+      .get_mut(&self.target)
+      .expect("detour should be part of a chain")
+      .link_mut(self.slot)
+  }
+
+  /// Makes the pages of all areas writable, merging overlapping page ranges.
+  fn unprotect(areas: impl Iterator<Item = Range<usize>>) -> Result<Vec<region::ProtectGuard>> {
+    let page_size = region::page::size();
+    let mut ranges = areas
+      .map(|area| (area.start & !(page_size - 1))..((area.end + page_size - 1) & !(page_size - 1)))
+      .collect::<Vec<_>>();
+    ranges.sort_by_key(|range| range.start);
+
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+      match merged.last_mut() {
+        Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+        _ => merged.push(range),
+      }
+    }
+
+    merged
+      .into_iter()
+      .map(|range| unsafe {
+        region::protect_with_handle(
+          range.start as *const u8,
+          range.end - range.start,
+          region::Protection::READ_WRITE_EXECUTE,
+        )
+        .map_err(Error::from)
+      })
+      .collect()
+  }
 }
 
 impl Drop for Detour {
   /// Disables the detour, if enabled.
+  ///
+  /// The trampoline and relay are released once no tracked call is in flight
+  /// through any link of the chain, and (with thread suspension) no other
+  /// thread is executing them. The original trampoline is never released if
+  /// its relocated prolog contains a call. If the detour cannot be disabled,
+  /// the target may still jump to it, so its link and memory are never
+  /// released.
   fn drop(&mut self) {
-    let did_succeed = unsafe { self.disable() }.is_ok();
-    debug_assert!(did_succeed);
+    let is_disabled = unsafe { self.disable() }.is_ok();
+
+    let mut pool = util::lock(&memory::POOL);
+    let mut chains = util::lock(&chain::CHAINS);
+    let trampoline = unsafe { ManuallyDrop::take(&mut self.trampoline) };
+    let relay = self.relay.take();
+
+    if !is_disabled {
+      pool.leak(trampoline);
+      if let Some(relay) = relay {
+        pool.leak(relay);
+      }
+      return;
+    }
+
+    // Calls through the links above may still reach this link
+    let mut counters = Vec::new();
+    if let Entry::Occupied(mut entry) = chains.entry(self.target) {
+      counters = entry.get().counters();
+      entry.get_mut().remove(self.slot);
+
+      // The original trampoline is released along with the last link
+      if entry.get().is_empty() {
+        let chain = entry.remove();
+        if chain.is_returned_into() {
+          pool.leak(chain.into_trampoline());
+        } else {
+          let in_flight = relay::InFlight(counters.clone());
+          pool.retire_with(chain.into_trampoline(), Box::new(in_flight));
+        }
+      }
+    }
+
+    pool.retire_with(trampoline, Box::new(relay::InFlight(counters.clone())));
+    if let Some(relay) = relay {
+      pool.retire_with(relay, Box::new(relay::InFlight(counters)));
+    }
+    pool.reclaim();
   }
 }
 
@@ -16,10 +16,27 @@ pub fn allocate_pic(
   origin: *const (),
 ) -> Result<alloc::ExecutableMemory> {
   // Allocate memory close to the origin
-  pool.allocate(origin, emitter.len()).map(|mut memory| {
-    // Generate code for the obtained address
-    let code = emitter.emit(memory.as_ptr() as *const _);
-    memory.copy_from_slice(code.as_slice());
-    memory
-  })
+  pool
+    .allocate(origin, emitter.len())
+    .and_then(|memory| emit(memory, emitter))
+}
+
+/// Allocates PIC code anywhere, for code that has no range constraints.
+pub fn allocate_pic_anywhere(
+  pool: &mut alloc::ThreadAllocator,
+  emitter: &pic::CodeEmitter,
+) -> Result<alloc::ExecutableMemory> {
+  pool
+    .allocate_anywhere(emitter.len())
+    .and_then(|memory| emit(memory, emitter))
+}
+
+/// Generates code for the obtained memory's address.
+fn emit(
+  mut memory: alloc::ExecutableMemory,
+  emitter: &pic::CodeEmitter,
+) -> Result<alloc::ExecutableMemory> {
+  let code = emitter.emit(memory.as_ptr() as *const _)?;
+  memory.copy_from_slice(code.as_slice());
+  Ok(memory)
 }
@@ -3,14 +3,21 @@
 /// The current implementation requires a module to expose some functionality:
 ///
 /// - A standalone `relay_builder` function.
-/// This function creates a relay for targets with large displacement, that
-/// requires special attention. An example would be detours further away than
-/// 2GB on x64. A relative jump is not enough, so the `relay_builder`
-/// generates an absolute jump that the relative jump can reach. If it's
-/// needless, `None` can   be returned.
+///   This function creates a relay for targets with large displacement, that
+///   requires special attention. An example would be detours further away
+///   than 2GB on x64. A relative jump is not enough, so the `relay_builder`
+///   generates an absolute jump that the relative jump can reach. If it's
+///   needless, `None` can be returned.
+///
+/// - A standalone `counting_relay_builder` function.
+///   This function creates the relay of a detour with tracked returns. It
+///   counts the calls in flight, replacing their return addresses with the
+///   code created by `exit_builder`, before jumping to the detour.
 ///
 /// - A `Patcher`, modifies a target in-memory.
 /// - A `Trampoline`, generates a callable address to the target.
+pub use self::chain::{analyze, live_detours};
+pub use self::closure::ClosureThunk;
 pub use self::detour::Detour;
 
 use cfg_if::cfg_if;
@@ -20,14 +27,30 @@ use cfg_if::cfg_if;
 cfg_if! {
     if #[cfg(any(target_arch = "x86", target_arch = "x86_64"))] {
         mod x86;
-        use self::x86::{Patcher, Trampoline, meta};
+        use self::x86::{Patcher, Trampoline, meta, scan};
     } else {
         // TODO: Implement ARM/AARCH64/MIPS support!
     }
 }
 
+mod chain;
+pub mod closure;
 mod detour;
 mod memory;
+mod relay;
+
+/// The method used to replace live code when a detour is toggled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum WriteStrategy {
+  /// The live code is replaced by a single compare-exchange of an aligned
+  /// 8-byte word, so other threads observe either the old or the new code.
+  Atomic64,
+  /// The live code is replaced by a single compare-exchange of an aligned
+  /// 16-byte word (`cmpxchg16b`).
+  Atomic128,
+  /// The live code straddles an atomic boundary and is copied byte by byte.
+  Copy,
+}
 
 /// Returns true if the displacement is within a certain range.
 pub fn is_within_range(displacement: isize) -> bool {
@@ -0,0 +1,148 @@
+use super::memory;
+use crate::alloc::{self, Attachment};
+use crate::arch;
+use crate::error::Result;
+use std::cell::Cell;
+use std::ptr;
+use std::sync::atomic::{compiler_fence, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The maximum number of detoured calls a thread may be executing at once,
+/// for which the returns are intercepted.
+const DEPTH: usize = 64;
+
+/// The address of the code that intercepted calls return to.
+static EXIT: AtomicUsize = AtomicUsize::new(0);
+
+/// A detoured call, of which the return address has been replaced.
+#[derive(Clone, Copy)]
+struct Frame {
+  /// The counter of the relay that was entered.
+  counter: *const AtomicUsize,
+  return_address: usize,
+}
+
+/// The detoured calls being executed by a thread, innermost last.
+struct Frames {
+  entries: Cell<[Frame; DEPTH]>,
+  len: Cell<usize>,
+}
+
+impl Frames {
+  /// Returns an entry of the stack.
+  fn entry(&self, index: usize) -> &Cell<Frame> {
+    let entries: &Cell<[Frame]> = &self.entries;
+    &entries.as_slice_of_cells()[index]
+  }
+}
+
+thread_local! {
+  static FRAMES: Frames = const {
+    Frames {
+      entries: Cell::new(
+        [Frame {
+          counter: ptr::null(),
+          return_address: 0,
+        }; DEPTH],
+      ),
+      len: Cell::new(0),
+    }
+  };
+}
+
+/// The number of calls in flight through one or more relays.
+pub struct InFlight(pub Vec<Arc<AtomicUsize>>);
+
+impl Attachment for InFlight {
+  fn is_in_use(&self) -> bool {
+    self
+      .0
+      .iter()
+      .any(|counter| counter.load(Ordering::SeqCst) != 0)
+  }
+}
+
+/// Allocates the relay of a detour, close to the target if possible.
+///
+/// With a counter, the relay replaces the return address of each call, so it
+/// is counted until the call returns. This covers both the detour and the
+/// trampoline, which is only called by the detour. Otherwise a relay is only
+/// required for a relative patch that cannot reach the detour.
+pub fn allocate(
+  pool: &mut alloc::ThreadAllocator,
+  target: *const (),
+  detour: *const (),
+  counter: Option<&AtomicUsize>,
+  is_relative: bool,
+) -> Result<Option<alloc::ExecutableMemory>> {
+  let counter = match counter {
+    Some(counter) => counter as *const AtomicUsize as *const (),
+    None => {
+      return match arch::meta::relay_builder(target, detour)? {
+        Some(emitter) if is_relative => memory::allocate_pic(pool, &emitter, target).map(Some),
+        _ => Ok(None),
+      };
+    },
+  };
+
+  // The code returned to is shared by all relays, and never released
+  if EXIT.load(Ordering::SeqCst) == 0 {
+    let emitter = arch::meta::exit_builder(leave as *const ());
+    let exit = memory::allocate_pic_anywhere(pool, &emitter)?;
+    EXIT.store(exit.as_ptr() as usize, Ordering::SeqCst);
+    pool.leak(exit);
+  }
+
+  let emitter = arch::meta::counting_relay_builder(enter as *const (), counter, detour);
+  memory::allocate_pic(pool, &emitter, target)
+    .or_else(|error| {
+      // Only a relative patch requires the relay to be in range
+      if is_relative {
+        Err(error)
+      } else {
+        memory::allocate_pic_anywhere(pool, &emitter)
+      }
+    })
+    .map(Some)
+}
+
+/// Registers a call entering a relay, replacing its return address.
+///
+/// If the thread is executing too many detoured calls, the call is no longer
+/// counted (as for a detour without tracking).
+extern "C" fn enter(counter: *const AtomicUsize, slot: *mut usize) {
+  FRAMES.with(|frames| {
+    let index = frames.len.get();
+    if index == DEPTH {
+      unsafe { (*counter).fetch_sub(1, Ordering::SeqCst) };
+      return;
+    }
+
+    // The entry is reserved before it is written, so a signal handler
+    // entering another relay in between uses the next one
+    frames.len.set(index + 1);
+    compiler_fence(Ordering::SeqCst);
+    frames.entry(index).set(Frame {
+      counter,
+      return_address: unsafe { *slot },
+    });
+    unsafe { *slot = EXIT.load(Ordering::SeqCst) };
+  });
+}
+
+/// Unregisters the innermost call of the thread, returning the address it
+/// returns to.
+extern "C" fn leave() -> usize {
+  FRAMES.with(|frames| {
+    let index = frames.len.get() - 1;
+    let frame = frames.entry(index).get();
+
+    // The frame is read before its entry is released
+    compiler_fence(Ordering::SeqCst);
+    frames.len.set(index);
+
+    // The code used by the call may be released from here on
+    unsafe { (*frame.counter).fetch_sub(1, Ordering::SeqCst) };
+    frame.return_address
+  })
+}
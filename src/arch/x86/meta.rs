@@ -1,13 +1,31 @@
-use super::thunk;
-use crate::{error::Result, pic};
-use std::mem;
+use super::{thunk, Patcher};
+use crate::detours::PatchStrategy;
+use crate::{error::Result, pic, util};
 
 /// The furthest distance between a target and its detour (2 GiB).
 pub const DETOUR_RANGE: usize = 0x8000_0000;
 
+/// The encodings of `endbr64` and `endbr32`.
+const ENDBR: [[u8; 4]; 2] = [[0xF3, 0x0F, 0x1E, 0xFA], [0xF3, 0x0F, 0x1E, 0xFB]];
+
 /// Returns the preferred prolog size for the target.
-pub fn prolog_margin(_target: *const ()) -> usize {
-  mem::size_of::<thunk::x86::JumpRel>()
+///
+/// At least one instruction is relocated, so that the trampoline does not
+/// return to a guarded target.
+pub unsafe fn prolog_margin(target: *const (), strategy: PatchStrategy) -> usize {
+  entry_size(target) + Patcher::patch_size(strategy).max(1)
+}
+
+/// Returns the size of the code at the start of the target that is kept in
+/// place when patched.
+///
+/// With indirect branch tracking (CET), indirect calls must land on an
+/// `endbr` instruction, so the patch is placed after it.
+pub unsafe fn entry_size(target: *const ()) -> usize {
+  match util::read_code(target as usize, ENDBR[0].len()) {
+    Ok(code) if ENDBR.iter().any(|endbr| code == endbr) => code.len(),
+    _ => 0,
+  }
 }
 
 /// Creates a relay; required for destinations further away than 2GB (on x64).
@@ -22,3 +40,57 @@ pub fn relay_builder(target: *const (), detour: *const ()) -> Result<Option<pic:
     Ok(None)
   }
 }
+
+/// Creates a relay, which counts the call and passes the counter along with
+/// the address of the return address to `enter`, before jumping to the
+/// detour with its arguments intact.
+pub fn counting_relay_builder(
+  enter: *const (),
+  counter: *const (),
+  detour: *const (),
+) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(thunk::relay_prolog(counter as usize));
+  emitter.add_thunk(thunk::call(enter as usize));
+  emitter.add_thunk(thunk::relay_epilog());
+  emitter.add_thunk(thunk::jmp(detour as usize));
+  emitter
+}
+
+/// Creates the code that intercepted calls return to, which returns to the
+/// address provided by `leave` with the return value intact.
+pub fn exit_builder(leave: *const ()) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(thunk::exit_prolog());
+  emitter.add_thunk(thunk::call(leave as usize));
+  emitter.add_thunk(thunk::exit_epilog());
+  emitter
+}
+
+/// Creates a thunk that counts the call, passes a context to `enter`, and
+/// then jumps to a closure's shim with its arguments intact.
+pub fn closure_builder(
+  enter: *const (),
+  context: *const (),
+  counter: *const (),
+  shim: *const (),
+) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(thunk::closure_prolog(context as usize, counter as usize));
+  emitter.add_thunk(thunk::call(enter as usize));
+  emitter.add_thunk(thunk::closure_epilog());
+  emitter.add_thunk(thunk::jmp(shim as usize));
+  emitter
+}
+
+/// Creates a trampoline that jumps to the address held in its slot.
+pub fn link_builder(destination: *const ()) -> pic::CodeEmitter {
+  let mut emitter = pic::CodeEmitter::new();
+  emitter.add_thunk(thunk::x86::jmp_slot(destination as usize));
+  emitter
+}
+
+/// Returns the slot of a trampoline created by `link_builder`.
+pub fn link_slot(trampoline: *const ()) -> usize {
+  thunk::x86::jump_slot(trampoline as usize)
+}
@@ -3,6 +3,7 @@ pub use self::trampoline::Trampoline;
 
 pub mod meta;
 mod patcher;
+pub mod scan;
 mod thunk;
 mod trampoline;
 
@@ -11,11 +12,12 @@ mod trampoline;
 // TODO: Add test for negative branch displacements
 #[cfg(all(feature = "nightly", test))]
 mod tests {
+  use crate::arch::memory;
   use crate::error::{Error, Result};
-  use crate::RawDetour;
+  use crate::{DetourOptions, PatchStrategy, RawDetour, Relocation};
   use matches::assert_matches;
   use std::arch::naked_asm;
-  use std::mem;
+  use std::{mem, slice};
 
   /// Default test case function definition.
   type CRet = unsafe extern "C" fn() -> i32;
@@ -37,6 +39,44 @@ mod tests {
     Ok(())
   }
 
+  /// Detours a C function using a single patch strategy, and asserts its
+  /// return value.
+  unsafe fn strategy_test(target: CRet, strategy: PatchStrategy, result: i32) -> Result<()> {
+    let options = DetourOptions::new().strategies(&[strategy]);
+    let hook = RawDetour::with_options(target as *const (), ret10 as *const (), &options)?;
+    assert_eq!(hook.analysis().strategy, Some(strategy));
+
+    hook.enable()?;
+    {
+      assert_eq!(target(), 10);
+      let original: CRet = mem::transmute(hook.trampoline());
+      assert_eq!(original(), result);
+    }
+    hook.disable()?;
+    assert_eq!(target(), result);
+    Ok(())
+  }
+
+  /// Relocates `margin` bytes of a C function's prolog, near the function or
+  /// anywhere in memory, and asserts the return value of the relocated
+  /// function.
+  unsafe fn trampoline_test(target: CRet, margin: usize, far: bool, result: i32) -> Result<()> {
+    let target = target as *const ();
+    let mut pool = crate::util::lock(&memory::POOL);
+    let code = if far {
+      let (_, trampoline) = super::Trampoline::analyze_far(target, margin);
+      memory::allocate_pic_anywhere(&mut pool, trampoline?.emitter())?
+    } else {
+      let (_, trampoline) = super::Trampoline::analyze(target, margin);
+      memory::allocate_pic(&mut pool, trampoline?.emitter(), target)?
+    };
+    drop(pool);
+
+    let relocated: CRet = mem::transmute(code.as_ptr());
+    assert_eq!(relocated(), result);
+    Ok(())
+  }
+
   #[test]
   fn detour_relative_branch() -> Result<()> {
     #[unsafe(naked)]
@@ -57,6 +97,127 @@ mod tests {
     unsafe { detour_test(mem::transmute(branch_ret5 as usize), 5) }
   }
 
+  #[test]
+  fn detour_internal_jump() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn internal_jump_ret5() -> i32 {
+      naked_asm!(
+        "
+            jmp 2f
+            int3
+          2:
+            xor eax, eax
+            mov al, 5
+            ret",
+      );
+    }
+
+    unsafe { detour_test(internal_jump_ret5, 5) }
+  }
+
+  #[test]
+  fn relocate_internal_branches() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn early_out_ret5() -> i32 {
+      naked_asm!(
+        "
+            xor ecx, ecx
+            je 2f
+            mov eax, 1
+            jmp 3f
+          2:
+            jne 4f
+            mov eax, 5
+          3:
+            ret
+          4:
+            mov eax, 7
+            ret",
+      );
+    }
+
+    // The relocated branches and the absolute jcc are all larger than their
+    // originals, which moves each of the internal destinations.
+    unsafe { trampoline_test(early_out_ret5, 19, false, 5) }
+  }
+
+  #[test]
+  fn detour_branch_into_prolog() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn loop_ret3() -> i32 {
+      naked_asm!(
+        "
+            xor eax, eax
+          2:
+            add eax, 1
+            cmp eax, 3
+            jne 2b
+            ret",
+      );
+    }
+
+    let target = loop_ret3 as *const ();
+    let options = DetourOptions::new().scan_body(true);
+    let error =
+      unsafe { RawDetour::with_options(target, ret10 as *const (), &options) }.unwrap_err();
+    assert_eq!(
+      error,
+      Error::BranchIntoProlog {
+        target: target as usize,
+        source: target as usize + 8,
+        destination: target as usize + 2,
+      }
+    );
+
+    // The body is only scanned when requested
+    unsafe { RawDetour::new(target, ret10 as *const ())? };
+    Ok(())
+  }
+
+  #[test]
+  fn detour_branch_after_endbr() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn endbr_loop() -> i32 {
+      naked_asm!(
+        "
+            endbr64
+          2:
+            add eax, 1
+            cmp eax, 3
+            jb 2b
+            ret",
+      );
+    }
+
+    // The loop branches to the patch, after the preserved marker
+    let target = endbr_loop as *const ();
+    let options = DetourOptions::new().scan_body(true);
+    let hook = unsafe { RawDetour::with_options(target, ret10 as *const (), &options)? };
+    unsafe {
+      hook.enable()?;
+      assert_eq!(endbr_loop(), 10);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn detour_end_of_region() -> Result<()> {
+    // int3 (x5), xor eax, eax, ret
+    let code = [0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0x31, 0xC0, 0xC3];
+    let page_size = region::page::size();
+    let mut memory = region::alloc(page_size * 2, region::Protection::READ_WRITE_EXECUTE)?;
+
+    // The function ends at its region, which is followed by inaccessible memory
+    let target = unsafe {
+      let end = memory.as_mut_ptr::<u8>().add(page_size);
+      std::ptr::copy_nonoverlapping(code.as_ptr(), end.sub(code.len()), code.len());
+      region::protect(end, page_size, region::Protection::NONE)?;
+      end.sub(3)
+    };
+
+    unsafe { detour_test(mem::transmute(target), 0) }
+  }
+
   #[test]
   fn detour_hotpatch() -> Result<()> {
     #[unsafe(naked)]
@@ -95,22 +256,455 @@ mod tests {
   }
 
   #[test]
-  fn detour_external_loop() {
+  fn detour_endbr() -> Result<()> {
     #[unsafe(naked)]
-    unsafe extern "C" fn external_loop() -> i32 {
+    unsafe extern "C" fn endbr_ret5() -> i32 {
+      naked_asm!(
+        "
+            endbr64
+            mov eax, 5
+            ret",
+      );
+    }
+
+    let target = endbr_ret5 as *const () as usize;
+    let analysis = unsafe { crate::analyze(target as *const ()) };
+    assert_eq!(analysis.strategy, Some(PatchStrategy::Rel32));
+    assert_eq!(analysis.patch_area, Some(target + 4..target + 9));
+
+    // The marker remains at the target, and starts the relocated prolog
+    let hook = unsafe { RawDetour::new(target as *const (), ret10 as *const ())? };
+    unsafe { hook.enable()? };
+    let original = crate::live_detours()
+      .into_iter()
+      .find(|info| info.target == target as *const ())
+      .map(|info| info.original.start)
+      .unwrap();
+    let endbr = [0xF3, 0x0F, 0x1E, 0xFA];
+    assert_eq!(
+      unsafe { slice::from_raw_parts(target as *const u8, 4) },
+      endbr
+    );
+    assert_eq!(
+      unsafe { slice::from_raw_parts(original as *const u8, 4) },
+      endbr
+    );
+    drop(hook);
+
+    unsafe { detour_test(endbr_ret5, 5) }
+  }
+
+  #[test]
+  fn detour_endbr_hotpatch() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn endbr_hotpatch_ret0() -> i32 {
       naked_asm!(
         "
-            loop 2f
             nop
             nop
             nop
-            2:",
+            nop
+            nop
+            endbr64
+            xor eax, eax
+            ret",
+      );
+    }
+
+    let target = endbr_hotpatch_ret0 as *const () as usize + 5;
+    let analysis = unsafe { crate::analyze(target as *const ()) };
+    assert_eq!(analysis.strategy, Some(PatchStrategy::HotPatch));
+    assert_eq!(analysis.patch_area, Some(target - 5..target + 6));
+
+    unsafe { detour_test(mem::transmute::<usize, CRet>(target), 0) }
+  }
+
+  #[test]
+  fn detour_nop_sled() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn nop_sled_ret5() -> i32 {
+      naked_asm!(
+        "
+            nop
+            nop
+            nop
+            nop
+            nop
+            xchg ax, ax
+            mov eax, 5
+            ret",
+      );
+    }
+
+    // The sled is replaced instead of the code after it
+    let target = nop_sled_ret5 as *const () as usize + 5;
+    let analysis = unsafe { crate::analyze(target as *const ()) };
+    assert_eq!(analysis.strategy, Some(PatchStrategy::HotPatch));
+    assert_eq!(analysis.patch_area, Some(target - 5..target + 2));
+
+    unsafe { detour_test(mem::transmute::<usize, CRet>(target), 5) }
+  }
+
+  #[test]
+  fn detour_fentry() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn fentry() {
+      naked_asm!("ret");
+    }
+
+    #[unsafe(naked)]
+    unsafe extern "C" fn fentry_ret5() -> i32 {
+      naked_asm!(
+        "
+            call {fentry}
+            mov eax, 5
+            ret",
+        fentry = sym fentry,
+      );
+    }
+
+    // The call is replaced as a whole
+    let target = fentry_ret5 as *const () as usize;
+    let analysis = unsafe { crate::analyze(target as *const ()) };
+    assert_eq!(analysis.prolog_size, 5);
+    assert_eq!(analysis.patch_area, Some(target..target + 5));
+    assert_matches!(
+      analysis.instructions[0].relocation,
+      Relocation::CallToAbsolute { .. }
+    );
+
+    // The call returns into the trampoline, so it is never released
+    let (_, trampoline) = unsafe { super::Trampoline::analyze(target as *const (), 5) };
+    assert!(trampoline?.has_call());
+
+    unsafe { detour_test(fentry_ret5, 5) }
+  }
+
+  #[test]
+  fn patch_absolute() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn sum_ret6() -> i32 {
+      naked_asm!(
+        "
+            mov eax, 1
+            mov ecx, 2
+            mov edx, 3
+            add eax, ecx
+            add eax, edx
+            ret",
+      );
+    }
+
+    unsafe { strategy_test(sum_ret6, PatchStrategy::AbsoluteJump, 6)? };
+    unsafe { strategy_test(sum_ret6, PatchStrategy::PushRet, 6) }
+  }
+
+  #[test]
+  fn patch_short_jump() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn short_jump_ret0() -> i32 {
+      naked_asm!(
+        "
+            xor eax, eax
+            ret
+            int3
+            int3
+            int3
+            int3
+            int3
+            int3",
       );
     }
 
+    // The relative jump is placed in the padding after the function
+    let target = short_jump_ret0 as *const () as usize;
+    let options = DetourOptions::new().strategies(&[PatchStrategy::ShortJump]);
+    let hook =
+      unsafe { RawDetour::with_options(target as *const (), ret10 as *const (), &options)? };
+    let analysis = hook.analysis();
+    assert_eq!(analysis.patch_area, Some(target..target + 2));
+    assert_eq!(analysis.padding, Some(target + 3..target + 8));
+    drop(hook);
+
+    unsafe { strategy_test(short_jump_ret0, PatchStrategy::ShortJump, 0) }
+  }
+
+  #[test]
+  fn patch_strategy_preference() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn tiny_ret0() -> i32 {
+      naked_asm!(
+        "
+            xor eax, eax
+            ret",
+      );
+    }
+
+    // The failure of the preferred strategy is reported
+    let options =
+      DetourOptions::new().strategies(&[PatchStrategy::Rel32, PatchStrategy::AbsoluteJump]);
     let error =
-      unsafe { RawDetour::new(external_loop as *const (), ret10 as *const ()) }.unwrap_err();
-    assert_matches!(error, Error::UnsupportedInstruction);
+      unsafe { RawDetour::with_options(tiny_ret0 as *const (), ret10 as *const (), &options) };
+    assert_matches!(error, Err(Error::NoPatchArea { required: 5, .. }));
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn patch_breakpoint() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn breakpoint_ret0() -> i32 {
+      naked_asm!(
+        "
+            xor eax, eax
+            ret",
+      );
+    }
+
+    unsafe { strategy_test(breakpoint_ret0, PatchStrategy::Breakpoint, 0)? };
+
+    // The breakpoint is used by default once no other strategy fits
+    let hook = unsafe { RawDetour::new(breakpoint_ret0 as *const (), ret10 as *const ())? };
+    assert_eq!(hook.analysis().strategy, Some(PatchStrategy::Breakpoint));
+    unsafe { hook.enable()? };
+    assert_eq!(unsafe { breakpoint_ret0() }, 10);
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn patch_guard_page() -> Result<()> {
+    // The functions are placed on their own page, since any other code
+    // sharing it is stepped through.
+    let mut page = region::alloc(region::page::size(), region::Protection::READ_WRITE_EXECUTE)?;
+    let code: [(usize, &[u8]); 3] = [
+      // mov eax, 5; ret
+      (0x00, &[0xB8, 0x05, 0x00, 0x00, 0x00, 0xC3]),
+      // mov eax, 1; ret
+      (0x10, &[0xB8, 0x01, 0x00, 0x00, 0x00, 0xC3]),
+      // call 0x00; add eax, 1; ret
+      (
+        0x20,
+        &[0xE8, 0xDB, 0xFF, 0xFF, 0xFF, 0x83, 0xC0, 0x01, 0xC3],
+      ),
+    ];
+    let base = page.as_mut_ptr::<u8>();
+    for (offset, code) in &code {
+      unsafe {
+        base
+          .add(*offset)
+          .copy_from_nonoverlapping(code.as_ptr(), code.len())
+      };
+    }
+
+    let function = |offset: usize| unsafe { mem::transmute::<*mut u8, CRet>(base.add(offset)) };
+    let (target, other, caller) = (function(0x00), function(0x10), function(0x20));
+
+    let options = DetourOptions::new().strategies(&[PatchStrategy::GuardPage]);
+    let hook =
+      unsafe { RawDetour::with_options(target as *const (), ret10 as *const (), &options)? };
+    assert_eq!(hook.analysis().strategy, Some(PatchStrategy::GuardPage));
+
+    unsafe {
+      hook.enable()?;
+      let original = mem::transmute::<&(), CRet>(hook.trampoline());
+      assert_eq!(target(), 10);
+      assert_eq!(original(), 5);
+      assert_eq!(other(), 1);
+      assert_eq!(caller(), 11);
+
+      // The target's code is never modified, and its page remains guarded
+      // whilst other code is executed
+      assert_eq!(slice::from_raw_parts(base, 6), code[0].1);
+      let protection = region::query(base)?.protection();
+      assert!(!protection.contains(region::Protection::EXECUTE));
+
+      hook.disable()?;
+      assert_eq!(target(), 5);
+      assert_eq!(caller(), 6);
+    }
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+  fn patch_guard_page_relative() -> Result<()> {
+    let mut page = region::alloc(region::page::size(), region::Protection::READ_WRITE_EXECUTE)?;
+    let code: [(usize, &[u8]); 3] = [
+      // mov eax, 5; ret
+      (0x00, &[0xB8, 0x05, 0x00, 0x00, 0x00, 0xC3]),
+      // mov eax, [rip+0x0A]; ret
+      (0x10, &[0x8B, 0x05, 0x0A, 0x00, 0x00, 0x00, 0xC3]),
+      (0x20, &[0x07, 0x00, 0x00, 0x00]),
+    ];
+    let base = page.as_mut_ptr::<u8>();
+    for (offset, code) in &code {
+      unsafe {
+        base
+          .add(*offset)
+          .copy_from_nonoverlapping(code.as_ptr(), code.len())
+      };
+    }
+
+    let function = |offset: usize| unsafe { mem::transmute::<*mut u8, CRet>(base.add(offset)) };
+    let (target, relative) = (function(0x00), function(0x10));
+
+    // The operand of a copied instruction still refers to the guarded page
+    let options = DetourOptions::new().strategies(&[PatchStrategy::GuardPage]);
+    let hook =
+      unsafe { RawDetour::with_options(target as *const (), ret10 as *const (), &options)? };
+    unsafe {
+      hook.enable()?;
+      assert_eq!(target(), 10);
+      assert_eq!(relative(), 7);
+    }
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+  fn patch_guard_page_syscall() -> Result<()> {
+    let mut page = region::alloc(region::page::size(), region::Protection::READ_WRITE_EXECUTE)?;
+    let code: [(usize, &[u8]); 2] = [
+      // mov eax, 5; ret
+      (0x00, &[0xB8, 0x05, 0x00, 0x00, 0x00, 0xC3]),
+      // mov eax, 39 (getpid); syscall; ret
+      (0x10, &[0xB8, 0x27, 0x00, 0x00, 0x00, 0x0F, 0x05, 0xC3]),
+    ];
+    let base = page.as_mut_ptr::<u8>();
+    for (offset, code) in &code {
+      unsafe {
+        base
+          .add(*offset)
+          .copy_from_nonoverlapping(code.as_ptr(), code.len())
+      };
+    }
+
+    let function = |offset: usize| unsafe { mem::transmute::<*mut u8, CRet>(base.add(offset)) };
+    let (target, getpid) = (function(0x00), function(0x10));
+
+    // A system call is also executed from a copy, whilst the page is guarded
+    let options = DetourOptions::new().strategies(&[PatchStrategy::GuardPage]);
+    let hook =
+      unsafe { RawDetour::with_options(target as *const (), ret10 as *const (), &options)? };
+    unsafe {
+      hook.enable()?;
+      assert_eq!(getpid(), std::process::id() as i32);
+      assert_eq!(target(), 10);
+      let protection = region::query(base)?.protection();
+      assert!(!protection.contains(region::Protection::EXECUTE));
+    }
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn patch_guard_page_shared() -> Result<()> {
+    let mut page = region::alloc(region::page::size(), region::Protection::READ_WRITE_EXECUTE)?;
+    let code: [(usize, &[u8]); 2] = [
+      // mov eax, 5; ret
+      (0x00, &[0xB8, 0x05, 0x00, 0x00, 0x00, 0xC3]),
+      // mov eax, 1; ret
+      (0x10, &[0xB8, 0x01, 0x00, 0x00, 0x00, 0xC3]),
+    ];
+    let base = page.as_mut_ptr::<u8>();
+    for (offset, code) in &code {
+      unsafe {
+        base
+          .add(*offset)
+          .copy_from_nonoverlapping(code.as_ptr(), code.len())
+      };
+    }
+
+    let function = |offset: usize| unsafe { mem::transmute::<*mut u8, CRet>(base.add(offset)) };
+    let (first, second) = (function(0x00), function(0x10));
+
+    let options = DetourOptions::new().strategies(&[PatchStrategy::GuardPage]);
+    unsafe {
+      let first_hook = RawDetour::with_options(first as *const (), ret10 as *const (), &options)?;
+      first_hook.enable()?;
+
+      // The second target is guarded whilst the page is not executable
+      let second_hook = RawDetour::with_options(second as *const (), ret10 as *const (), &options)?;
+      second_hook.enable()?;
+      assert_eq!(first(), 10);
+      assert_eq!(second(), 10);
+
+      drop(first_hook);
+      assert_eq!(first(), 5);
+      assert_eq!(second(), 10);
+
+      // The page's original protection is restored
+      second_hook.disable()?;
+      assert_eq!(first(), 5);
+      assert_eq!(second(), 1);
+      let protection = region::query(base)?.protection();
+      assert!(protection.contains(region::Protection::EXECUTE));
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn detour_external_loop() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn external_loop_ret5() -> i32 {
+      naked_asm!(
+        "
+            xor ecx, ecx
+            mov cl, 2
+            loop 2f
+            mov eax, 1
+            ret
+          2:
+            mov eax, 5
+            ret",
+      );
+    }
+
+    #[unsafe(naked)]
+    unsafe extern "C" fn external_loop_ret1() -> i32 {
+      naked_asm!(
+        "
+            xor ecx, ecx
+            mov cl, 1
+            loop 2f
+            mov eax, 1
+            ret
+          2:
+            mov eax, 5
+            ret",
+      );
+    }
+
+    let analysis = unsafe { crate::analyze(external_loop_ret5 as *const ()) };
+    assert!(analysis.is_hookable());
+    assert_matches!(
+      analysis.instructions[2].relocation,
+      Relocation::LoopToAbsolute { .. }
+    );
+
+    unsafe { detour_test(external_loop_ret5, 5)? };
+    unsafe { detour_test(external_loop_ret1, 1) }
+  }
+
+  #[test]
+  fn detour_external_jecxz() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn external_jecxz_ret5() -> i32 {
+      naked_asm!(
+        "
+            xor ecx, ecx
+            xor eax, eax
+            jecxz 2f
+            mov eax, 1
+            ret
+          2:
+            mov eax, 5
+            ret",
+      );
+    }
+
+    unsafe { detour_test(external_jecxz_ret5, 5) }
   }
 
   #[test]
@@ -148,6 +742,59 @@ mod tests {
     unsafe { detour_test(rip_relative_prolog_ret49, 49) }
   }
 
+  #[test]
+  #[cfg(target_arch = "x86_64")]
+  fn far_rip_relative_load() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn rip_relative_ret195() -> i32 {
+      naked_asm!(
+        "
+            xor eax, eax
+            mov al, [rip+0x3]
+            nop
+            nop
+            nop
+            ret",
+      );
+    }
+
+    unsafe { trampoline_test(rip_relative_ret195, 5, true, 195) }
+  }
+
+  #[test]
+  #[cfg(target_arch = "x86_64")]
+  fn far_rip_relative_lea() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn rip_relative_lea_ret195() -> i32 {
+      naked_asm!(
+        "
+            lea rcx, [rip+0x3]
+            movzx eax, byte ptr [rcx]
+            ret",
+      );
+    }
+
+    unsafe { trampoline_test(rip_relative_lea_ret195, 5, true, 195) }
+  }
+
+  #[test]
+  #[cfg(target_arch = "x86_64")]
+  fn far_rip_relative_cmp() -> Result<()> {
+    #[unsafe(naked)]
+    unsafe extern "C" fn rip_relative_cmp_ret1() -> i32 {
+      naked_asm!(
+        "
+            xor eax, eax
+            cmp byte ptr [rip+0x3], 0xC3
+            sete al
+            ret",
+      );
+    }
+
+    // The flags must survive the restoration of the scratch register
+    unsafe { trampoline_test(rip_relative_cmp_ret1, 5, true, 1) }
+  }
+
   /// Default detour target.
   unsafe extern "C" fn ret10() -> i32 {
     10
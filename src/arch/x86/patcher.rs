@@ -1,12 +1,25 @@
-use super::thunk;
+use super::{meta, thunk};
+use crate::arch::WriteStrategy;
+use crate::detours::PatchStrategy;
 use crate::error::{Error, Result};
-use crate::{pic, util};
+use crate::{pic, trap, util};
+use iced_x86::{Decoder, DecoderOptions, Mnemonic};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::{mem, slice};
 
+/// The furthest a short jump reaches before and after itself.
+const SHORT_JUMP_RANGE: Range<isize> = -126..128;
+
 pub struct Patcher {
-  patch_area: &'static mut [u8],
-  original_prolog: Vec<u8>,
-  detour_prolog: Vec<u8>,
+  strategy: PatchStrategy,
+  /// The bytes that replace live code.
+  live: &'static mut [u8],
+  /// Unused padding holding the long jump of a short jump, if any.
+  padding: Option<&'static mut [u8]>,
+  /// The original code of the padding, followed by that of the live code.
+  original: Vec<u8>,
+  write_strategy: WriteStrategy,
 }
 
 impl Patcher {
@@ -15,118 +28,527 @@ impl Patcher {
   /// # Arguments
   ///
   /// * `target` - An address that should be hooked.
-  /// * `detour` - An address that the target should be redirected to.
   /// * `prolog_size` - The available inline space for the hook.
-  pub unsafe fn new(target: *const (), detour: *const (), prolog_size: usize) -> Result<Patcher> {
-    // Calculate the patch area (i.e if a short or long jump should be used)
-    let patch_area = Self::patch_area(target, prolog_size)?;
-    let emitter = Self::hook_template(detour, patch_area);
+  /// * `strategy` - The way the target is patched.
+  /// * `reserved` - Code claimed by other patches, which cannot be used as
+  ///   padding.
+  pub unsafe fn new(
+    target: *const (),
+    prolog_size: usize,
+    strategy: PatchStrategy,
+    reserved: &[Range<usize>],
+  ) -> Result<Patcher> {
+    // The patch is placed after the entry of the function (i.e `endbr`),
+    // whilst a guard is placed on the address that is executed.
+    let start = match strategy {
+      PatchStrategy::GuardPage => target as usize,
+      _ => target as usize + meta::entry_size(target),
+    };
+    let available = prolog_size.saturating_sub(start - target as usize);
+    let patch_size = Self::patch_size(strategy);
+
+    if !Self::is_patchable(start, available, patch_size) {
+      Err(Self::no_patch_area(target, available, patch_size))?;
+    }
+
+    let is_trap = matches!(
+      strategy,
+      PatchStrategy::Breakpoint | PatchStrategy::GuardPage
+    );
+    if is_trap && !trap::is_supported() {
+      Err(Error::TrapFailed)?;
+    }
+
+    // A short jump relies on a long jump placed in padding
+    let padding = match strategy {
+      PatchStrategy::HotPatch => Some(Self::hot_patch_padding(target)?),
+      PatchStrategy::ShortJump => Some(Self::nearby_padding(start, reserved)?),
+      _ => None,
+    };
+
+    let jump_rel32_size = mem::size_of::<thunk::x86::JumpRel>();
+    let padding = match padding {
+      Some(Some(padding)) => Some(slice::from_raw_parts_mut(
+        padding as *mut u8,
+        jump_rel32_size,
+      )),
+      Some(None) => Err(Self::no_patch_area(target, available, patch_size))?,
+      None => None,
+    };
+    let live = slice::from_raw_parts_mut(start as *mut u8, patch_size);
 
-    let patch_address = patch_area.as_ptr() as *const ();
-    let original_prolog = patch_area.to_vec();
+    let mut original = padding.as_deref().map_or_else(Vec::new, <[u8]>::to_vec);
+    original.extend_from_slice(live);
 
     Ok(Patcher {
-      detour_prolog: emitter.emit(patch_address),
-      original_prolog,
-      patch_area,
+      write_strategy: Self::write_strategy_for(live),
+      strategy,
+      live,
+      padding,
+      original,
     })
   }
 
+  /// Returns the strategies to attempt for a target, in order of preference.
+  ///
+  /// A NOP sled too short for a long jump (e.g from
+  /// `-fpatchable-function-entry`) is replaced by the short jump of a hot
+  /// patch, instead of overwriting the code after it. A single instruction
+  /// covering the patch, such as a `call __fentry__` or a multi-byte NOP, is
+  /// replaced without any thread being able to execute part of it.
+  pub unsafe fn strategies(target: *const (), preferred: &[PatchStrategy]) -> Vec<PatchStrategy> {
+    let jump_rel08_size = mem::size_of::<thunk::x86::JumpShort>();
+    let jump_rel32_size = mem::size_of::<thunk::x86::JumpRel>();
+
+    let start = target as usize + meta::entry_size(target);
+    let sled = Self::sled_size(start, jump_rel32_size);
+
+    let mut strategies = preferred.to_vec();
+    if sled >= jump_rel08_size && sled < jump_rel32_size {
+      strategies.sort_by_key(|&strategy| strategy != PatchStrategy::HotPatch);
+    }
+    strategies
+  }
+
+  /// Returns the number of bytes of live code replaced by a strategy.
+  pub fn patch_size(strategy: PatchStrategy) -> usize {
+    match strategy {
+      PatchStrategy::HotPatch | PatchStrategy::ShortJump => mem::size_of::<thunk::x86::JumpShort>(),
+      PatchStrategy::Breakpoint => thunk::x86::int3().len(),
+      PatchStrategy::GuardPage => 0,
+      PatchStrategy::AbsoluteJump => thunk::jmp_inline(0).len(),
+      PatchStrategy::PushRet => thunk::push_ret(0).len(),
+      PatchStrategy::Rel32 => mem::size_of::<thunk::x86::JumpRel>(),
+    }
+  }
+
+  /// Returns the way the target is patched.
+  pub fn strategy(&self) -> PatchStrategy {
+    self.strategy
+  }
+
+  /// Returns whether the patch relies on a relative jump, which may require
+  /// a relay to reach its destination.
+  pub fn is_relative(&self) -> bool {
+    !matches!(
+      self.strategy,
+      PatchStrategy::AbsoluteJump
+        | PatchStrategy::PushRet
+        | PatchStrategy::Breakpoint
+        | PatchStrategy::GuardPage
+    )
+  }
+
   /// Returns the target's patch area.
-  pub fn area(&self) -> &[u8] {
-    self.patch_area
+  ///
+  /// The area of a hot patch extends from its padding above the function.
+  pub fn area(&self) -> Range<usize> {
+    let start = self.live.as_ptr() as usize;
+    match self.padding() {
+      Some(padding) if self.strategy == PatchStrategy::HotPatch => {
+        padding.start..start + self.live.len()
+      },
+      _ => start..start + self.live.len(),
+    }
+  }
+
+  /// Returns the padding holding the long jump of a short jump, if any.
+  pub fn padding(&self) -> Option<Range<usize>> {
+    self.padding.as_ref().map(|padding| {
+      let start = padding.as_ptr() as usize;
+      start..start + padding.len()
+    })
+  }
+
+  /// Returns all code modified by the patch.
+  ///
+  /// A guard modifies no code, only the protection of its page.
+  pub fn areas(&self) -> Vec<Range<usize>> {
+    let live = self.live.as_ptr() as usize;
+    self
+      .padding()
+      .into_iter()
+      .chain(Some(live..live + self.live.len()))
+      .filter(|area| !area.is_empty())
+      .collect()
+  }
+
+  /// Returns how the live code is replaced.
+  pub fn write_strategy(&self) -> WriteStrategy {
+    self.write_strategy
   }
 
-  /// Either patches or unpatches the function.
-  pub unsafe fn toggle(&mut self, enable: bool) {
-    // Copy either the detour or the original bytes of the function
-    self.patch_area.copy_from_slice(if enable {
-      &self.detour_prolog
+  /// Returns the code redirecting the target to a detour.
+  ///
+  /// The code of the padding, if any, precedes that of the live code.
+  pub fn hook(&self, detour: *const ()) -> Result<Vec<u8>> {
+    let live = self.live.as_ptr() as usize;
+    let mut code = Vec::with_capacity(self.original.len());
+    let mut emitter = pic::CodeEmitter::new();
+
+    if let Some(padding) = self.padding() {
+      // The short jump leads to a relative long jump in the padding
+      let mut padding_emitter = pic::CodeEmitter::new();
+      padding_emitter.add_thunk(thunk::x86::jmp_rel32(detour as usize));
+      code.extend(padding_emitter.emit(padding.start as *const ())?);
+
+      let displacement = padding.start as isize - live as isize;
+      emitter.add_thunk(thunk::x86::jmp_rel8(displacement as i8));
     } else {
-      &self.original_prolog
-    });
+      emitter.add_thunk(match self.strategy {
+        PatchStrategy::AbsoluteJump => thunk::jmp_inline(detour as usize),
+        PatchStrategy::PushRet => thunk::push_ret(detour as usize),
+        PatchStrategy::Breakpoint => {
+          // The breakpoint's destination is assigned once it is written
+          trap::reserve(live)?;
+          thunk::x86::int3()
+        },
+        PatchStrategy::GuardPage => {
+          // The target is left as is, and its page is guarded once written
+          trap::reserve_guard(live)?;
+          return Ok(code);
+        },
+        PatchStrategy::Rel32 | PatchStrategy::HotPatch | PatchStrategy::ShortJump => {
+          thunk::x86::jmp_rel32(detour as usize)
+        },
+      });
+    }
+
+    code.extend(emitter.emit(live as *const ())?);
+    Ok(code)
   }
 
-  /// Returns the patch area for a function, consisting of a long jump and
-  /// possibly a short jump.
-  unsafe fn patch_area(target: *const (), prolog_size: usize) -> Result<&'static mut [u8]> {
-    let jump_rel08_size = mem::size_of::<thunk::x86::JumpShort>();
-    let jump_rel32_size = mem::size_of::<thunk::x86::JumpRel>();
+  /// Returns the original code of the patch.
+  pub fn original(&self) -> &[u8] {
+    &self.original
+  }
 
-    // Check if there isn't enough space for a relative long jump
-    if !Self::is_patchable(target, prolog_size, jump_rel32_size) {
-      // ... check if a relative small jump fits instead
-      if Self::is_patchable(target, prolog_size, jump_rel08_size) {
-        // A small jump relies on there being a hot patch area above the
-        // function, that consists of at least 5 bytes (a rel32 jump).
-        let hot_patch = target as usize - jump_rel32_size;
-        let hot_patch_area = slice::from_raw_parts(hot_patch as *const u8, jump_rel32_size);
-
-        // Ensure that the hot patch area only contains padding and is executable
-        if !Self::is_code_padding(hot_patch_area)
-          || !util::is_executable_address(hot_patch_area.as_ptr() as *const _)?
-        {
-          Err(Error::NoPatchArea)?;
-        }
-
-        // The range is from the start of the hot patch to the end of the jump
-        let patch_size = jump_rel32_size + jump_rel08_size;
-        Ok(slice::from_raw_parts_mut(hot_patch as *mut u8, patch_size))
-      } else {
-        Err(Error::NoPatchArea)
+  /// Writes code to the patch, as returned by `hook` or `original`.
+  ///
+  /// When a destination is set, the padding is written before the live code,
+  /// otherwise after it.
+  pub unsafe fn write(&mut self, code: &[u8], destination: Option<usize>) {
+    let (padding_code, live_code) = code.split_at(code.len() - self.live.len());
+    let live = &mut *self.live;
+    let enable = destination.is_some();
+
+    // A breakpoint hit whilst it is removed resumes at the original code
+    if self.strategy == PatchStrategy::Breakpoint {
+      let address = live.as_ptr() as usize;
+      trap::redirect(address, destination.unwrap_or(address));
+    }
+
+    // A guard replaces no code, and takes effect once its page is protected
+    if self.strategy == PatchStrategy::GuardPage {
+      trap::redirect_guard(live.as_ptr() as usize, destination);
+      return;
+    }
+
+    // The padding must contain the long jump before the short jump to it is
+    // written, and vice versa when the patch is removed.
+    if enable {
+      if let Some(padding) = self.padding.as_deref_mut() {
+        write_code(padding, padding_code);
+      }
+    }
+
+    match self.write_strategy {
+      WriteStrategy::Atomic64 => write_atomic64(live, live_code),
+      #[cfg(target_arch = "x86_64")]
+      WriteStrategy::Atomic128 => write_atomic128(live, live_code),
+      _ => live.copy_from_slice(live_code),
+    }
+
+    if !enable {
+      if let Some(padding) = self.padding.as_deref_mut() {
+        write_code(padding, padding_code);
       }
-    } else {
-      // The range is from the start of the function to the end of the jump
-      Ok(slice::from_raw_parts_mut(
-        target as *mut u8,
-        jump_rel32_size,
-      ))
     }
   }
 
-  /// Creates a redirect code template for the targetted patch area.
-  fn hook_template(detour: *const (), patch_area: &[u8]) -> pic::CodeEmitter {
-    let mut emitter = pic::CodeEmitter::new();
+  /// Returns the padding above the function used by a hot patch, if any.
+  unsafe fn hot_patch_padding(target: *const ()) -> Result<Option<usize>> {
+    let jump_rel32_size = mem::size_of::<thunk::x86::JumpRel>();
+
+    // A small jump relies on there being a hot patch area above the
+    // function, that consists of at least 5 bytes (a rel32 jump).
+    let hot_patch = match (target as usize).checked_sub(jump_rel32_size) {
+      Some(hot_patch) => hot_patch,
+      None => return Ok(None),
+    };
 
-    // Both hot patch and normal detours use a relative long jump
-    emitter.add_thunk(thunk::x86::jmp_rel32(detour as usize));
+    // Ensure that the hot patch area only contains padding and is executable
+    let is_usable = Self::is_padding_at(hot_patch, jump_rel32_size)
+      && util::is_executable_address(hot_patch as *const _)?;
+    Ok(Some(hot_patch).filter(|_| is_usable))
+  }
 
-    // The hot patch relies on a small jump to get to the long jump
+  /// Returns the closest unclaimed run of `int3` padding within reach of a
+  /// short jump, if any.
+  ///
+  /// Unlike NOPs, which may be executed (e.g for alignment within a
+  /// function), `int3` is only used between functions.
+  unsafe fn nearby_padding(source: usize, reserved: &[Range<usize>]) -> Result<Option<usize>> {
+    let jump_rel08_size = mem::size_of::<thunk::x86::JumpShort>() as isize;
     let jump_rel32_size = mem::size_of::<thunk::x86::JumpRel>();
-    let uses_hot_patch = patch_area.len() > jump_rel32_size;
 
-    if uses_hot_patch {
-      let displacement = -(jump_rel32_size as i8);
-      emitter.add_thunk(thunk::x86::jmp_rel8(displacement));
+    // Both runs following and preceding the jump are searched
+    let after = source + jump_rel08_size as usize;
+    let before = source.saturating_sub(-SHORT_JUMP_RANGE.start as usize);
+    let mut candidates = Vec::new();
+    for start in [after, before] {
+      let end = if start == after {
+        source.saturating_add(SHORT_JUMP_RANGE.end as usize)
+      } else {
+        source
+      };
+      if let Ok(code) = util::read_memory(start, end.saturating_sub(start)) {
+        candidates.extend(
+          code
+            .windows(jump_rel32_size)
+            .enumerate()
+            .filter(|(_, window)| window.iter().all(|&code| code == 0xCC))
+            .map(|(offset, _)| start + offset),
+        );
+      }
     }
 
-    // Pad leftover bytes with nops
-    while emitter.len() < patch_area.len() {
-      emitter.add_thunk(thunk::x86::nop());
+    candidates.retain(|&padding| {
+      let displacement = padding as isize - source as isize;
+      let area = padding..padding + jump_rel32_size;
+      SHORT_JUMP_RANGE.contains(&displacement)
+        && !reserved
+          .iter()
+          .any(|other| other.start < area.end && area.start < other.end)
+    });
+    candidates.sort_by_key(|&padding| (padding as isize - source as isize).abs());
+
+    for padding in candidates {
+      if util::is_executable_address(padding as *const _)? {
+        return Ok(Some(padding));
+      }
     }
+    Ok(None)
+  }
 
-    emitter
+  /// Returns an error for a target without room for a patch.
+  fn no_patch_area(target: *const (), available: usize, required: usize) -> Error {
+    Error::NoPatchArea {
+      target: target as usize,
+      available,
+      required,
+    }
   }
 
   /// Returns whether an address can be inline patched or not.
-  unsafe fn is_patchable(target: *const (), prolog_size: usize, patch_size: usize) -> bool {
+  unsafe fn is_patchable(address: usize, prolog_size: usize, patch_size: usize) -> bool {
     if prolog_size >= patch_size {
       // If the whole patch fits it's good to go!
       return true;
     }
 
     // Otherwise the inline patch relies on padding after the prolog
-    let slice = slice::from_raw_parts(
-      (target as usize + prolog_size) as *const u8,
-      patch_size - prolog_size,
-    );
+    Self::is_padding_at(address + prolog_size, patch_size - prolog_size)
+  }
+
+  /// Returns the size of the NOP instructions at an address, up to a limit.
+  unsafe fn sled_size(address: usize, len: usize) -> usize {
+    util::read_code(address, len).map_or(0, |code| Self::nop_size(&code))
+  }
+
+  /// Returns whether the memory at an address is readable and only contains
+  /// code padding.
+  unsafe fn is_padding_at(address: usize, len: usize) -> bool {
+    util::read_memory(address, len).map_or(false, |padding| {
+      padding.len() == len && Self::is_code_padding(&padding)
+    })
+  }
 
-    Self::is_code_padding(slice)
+  /// Returns the widest atomic write that covers the live code.
+  fn write_strategy_for(live: &[u8]) -> WriteStrategy {
+    let start = live.as_ptr() as usize;
+    let end = start + live.len() - 1;
+
+    if start / 8 == end / 8 {
+      WriteStrategy::Atomic64
+    } else if cfg!(target_arch = "x86_64") && start / 16 == end / 16 && has_cmpxchg16b() {
+      WriteStrategy::Atomic128
+    } else {
+      WriteStrategy::Copy
+    }
   }
 
   /// Returns true if the slice only contains code padding.
   fn is_code_padding(buffer: &[u8]) -> bool {
     const PADDING: [u8; 3] = [0x00, 0x90, 0xCC];
-    buffer.iter().all(|code| PADDING.contains(code))
+    buffer.iter().all(|code| PADDING.contains(code)) || Self::nop_size(buffer) == buffer.len()
+  }
+
+  /// Returns the size of the leading NOP instructions (including multi-byte
+  /// NOPs) of some code.
+  fn nop_size(code: &[u8]) -> usize {
+    let bitness = (mem::size_of::<usize>() * 8) as u32;
+    Decoder::new(bitness, code, DecoderOptions::NONE)
+      .into_iter()
+      .take_while(|instruction| instruction.mnemonic() == Mnemonic::Nop)
+      .map(|instruction| instruction.len())
+      .sum()
+  }
+}
+
+impl Drop for Patcher {
+  fn drop(&mut self) {
+    match self.strategy {
+      PatchStrategy::Breakpoint => trap::release(self.live.as_ptr() as usize),
+      PatchStrategy::GuardPage => trap::release_guard(self.live.as_ptr() as usize),
+      _ => (),
+    }
+  }
+}
+
+/// Replaces bytes using the widest atomic write that covers them.
+///
+/// The long jump of a hot patch is live code once the short jump has been
+/// written, so it is replaced atomically when retargeted, if possible.
+unsafe fn write_code(destination: &mut [u8], code: &[u8]) {
+  if destination == code {
+    return;
+  }
+
+  match Patcher::write_strategy_for(destination) {
+    WriteStrategy::Atomic64 => write_atomic64(destination, code),
+    #[cfg(target_arch = "x86_64")]
+    WriteStrategy::Atomic128 => write_atomic128(destination, code),
+    _ => destination.copy_from_slice(code),
+  }
+}
+
+/// Replaces bytes within an aligned 8-byte word using a compare-exchange.
+unsafe fn write_atomic64(destination: &mut [u8], code: &[u8]) {
+  let address = destination.as_ptr() as usize;
+  let offset = address % 8;
+  let word = &*((address - offset) as *const AtomicU64);
+
+  let mut current = word.load(Ordering::SeqCst);
+  loop {
+    let mut bytes = current.to_ne_bytes();
+    bytes[offset..offset + code.len()].copy_from_slice(code);
+
+    match word.compare_exchange(
+      current,
+      u64::from_ne_bytes(bytes),
+      Ordering::SeqCst,
+      Ordering::SeqCst,
+    ) {
+      Ok(_) => break,
+      Err(actual) => current = actual,
+    }
+  }
+}
+
+/// Replaces bytes within an aligned 16-byte word using `cmpxchg16b`.
+#[cfg(target_arch = "x86_64")]
+unsafe fn write_atomic128(destination: &mut [u8], code: &[u8]) {
+  use std::arch::asm;
+
+  let address = destination.as_ptr() as usize;
+  let offset = address % 16;
+  let word = (address - offset) as *mut u128;
+
+  let mut current = std::ptr::read_volatile(word);
+  loop {
+    let mut bytes = current.to_ne_bytes();
+    bytes[offset..offset + code.len()].copy_from_slice(code);
+    let new = u128::from_ne_bytes(bytes);
+
+    let (low, high): (u64, u64);
+    // RBX is reserved by LLVM, so it is swapped in and out manually
+    asm!(
+      "xchg {new_low}, rbx",
+      "lock cmpxchg16b xmmword ptr [{word}]",
+      "mov rbx, {new_low}",
+      word = in(reg) word,
+      new_low = inout(reg) new as u64 => _,
+      in("rcx") (new >> 64) as u64,
+      inout("rax") current as u64 => low,
+      inout("rdx") (current >> 64) as u64 => high,
+      options(nostack),
+    );
+
+    let actual = (high as u128) << 64 | low as u128;
+    if actual == current {
+      break;
+    }
+    current = actual;
+  }
+}
+
+/// Returns true if the processor supports `cmpxchg16b`.
+fn has_cmpxchg16b() -> bool {
+  #[cfg(target_arch = "x86_64")]
+  {
+    // CPUID.01H:ECX.CX16[bit 13]
+    #[allow(unused_unsafe)]
+    unsafe {
+      std::arch::x86_64::__cpuid(1).ecx & (1 << 13) != 0
+    }
+  }
+  #[cfg(not(target_arch = "x86_64"))]
+  {
+    false
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[repr(align(16))]
+  struct Aligned([u8; 32]);
+
+  #[test]
+  fn write_strategy_depends_on_alignment() {
+    let code = Aligned([0xCC; 32]);
+    assert_eq!(
+      Patcher::write_strategy_for(&code.0[1..6]),
+      WriteStrategy::Atomic64
+    );
+    assert_eq!(
+      Patcher::write_strategy_for(&code.0[14..19]),
+      WriteStrategy::Copy
+    );
+
+    let expected = if has_cmpxchg16b() {
+      WriteStrategy::Atomic128
+    } else {
+      WriteStrategy::Copy
+    };
+    assert_eq!(Patcher::write_strategy_for(&code.0[6..11]), expected);
+  }
+
+  #[test]
+  fn multi_byte_nops_are_padding() {
+    assert!(Patcher::is_code_padding(&[0x0F, 0x1F, 0x44, 0x00, 0x00]));
+    assert!(Patcher::is_code_padding(&[
+      0x66, 0x90, 0x90, 0x0F, 0x1F, 0x00
+    ]));
+    assert!(!Patcher::is_code_padding(&[0x66, 0x90, 0x31, 0xC0, 0xC3]));
+    assert_eq!(Patcher::nop_size(&[0x66, 0x90, 0x90, 0x31, 0xC0]), 3);
+  }
+
+  #[test]
+  fn atomic_writes_preserve_surrounding_bytes() {
+    let mut code = Aligned([0xCC; 32]);
+    unsafe { write_atomic64(&mut code.0[3..8], &[1, 2, 3, 4, 5]) };
+    assert_eq!(code.0[..9], [0xCC, 0xCC, 0xCC, 1, 2, 3, 4, 5, 0xCC]);
+
+    #[cfg(target_arch = "x86_64")]
+    if has_cmpxchg16b() {
+      unsafe { write_atomic128(&mut code.0[22..29], &[6, 7, 8, 9, 10, 11, 12]) };
+      assert_eq!(
+        code.0[16..],
+        [0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 0xCC, 6, 7, 8, 9, 10, 11, 12, 0xCC, 0xCC, 0xCC]
+      );
+    }
   }
 }
@@ -0,0 +1,66 @@
+use super::meta;
+use crate::error::{Error, Result};
+use crate::util;
+use iced_x86::{Decoder, DecoderOptions, FlowControl, Mnemonic, OpKind};
+use std::mem;
+
+/// The maximum amount of bytes scanned after the prolog.
+const MAX_BODY_SIZE: usize = 0x1000;
+
+/// Returns an error if the body of a function branches into its prolog.
+///
+/// Branches to the start of the function (or of its patch, after an `endbr`
+/// marker) are allowed, since they execute the patch as any other caller
+/// would. The body is scanned until its first return or jump that is not
+/// branched over (as a heuristic for the end of the function), or until the
+/// end of its memory region or symbol.
+pub unsafe fn check_body(target: *const (), prolog_size: usize) -> Result<()> {
+  let start = target as usize + prolog_size;
+  let body = match util::read_code(start, MAX_BODY_SIZE) {
+    Ok(body) => body,
+    // The function ends with its prolog
+    Err(_) => return Ok(()),
+  };
+
+  let decoder = Decoder::with_ip(
+    (mem::size_of::<usize>() * 8) as u32,
+    &body,
+    start as u64,
+    DecoderOptions::NONE,
+  );
+
+  // The patch follows any entry marker, which is executed as before
+  let prolog = (target as usize + meta::entry_size(target) + 1)..start;
+  let mut furthest_branch = start;
+  for instruction in decoder {
+    if instruction.is_invalid() || instruction.mnemonic() == Mnemonic::Int3 {
+      break;
+    }
+
+    if let OpKind::NearBranch16 | OpKind::NearBranch32 | OpKind::NearBranch64 =
+      instruction.op0_kind()
+    {
+      let destination = instruction.near_branch_target() as usize;
+      if prolog.contains(&destination) {
+        Err(Error::BranchIntoProlog {
+          target: target as usize,
+          source: instruction.ip() as usize,
+          destination,
+        })?;
+      }
+      if !instruction.is_call_near() {
+        furthest_branch = furthest_branch.max(destination);
+      }
+    }
+
+    // The function ends unless the code after this is branched to
+    let terminates = matches!(
+      instruction.flow_control(),
+      FlowControl::Return | FlowControl::UnconditionalBranch | FlowControl::IndirectBranch
+    );
+    if terminates && instruction.next_ip() as usize >= furthest_branch {
+      break;
+    }
+  }
+  Ok(())
+}
@@ -1,5 +1,9 @@
 #![allow(dead_code)]
 
+use crate::pic::{CodeEmitter, Thunkable};
+use iced_x86::{Code, Encoder, IcedError, Instruction};
+use std::mem;
+
 /// Implements x86 operations
 pub mod x86;
 
@@ -10,16 +14,239 @@ pub mod x64;
 #[cfg(target_arch = "x86")]
 mod arch {
   pub use super::x86::call_rel32 as call;
-  pub use super::x86::jcc_rel32 as jcc;
+  pub use super::x86::jmp_abs as jmp_inline;
   pub use super::x86::jmp_rel32 as jmp;
+  pub use super::x86::push_ret;
+  pub use super::x86::{closure_epilog, closure_prolog};
+  pub use super::x86::{exit_epilog, exit_prolog, relay_epilog, relay_prolog};
 }
 
 #[cfg(target_arch = "x86_64")]
 mod arch {
   pub use super::x64::call_abs as call;
-  pub use super::x64::jcc_abs as jcc;
+  pub use super::x64::closure_epilog as relay_epilog;
   pub use super::x64::jmp_abs as jmp;
+  pub use super::x64::jmp_abs as jmp_inline;
+  pub use super::x64::push_ret;
+  pub use super::x64::{closure_epilog, closure_prolog};
+  pub use super::x64::{exit_epilog, exit_prolog, relay_prolog};
 }
 
 // Export the default architecture
 pub use self::arch::*;
+
+/// The bitness of the current architecture.
+pub const BITNESS: u32 = (mem::size_of::<usize>() * 8) as u32;
+
+/// Constructs a conditional jump (Jcc) to any destination.
+///
+/// The condition is inverted, so the branch skips an adjacent jump:
+///
+/// ```asm
+///     jne 2f          ; originally 'je destination'
+///     jmp destination
+/// 2:
+/// ```
+pub fn jcc(instruction: &Instruction, destination: usize) -> Result<Box<dyn Thunkable>, IcedError> {
+  let absolute = jmp(destination);
+
+  let mut branch = *instruction;
+  branch.negate_condition_code();
+  branch.as_short_branch();
+  branch.set_near_branch64(0);
+  let size = encode(&[branch], 0)?.len();
+  branch.set_near_branch64((size + absolute.len()) as u64);
+
+  let mut emitter = CodeEmitter::new();
+  emitter.add_thunk(Box::new(encode(&[branch], 0)?));
+  emitter.add_thunk(absolute);
+  Ok(Box::new(emitter))
+}
+
+/// Constructs a counter branch (i.e `loop` or `jcxz`) to any destination.
+///
+/// These instructions only have a short form, so the branch is taken to an
+/// adjacent jump, which is otherwise skipped:
+///
+/// ```asm
+///     loop 2f
+///     jmp 3f
+/// 2:  jmp destination
+/// 3:
+/// ```
+///
+/// The original instruction is kept, so the counter and flags are handled
+/// exactly as before.
+pub fn counter_branch(
+  instruction: &Instruction,
+  destination: usize,
+) -> Result<Box<dyn Thunkable>, IcedError> {
+  let absolute = jmp(destination);
+
+  let mut branch = *instruction;
+  branch.set_near_branch64(0);
+  let size = encode(&[branch], 0)?.len();
+  let skip = encode(&[jump(true, 0)?], size)?.len();
+  branch.set_near_branch64((size + skip) as u64);
+
+  let mut code = encode(&[branch], 0)?;
+  code.extend(encode(&[jump(true, size + skip + absolute.len())?], size)?);
+
+  let mut emitter = CodeEmitter::new();
+  emitter.add_thunk(Box::new(code));
+  emitter.add_thunk(absolute);
+  Ok(Box::new(emitter))
+}
+
+/// Returns a short or near jump.
+pub fn jump(short: bool, destination: usize) -> Result<Instruction, IcedError> {
+  let code = match (BITNESS, short) {
+    (64, true) => Code::Jmp_rel8_64,
+    (64, false) => Code::Jmp_rel32_64,
+    (_, true) => Code::Jmp_rel8_32,
+    (_, false) => Code::Jmp_rel32_32,
+  };
+  Instruction::with_branch(code, destination as u64)
+}
+
+/// Encodes consecutive instructions, starting at an address.
+pub fn encode(instructions: &[Instruction], address: usize) -> Result<Vec<u8>, IcedError> {
+  let mut encoder = Encoder::new(BITNESS);
+  let mut address = address as u64;
+  for instruction in instructions {
+    address += encoder.encode(instruction, address)? as u64;
+  }
+  Ok(encoder.take_buffer())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use iced_x86::{Decoder, DecoderOptions};
+  use std::convert::TryInto;
+
+  /// Generates a thunk at an address, and decodes its instructions.
+  fn decode(thunk: &dyn Thunkable, base: usize) -> (Vec<u8>, Vec<Instruction>) {
+    let code = thunk.generate(base).unwrap();
+    assert_eq!(code.len(), thunk.len());
+
+    // Embedded addresses are decoded as well, but are never branched to
+    let instructions = Decoder::with_ip(BITNESS, &code, base as u64, DecoderOptions::NONE)
+      .into_iter()
+      .take_while(|instruction| !instruction.is_invalid())
+      .collect();
+    (code, instructions)
+  }
+
+  /// Returns the destination of a branch, reading the address of indirect
+  /// branches from the thunk.
+  fn branch_target(code: &[u8], base: usize, instruction: &Instruction) -> usize {
+    if instruction.is_jmp_near_indirect() || instruction.is_call_near_indirect() {
+      let offset = instruction.ip_rel_memory_address() as usize - base;
+      let address = &code[offset..offset + mem::size_of::<usize>()];
+      usize::from_ne_bytes(address.try_into().unwrap())
+    } else {
+      instruction.near_branch_target() as usize
+    }
+  }
+
+  /// Returns the instruction at an address.
+  fn at(instructions: &[Instruction], address: usize) -> &Instruction {
+    instructions
+      .iter()
+      .find(|instruction| instruction.ip() == address as u64)
+      .expect("branch to the start of an instruction")
+  }
+
+  /// Decodes a single instruction at an address.
+  fn instruction(code: &[u8], address: usize) -> Instruction {
+    Decoder::with_ip(BITNESS, code, address as u64, DecoderOptions::NONE).decode()
+  }
+
+  /// Returns destinations near to, and for x64 far away from, a base.
+  fn destinations(base: usize) -> Vec<usize> {
+    let mut destinations = vec![base + 0x1000, base - 0x1000];
+    if cfg!(target_arch = "x86_64") {
+      destinations.push(base.wrapping_add(0x7FFF_0000_0000));
+    }
+    destinations
+  }
+
+  #[test]
+  fn jmp_reaches_destination() {
+    let base = 0x4000_0000;
+    for destination in destinations(base) {
+      let (code, instructions) = decode(&*jmp(destination), base);
+      let jump = &instructions[0];
+      assert!(jump.is_jmp_short_or_near() || jump.is_jmp_near_indirect());
+      assert_eq!(branch_target(&code, base, jump), destination);
+    }
+  }
+
+  #[test]
+  fn call_reaches_destination() {
+    let base = 0x4000_0000;
+    for destination in destinations(base) {
+      let (code, instructions) = decode(&*call(destination), base);
+      let call = &instructions[0];
+      assert!(call.is_call_near() || call.is_call_near_indirect());
+      assert_eq!(branch_target(&code, base, call), destination);
+
+      // The call returns to the end of the thunk
+      let end = base + code.len();
+      if call.next_ip() as usize != end {
+        let skip = at(&instructions, call.next_ip() as usize);
+        assert!(skip.is_jmp_short_or_near());
+        assert_eq!(skip.near_branch_target() as usize, end);
+      }
+    }
+  }
+
+  #[test]
+  fn jcc_reaches_destination() {
+    let base = 0x4000_0000;
+    for destination in destinations(base) {
+      for condition in 0..16 {
+        // jcc +0x10
+        let original = instruction(&[0x70 | condition, 0x10], 0x1000);
+        let (code, instructions) = decode(&*jcc(&original, destination).unwrap(), base);
+        let branch = &instructions[0];
+        assert!(branch.is_jcc_short_or_near());
+
+        let expected = original.condition_code() as u8;
+        if branch.condition_code() as u8 == expected {
+          assert_eq!(branch_target(&code, base, branch), destination);
+        } else {
+          // The inverted condition skips an unconditional jump
+          let mut inverted = *branch;
+          inverted.negate_condition_code();
+          assert_eq!(inverted.condition_code() as u8, expected);
+          assert_eq!(branch.near_branch_target() as usize, base + code.len());
+
+          let jump = at(&instructions, branch.next_ip() as usize);
+          assert_eq!(branch_target(&code, base, jump), destination);
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn counter_branch_reaches_destination() {
+    let base = 0x4000_0000;
+    for destination in destinations(base) {
+      // loop +0x10
+      let original = instruction(&[0xE2, 0x10], 0x1000);
+      let (code, instructions) = decode(&*counter_branch(&original, destination).unwrap(), base);
+      let counter = &instructions[0];
+      assert!(counter.is_loop());
+
+      // The loop branches to a jump, which is otherwise skipped
+      let skip = at(&instructions, counter.next_ip() as usize);
+      assert!(skip.is_jmp_short_or_near());
+      assert_eq!(skip.near_branch_target() as usize, base + code.len());
+
+      let jump = at(&instructions, counter.near_branch_target() as usize);
+      assert_eq!(branch_target(&code, base, jump), destination);
+    }
+  }
+}
@@ -51,28 +51,145 @@ pub fn jmp_abs(destination: usize) -> Box<dyn Thunkable> {
 }
 
 #[repr(packed)]
-struct JccAbs {
-  // jxx + 16
-  opcode: u8,
+struct PushRet {
+  // push imm32 (sign-extended)
+  opcode0: u8,
+  low: u32,
+  // mov dword [rsp+4], imm32
+  opcode1: u8,
+  opcode2: u8,
+  opcode3: u8,
   dummy0: u8,
-  dummy1: u8,
-  dummy2: u8,
-  dummy3: u32,
-  // destination
-  address: usize,
+  high: u32,
+  // ret
+  opcode4: u8,
 }
 
-pub fn jcc_abs(destination: usize, condition: u8) -> Box<dyn Thunkable> {
-  let code = JccAbs {
-    // Invert the condition in x64 mode to simplify the conditional jump logic
-    opcode: 0x71 ^ condition,
-    dummy0: 0x0E,
-    dummy1: 0xFF,
-    dummy2: 0x25,
-    dummy3: 0x0000_0000,
-    address: destination,
+pub fn push_ret(destination: usize) -> Box<dyn Thunkable> {
+  let code = PushRet {
+    opcode0: 0x68,
+    low: destination as u32,
+    opcode1: 0xC7,
+    opcode2: 0x44,
+    opcode3: 0x24,
+    dummy0: 0x04,
+    high: (destination >> 32) as u32,
+    opcode4: 0xC3,
   };
 
-  let slice: [u8; 16] = unsafe { mem::transmute(code) };
+  let slice: [u8; 14] = unsafe { mem::transmute(code) };
   Box::new(slice.to_vec())
 }
+
+/// Saves all argument registers, increments a counter, and passes a context
+/// as the first argument of the next call (for both the System V and
+/// Microsoft ABIs).
+pub fn closure_prolog(context: usize, counter: usize) -> Box<dyn Thunkable> {
+  Box::new(prolog(context, counter))
+}
+
+/// Saves all argument registers, increments a counter, and passes it along
+/// with the address of the return address to the next call.
+pub fn relay_prolog(counter: usize) -> Box<dyn Thunkable> {
+  let mut code = prolog(counter, counter);
+
+  // lea rsi, [rsp+0xD8]; lea rdx, [rsp+0xD8] (above the saved registers)
+  code.extend_from_slice(&[0x48, 0x8D, 0xB4, 0x24, 0xD8, 0x00, 0x00, 0x00]);
+  code.extend_from_slice(&[0x48, 0x8D, 0x94, 0x24, 0xD8, 0x00, 0x00, 0x00]);
+  Box::new(code)
+}
+
+/// Saves the return value registers, reserving a slot for the address to
+/// return to.
+pub fn exit_prolog() -> Box<dyn Thunkable> {
+  let mut code = vec![
+    // sub rsp, 8; push rax; push rdx
+    0x48, 0x83, 0xEC, 0x08, 0x50, 0x52,
+  ];
+
+  // sub rsp, 0x48 (the vector registers and shadow space)
+  code.extend_from_slice(&[0x48, 0x83, 0xEC, 0x48]);
+
+  // movdqu [rsp+0x20], xmm0; movdqu [rsp+0x30], xmm1
+  code.extend_from_slice(&[0xF3, 0x0F, 0x7F, 0x44, 0x24, 0x20]);
+  code.extend_from_slice(&[0xF3, 0x0F, 0x7F, 0x4C, 0x24, 0x30]);
+  Box::new(code)
+}
+
+/// Stores the address returned by the previous call in the slot reserved by
+/// `exit_prolog`, and returns to it with the return value restored.
+pub fn exit_epilog() -> Box<dyn Thunkable> {
+  let mut code = vec![
+    // mov [rsp+0x58], rax
+    0x48, 0x89, 0x44, 0x24, 0x58,
+  ];
+
+  // movdqu xmm0, [rsp+0x20]; movdqu xmm1, [rsp+0x30]
+  code.extend_from_slice(&[0xF3, 0x0F, 0x6F, 0x44, 0x24, 0x20]);
+  code.extend_from_slice(&[0xF3, 0x0F, 0x6F, 0x4C, 0x24, 0x30]);
+
+  // add rsp, 0x48; pop rdx; pop rax; ret
+  code.extend_from_slice(&[0x48, 0x83, 0xC4, 0x48, 0x5A, 0x58, 0xC3]);
+  Box::new(code)
+}
+
+/// Saves all argument registers, increments a counter, and assigns a context
+/// to the first argument.
+fn prolog(context: usize, counter: usize) -> Vec<u8> {
+  let mut code = vec![
+    // push rdi; push rsi; push rdx; push rcx; push r8; push r9; push rax
+    0x57, 0x56, 0x52, 0x51, 0x41, 0x50, 0x41, 0x51, 0x50,
+  ];
+
+  // mov rax, counter; lock inc qword [rax]
+  code.extend_from_slice(&[0x48, 0xB8]);
+  code.extend_from_slice(&counter.to_le_bytes());
+  code.extend_from_slice(&[0xF0, 0x48, 0xFF, 0x00]);
+
+  code.extend_from_slice(&[
+    // sub rsp, 0xA0 (the vector registers and shadow space, keeping the
+    // stack 16-byte aligned)
+    0x48, 0x81, 0xEC, 0xA0, 0x00, 0x00, 0x00,
+  ]);
+
+  // movdqu [rsp+0x20+n*16], xmmN
+  code.extend(move_vectors(0x7F));
+
+  // mov rdi, context; mov rcx, context
+  code.extend_from_slice(&[0x48, 0xBF]);
+  code.extend_from_slice(&context.to_le_bytes());
+  code.extend_from_slice(&[0x48, 0xB9]);
+  code.extend_from_slice(&context.to_le_bytes());
+  code
+}
+
+/// Restores the registers saved by `closure_prolog`.
+pub fn closure_epilog() -> Box<dyn Thunkable> {
+  // movdqu xmmN, [rsp+0x20+n*16]
+  let mut code = move_vectors(0x6F);
+  code.extend_from_slice(&[
+    // add rsp, 0xA0
+    0x48, 0x81, 0xC4, 0xA0, 0x00, 0x00, 0x00,
+    // pop rax; pop r9; pop r8; pop rcx; pop rdx; pop rsi; pop rdi
+    0x58, 0x41, 0x59, 0x41, 0x58, 0x59, 0x5A, 0x5E, 0x5F,
+  ]);
+  Box::new(code)
+}
+
+/// Moves `xmm0` to `xmm7` to or from the stack, depending on the opcode.
+fn move_vectors(opcode: u8) -> Vec<u8> {
+  let mut code = Vec::new();
+  for register in 0..8u8 {
+    let offset = 0x20 + u32::from(register) * 0x10;
+    code.extend_from_slice(&[0xF3, 0x0F, opcode]);
+
+    // [rsp+disp8] or [rsp+disp32]
+    if offset < 0x80 {
+      code.extend_from_slice(&[0x44 | register << 3, 0x24, offset as u8]);
+    } else {
+      code.extend_from_slice(&[0x84 | register << 3, 0x24]);
+      code.extend_from_slice(&offset.to_le_bytes());
+    }
+  }
+  code
+}
@@ -1,4 +1,5 @@
-use crate::pic::{FixedThunk, Thunkable};
+use crate::error::{Error, Result};
+use crate::pic::{FixedThunk, Thunkable, UnsafeThunk};
 use generic_array::{typenum, GenericArray};
 use std::mem;
 
@@ -16,11 +17,11 @@ fn relative32(destination: usize, is_jump: bool) -> Box<dyn Thunkable> {
   Box::new(FixedThunk::<typenum::U5>::new(move |source| {
     let code = JumpRel {
       opcode: if is_jump { JMP } else { CALL },
-      operand: calculate_displacement(source, destination, mem::size_of::<JumpRel>()),
+      operand: calculate_displacement(source, destination, mem::size_of::<JumpRel>())?,
     };
 
     let slice: [u8; 5] = unsafe { mem::transmute(code) };
-    GenericArray::clone_from_slice(&slice)
+    Ok(GenericArray::clone_from_slice(&slice))
   }))
 }
 
@@ -29,6 +30,11 @@ pub fn nop() -> Box<dyn Thunkable> {
   Box::new([0x90].to_vec())
 }
 
+/// Returns a breakpoint instruction.
+pub fn int3() -> Box<dyn Thunkable> {
+  Box::new([0xCC].to_vec())
+}
+
 /// Constructs a relative call operation.
 pub fn call_rel32(destination: usize) -> Box<dyn Thunkable> {
   relative32(destination, false)
@@ -39,27 +45,6 @@ pub fn jmp_rel32(destination: usize) -> Box<dyn Thunkable> {
   relative32(destination, true)
 }
 
-#[repr(packed)]
-struct JccRel {
-  opcode0: u8,
-  opcode1: u8,
-  operand: u32,
-}
-
-/// Constructs a conditional relative jump operation.
-pub fn jcc_rel32(destination: usize, condition: u8) -> Box<dyn Thunkable> {
-  Box::new(FixedThunk::<typenum::U6>::new(move |source| {
-    let code = JccRel {
-      opcode0: 0x0F,
-      opcode1: 0x80 | condition,
-      operand: calculate_displacement(source, destination, mem::size_of::<JccRel>()),
-    };
-
-    let slice: [u8; 6] = unsafe { mem::transmute(code) };
-    GenericArray::clone_from_slice(&slice)
-  }))
-}
-
 #[repr(packed)]
 pub struct JumpShort {
   opcode: u8,
@@ -75,19 +60,143 @@ pub fn jmp_rel8(displacement: i8) -> Box<dyn Thunkable> {
     };
 
     let slice: [u8; 2] = unsafe { mem::transmute(code) };
-    GenericArray::clone_from_slice(&slice)
+    Ok(GenericArray::clone_from_slice(&slice))
   }))
 }
 
+/// The size of an indirect jump, including its (aligned) address slot.
+pub const JUMP_SLOT_SIZE: usize = 6 + (mem::size_of::<usize>() - 1) + mem::size_of::<usize>();
+
+/// Returns the address slot of an indirect jump located at `source`.
+///
+/// The slot is pointer aligned, so it can be updated atomically.
+pub fn jump_slot(source: usize) -> usize {
+  let alignment = mem::size_of::<usize>();
+  (source + 6 + alignment - 1) & !(alignment - 1)
+}
+
+/// Constructs an indirect jump through an address slot following it.
+pub fn jmp_slot(destination: usize) -> Box<dyn Thunkable> {
+  let generate = move |source| {
+    let slot = jump_slot(source);
+
+    // The operand is RIP-relative on x64, but absolute on x86
+    let operand = if cfg!(target_arch = "x86_64") {
+      (slot - (source + 6)) as u32
+    } else {
+      slot as u32
+    };
+
+    let mut code = vec![0xFF, 0x25];
+    code.extend_from_slice(&operand.to_le_bytes());
+    code.resize(slot - source, 0xCC);
+    code.extend_from_slice(&destination.to_le_bytes());
+    code.resize(JUMP_SLOT_SIZE, 0xCC);
+    Ok(code)
+  };
+
+  Box::new(unsafe { UnsafeThunk::new(generate, JUMP_SLOT_SIZE) })
+}
+
+/// Constructs an absolute jump through an address following it.
+#[cfg(target_arch = "x86")]
+pub fn jmp_abs(destination: usize) -> Box<dyn Thunkable> {
+  let generate = move |source: usize| {
+    let mut code = vec![0xFF, 0x25];
+    code.extend_from_slice(&(source as u32 + 6).to_le_bytes());
+    code.extend_from_slice(&(destination as u32).to_le_bytes());
+    Ok(code)
+  };
+
+  Box::new(unsafe { UnsafeThunk::new(generate, 10) })
+}
+
+/// Constructs an absolute jump by returning to a pushed address.
+#[cfg(target_arch = "x86")]
+pub fn push_ret(destination: usize) -> Box<dyn Thunkable> {
+  let mut code = vec![0x68];
+  code.extend_from_slice(&(destination as u32).to_le_bytes());
+  code.push(0xC3);
+  Box::new(code)
+}
+
 /// Calculates the relative displacement for an instruction.
-fn calculate_displacement(source: usize, destination: usize, instruction_size: usize) -> u32 {
+fn calculate_displacement(
+  source: usize,
+  destination: usize,
+  instruction_size: usize,
+) -> Result<u32> {
   let displacement =
     (destination as isize).wrapping_sub(source as isize + instruction_size as isize);
 
   // Ensure that the detour can be reached with a relative jump (+/- 2GB).
-  // This only needs to be asserted on x64, since it wraps around on x86.
-  #[cfg(target_arch = "x86_64")]
-  assert!(crate::arch::is_within_range(displacement));
+  // This only needs to be checked on x64, since it wraps around on x86.
+  if cfg!(target_arch = "x86_64") && !crate::arch::is_within_range(displacement) {
+    Err(Error::DisplacementOutOfRange {
+      source,
+      destination,
+    })?;
+  }
+
+  Ok(displacement as u32)
+}
+
+/// Saves all argument registers, increments a counter, and pushes a context
+/// as the argument of the next call.
+#[cfg(target_arch = "x86")]
+pub fn closure_prolog(context: usize, counter: usize) -> Box<dyn Thunkable> {
+  // push eax; push ecx; push edx; lock inc dword [counter]
+  let mut code = vec![0x50, 0x51, 0x52, 0xF0, 0xFF, 0x05];
+  code.extend_from_slice(&(counter as u32).to_le_bytes());
+
+  // push context
+  code.push(0x68);
+  code.extend_from_slice(&(context as u32).to_le_bytes());
+  Box::new(code)
+}
+
+/// Removes the context, and restores the registers saved by
+/// `closure_prolog`.
+#[cfg(target_arch = "x86")]
+pub fn closure_epilog() -> Box<dyn Thunkable> {
+  // add esp, 4; pop edx; pop ecx; pop eax
+  Box::new([0x83, 0xC4, 0x04, 0x5A, 0x59, 0x58].to_vec())
+}
+
+/// Saves all argument registers, increments a counter, and pushes it along
+/// with the address of the return address as the arguments of the next call.
+#[cfg(target_arch = "x86")]
+pub fn relay_prolog(counter: usize) -> Box<dyn Thunkable> {
+  // push eax; push ecx; push edx; lock inc dword [counter]
+  let mut code = vec![0x50, 0x51, 0x52, 0xF0, 0xFF, 0x05];
+  code.extend_from_slice(&(counter as u32).to_le_bytes());
+
+  // lea eax, [esp+12]; push eax; push counter
+  code.extend_from_slice(&[0x8D, 0x44, 0x24, 0x0C, 0x50, 0x68]);
+  code.extend_from_slice(&(counter as u32).to_le_bytes());
+  Box::new(code)
+}
+
+/// Removes the arguments, and restores the registers saved by
+/// `relay_prolog`.
+#[cfg(target_arch = "x86")]
+pub fn relay_epilog() -> Box<dyn Thunkable> {
+  // add esp, 8; pop edx; pop ecx; pop eax
+  Box::new([0x83, 0xC4, 0x08, 0x5A, 0x59, 0x58].to_vec())
+}
+
+/// Saves the return value registers, reserving a slot for the address to
+/// return to.
+#[cfg(target_arch = "x86")]
+pub fn exit_prolog() -> Box<dyn Thunkable> {
+  // sub esp, 4; push eax; push edx
+  Box::new([0x83, 0xEC, 0x04, 0x50, 0x52].to_vec())
+}
 
-  displacement as u32
+/// Stores the address returned by the previous call in the slot reserved by
+/// `exit_prolog`, and returns to it with the return value restored.
+#[cfg(target_arch = "x86")]
+pub fn exit_epilog() -> Box<dyn Thunkable> {
+  // mov [esp+8], eax; pop edx; pop eax; ret
+  Box::new([0x89, 0x44, 0x24, 0x08, 0x5A, 0x58, 0xC3].to_vec())
 }
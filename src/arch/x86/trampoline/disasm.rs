@@ -6,8 +6,9 @@ pub trait InstructionExt {
   fn relative_branch_target(&self) -> Option<u64>;
   /// Returns the instructions RIP operand displacement if applicable.
   fn rip_operand_target(&self) -> Option<u64>;
-  /// Returns true if this instruction any type of a loop.
-  fn is_loop(&self) -> bool;
+  /// Returns true if this instruction branches on the counter register (i.e a
+  /// loop or jcxz).
+  fn is_counter_branch(&self) -> bool;
   /// Returns true if this instruction is an unconditional jump.
   fn is_unconditional_jump(&self) -> bool;
   /// Returns true if this instruction is a function call.
@@ -34,8 +35,9 @@ impl InstructionExt for Instruction {
       .map(|_| self.memory_displacement64())
   }
 
-  /// Returns true if this instruction any type of a loop.
-  fn is_loop(&self) -> bool {
+  /// Returns true if this instruction branches on the counter register (i.e a
+  /// loop or jcxz).
+  fn is_counter_branch(&self) -> bool {
     use Mnemonic::*;
     matches!(self.mnemonic(), Loop | Loope | Loopne | Jecxz | Jcxz)
   }
@@ -1,23 +1,49 @@
 use self::disasm::*;
-use crate::arch::x86::thunk;
+use crate::arch::x86::thunk::{self, encode, jump, BITNESS};
+use crate::detours::{PrologInstruction, Relocation};
 use crate::error::{Error, Result};
-use crate::pic;
-use iced_x86::{Decoder, DecoderOptions, Instruction, OpKind};
-use std::ptr::slice_from_raw_parts;
-use std::{mem, slice};
+use crate::{pic, util};
+use iced_x86::{
+  Code, Decoder, DecoderOptions, FastFormatter, FlowControl, IcedError, Instruction,
+  InstructionInfoFactory, MemoryOperand, Register,
+};
+use std::mem;
 
 mod disasm;
 
+/// The size of the area below the stack pointer that may be used without
+/// adjusting it (x64 System V).
+const RED_ZONE: i64 = 128;
+
 /// A trampoline generator (x86/x64).
 pub struct Trampoline {
   emitter: pic::CodeEmitter,
   prolog_size: usize,
+  instructions: Vec<(usize, usize)>,
+  has_call: bool,
 }
 
 impl Trampoline {
-  /// Constructs a new trampoline for an address.
-  pub unsafe fn new(target: *const (), margin: usize) -> Result<Trampoline> {
-    Builder::new(target, margin).build()
+  /// Constructs a new trampoline for an address, also returning the decoded
+  /// prolog instructions (including any that could not be relocated).
+  pub unsafe fn analyze(
+    target: *const (),
+    margin: usize,
+  ) -> (Vec<PrologInstruction>, Result<Trampoline>) {
+    let mut builder = Builder::new(target, margin, false);
+    let trampoline = builder.build();
+    (builder.report, trampoline)
+  }
+
+  /// Constructs a new trampoline that can be placed anywhere in memory, by
+  /// replacing RIP-relative operands with absolute addresses.
+  pub unsafe fn analyze_far(
+    target: *const (),
+    margin: usize,
+  ) -> (Vec<PrologInstruction>, Result<Trampoline>) {
+    let mut builder = Builder::new(target, margin, true);
+    let trampoline = builder.build();
+    (builder.report, trampoline)
   }
 
   /// Returns a reference to the trampoline's code emitter.
@@ -29,29 +55,137 @@ impl Trampoline {
   pub fn prolog_size(&self) -> usize {
     self.prolog_size
   }
+
+  /// Returns the offset of each prolog instruction paired with the offset of
+  /// its relocated counterpart in the trampoline.
+  pub fn instructions(&self) -> &[(usize, usize)] {
+    &self.instructions
+  }
+
+  /// Returns whether the relocated prolog contains a call, which returns
+  /// into the trampoline.
+  pub fn has_call(&self) -> bool {
+    self.has_call
+  }
+}
+
+/// A relocated prolog instruction, before the layout of the trampoline is
+/// known.
+enum Relocated {
+  /// Code which does not depend on the layout of the trampoline.
+  Thunk(Box<dyn pic::Thunkable>),
+  /// A branch to another prolog instruction.
+  Branch(Branch),
+}
+
+impl Relocated {
+  /// Returns the size of the relocated instruction.
+  fn len(&self) -> usize {
+    match self {
+      Relocated::Thunk(thunk) => thunk.len(),
+      Relocated::Branch(branch) => branch.len(),
+    }
+  }
+}
+
+/// A branch to another prolog instruction.
+///
+/// The distance between the instructions may change once relocated, so the
+/// branch is always encoded with a 32-bit displacement.
+struct Branch {
+  /// The original instruction.
+  instruction: Instruction,
+  /// The address of the destination within the prolog.
+  destination: usize,
+  /// The index of the instruction in the report.
+  index: usize,
+  /// The size of the encoded branch.
+  len: usize,
+}
+
+impl Branch {
+  /// Creates a branch, with the size of its near form.
+  fn new(
+    instruction: Instruction,
+    destination: usize,
+    index: usize,
+  ) -> std::result::Result<Self, IcedError> {
+    let mut branch = Branch {
+      len: 0,
+      instruction,
+      destination,
+      index,
+    };
+    branch.len = branch.encode(0, 0)?.len();
+    Ok(branch)
+  }
+
+  /// Returns the size of the encoded branch.
+  fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Encodes the branch, with its source and destination as offsets within
+  /// the trampoline.
+  fn encode(&self, source: usize, destination: usize) -> std::result::Result<Vec<u8>, IcedError> {
+    let mut branch = self.instruction;
+    if !branch.is_counter_branch() {
+      branch.as_near_branch();
+      branch.set_near_branch64(destination as u64);
+      return encode(&[branch], source);
+    }
+
+    // Loops only have a short form, so they branch over a short jump, to a
+    // near jump.
+    branch.set_near_branch64(source as u64);
+    let size = encode(&[branch], source)?.len();
+    let near_jump = source + size + 2;
+    branch.set_near_branch64(near_jump as u64);
+
+    let mut code = encode(&[branch], source)?;
+    code.extend(encode(&[jump(true, near_jump + 5)?], source + size)?);
+    code.extend(encode(&[jump(false, destination)?], near_jump)?);
+    Ok(code)
+  }
 }
 
 /// A trampoline builder.
 struct Builder {
-  /// Target destination for a potential internal branch.
-  branch_address: Option<usize>,
+  /// Destinations of internal branches.
+  branch_addresses: Vec<usize>,
   /// Total amount of bytes disassembled.
   total_bytes_disassembled: usize,
+  /// Prolog instruction offsets paired with their offsets in the trampoline.
+  instructions: Vec<(usize, usize)>,
+  /// The decoded prolog instructions and how they are relocated.
+  report: Vec<PrologInstruction>,
+  /// How the instruction currently being processed is relocated.
+  relocation: Relocation,
   /// The preferred minimum amount of bytes disassembled.
   margin: usize,
   /// Whether disassembling has finished or not.
   finished: bool,
+  /// Whether any relocated instruction is a call.
+  has_call: bool,
+  /// Whether the trampoline may be placed out of range of RIP-relative
+  /// operands.
+  far: bool,
   /// The target the trampoline is adapted for.
   target: *const (),
 }
 
 impl Builder {
   /// Returns a trampoline builder.
-  pub fn new(target: *const (), margin: usize) -> Self {
+  pub fn new(target: *const (), margin: usize, far: bool) -> Self {
     Builder {
-      branch_address: None,
+      branch_addresses: Vec::new(),
       total_bytes_disassembled: 0,
+      instructions: Vec::new(),
+      report: Vec::new(),
+      relocation: Relocation::Copy,
       finished: false,
+      has_call: false,
+      far,
       target,
       margin,
     }
@@ -59,24 +193,18 @@ impl Builder {
 
   /// Creates a trampoline with the supplied settings.
   ///
-  /// # Safety
-  ///
-  /// target..target+margin+15 must be valid to read as a u8 slice or behavior
-  /// may be undefined
-  pub unsafe fn build(mut self) -> Result<Trampoline> {
-    let mut emitter = pic::CodeEmitter::new();
+  /// The target's code is read without faulting, and no further than its
+  /// readable memory and the end of its symbol (if known).
+  pub unsafe fn build(&mut self) -> Result<Trampoline> {
+    let mut relocated = Vec::new();
+    let mut epilog = None;
+    let mut formatter = FastFormatter::new();
 
     // 15 = max size of x64 instruction
-    // safety: we don't know the end address of a function so this could be too far
-    // if the function is right at the end of the code section iced_x86 decoder
-    // doesn't have a way to read one byte at a time without creating a slice in
-    // advance and it's invalid to make a slice that's too long we could make a
-    // new Decoder before reading every individual instruction? but it'd still need
-    // to be given a 15 byte slice to handle any valid x64 instruction
-    let target: *const u8 = self.target.cast();
-    let slice = unsafe { slice::from_raw_parts(std::hint::black_box(target), self.margin + 15) };
+    let code = util::read_code(self.target as usize, self.margin + 15)?;
+    let slice = code.as_slice();
     let decoder = Decoder::with_ip(
-      (mem::size_of::<usize>() * 8) as u32,
+      BITNESS,
       slice,
       self.target as u64,
       DecoderOptions::NONE,
@@ -88,21 +216,29 @@ impl Builder {
       self.total_bytes_disassembled += instruction.len();
       let instr_offset = instruction.ip() as usize - (self.target as usize);
       let instruction_bytes = &slice[instr_offset..instr_offset + instruction.len()];
-      let thunk = self.process_instruction(&instruction, instruction_bytes)?;
-
-      // If the trampoline displacement is larger than the target
-      // function, all instructions will be displaced, and if there is
-      // internal branching, it will end up at the wrong instructions.
-      if self.is_instruction_in_branch(&instruction) && instruction.len() != thunk.len() {
-        Err(Error::UnsupportedInstruction)?;
-      } else {
-        emitter.add_thunk(thunk);
-      }
+
+      let mut text = String::new();
+      formatter.format(&instruction, &mut text);
+      self.report.push(PrologInstruction {
+        address: instruction.ip() as usize,
+        bytes: instruction_bytes.to_vec(),
+        relocation: Relocation::Copy,
+        text,
+      });
+
+      self.relocation = Relocation::Copy;
+      self.has_call |= matches!(
+        instruction.flow_control(),
+        FlowControl::Call | FlowControl::IndirectCall
+      );
+      let instruction_relocated = self.process_instruction(&instruction, instruction_bytes);
+      self.report_relocation(self.relocation);
+      relocated.push((instr_offset, instruction_relocated?));
 
       // Determine whether enough bytes for the margin has been disassembled
       if self.total_bytes_disassembled >= self.margin && !self.finished {
         // Add a jump to the first instruction after the prolog
-        emitter.add_thunk(thunk::jmp(instruction.next_ip() as usize));
+        epilog = Some(thunk::jmp(instruction.next_ip() as usize));
         self.finished = true;
       }
 
@@ -111,20 +247,85 @@ impl Builder {
       }
     }
 
+    // The code may end (e.g at the end of its symbol) short of the margin,
+    // leaving the trampoline without a way back to the target
+    if !self.finished {
+      Err(Error::NoPatchArea {
+        target: self.target as usize,
+        available: self.total_bytes_disassembled,
+        required: self.margin,
+      })?;
+    }
+
+    // Instructions may change size when relocated, so internal branches are
+    // encoded once the offset of every instruction is known.
+    let mut trampoline_offset = 0;
+    for (instr_offset, instruction) in &relocated {
+      self.instructions.push((*instr_offset, trampoline_offset));
+      trampoline_offset += instruction.len();
+    }
+
+    let mut emitter = pic::CodeEmitter::new();
+    for (index, (_, instruction)) in relocated.into_iter().enumerate() {
+      match instruction {
+        Relocated::Thunk(thunk) => emitter.add_thunk(thunk),
+        Relocated::Branch(branch) => {
+          let destination = branch.destination - self.target as usize;
+          let destination = self
+            .instructions
+            .iter()
+            .find(|(instr_offset, _)| *instr_offset == destination)
+            .map(|(_, trampoline_offset)| *trampoline_offset);
+
+          let destination = match destination {
+            Some(destination) => destination,
+            None => {
+              self.report[branch.index].relocation = Relocation::Rejected {
+                reason: "the branch's destination is not the start of a prolog instruction",
+              };
+              let bytes = self.report[branch.index].bytes.clone();
+              Err(self.unsupported(&branch.instruction, &bytes))?
+            },
+          };
+
+          let source = self.instructions[index].1;
+          let code = branch
+            .encode(source, destination)
+            .map_err(|_| self.unsupported(&branch.instruction, &self.report[branch.index].bytes))?;
+          emitter.add_thunk(Box::new(code));
+        },
+      }
+    }
+
+    if let Some(epilog) = epilog {
+      emitter.add_thunk(epilog);
+    }
+
     Ok(Trampoline {
       prolog_size: self.total_bytes_disassembled,
+      instructions: mem::take(&mut self.instructions),
+      has_call: self.has_call,
       emitter,
     })
   }
 
+  /// Records how the most recently decoded instruction is relocated.
+  fn report_relocation(&mut self, relocation: Relocation) {
+    if let Some(instruction) = self.report.last_mut() {
+      instruction.relocation = relocation;
+    }
+  }
+
   /// Returns an instruction after analysing and potentially modifies it.
   unsafe fn process_instruction(
     &mut self,
     instruction: &Instruction,
     instruction_bytes: &[u8],
-  ) -> Result<Box<dyn pic::Thunkable>> {
+  ) -> Result<Relocated> {
     if let Some(target) = instruction.rip_operand_target() {
-      return self.handle_rip_relative_instruction(instruction, instruction_bytes, target as usize);
+      return self
+        .handle_rip_relative_instruction(instruction, instruction_bytes, target as usize)
+        .map(Relocated::Thunk);
     } else if let Some(target) = instruction.relative_branch_target() {
       return self.handle_relative_branch(instruction, instruction_bytes, target as usize);
     } else if instruction.is_return() {
@@ -135,7 +336,7 @@ impl Builder {
 
     // The instruction does not use any position-dependant operands,
     // therefore the bytes can be copied directly from source.
-    Ok(Box::new(instruction_bytes.to_vec()))
+    Ok(Relocated::Thunk(Box::new(instruction_bytes.to_vec())))
   }
 
   /// Adjusts the offsets for RIP relative operands. They are only available
@@ -163,96 +364,226 @@ impl Builder {
       return Ok(Box::new(instruction_bytes.to_vec()));
     }
 
-    // These need to be captured by the closure
-    let instruction_address = instruction.ip() as isize;
-    let instruction_bytes = instruction_bytes.to_vec();
-    let immediate_size = instruction.op_kinds().find_map(|kind| {
-        match kind {
-            OpKind::Immediate8 => Some(1),
-            OpKind::Immediate8_2nd => Some(1),
-            OpKind::Immediate16 => Some(2),
-            OpKind::Immediate32 => Some(4),
-            OpKind::Immediate64 => Some(8),
-            OpKind::Immediate8to16 => Some(1),
-            OpKind::Immediate8to32 => Some(1),
-            OpKind::Immediate8to64 => Some(1),
-            OpKind::Immediate32to64 => Some(4),
-            _ => None,
-        }
-    }).unwrap_or(0);
+    if self.far {
+      return self.handle_far_rip_relative_instruction(instruction, instruction_bytes, target);
+    }
+    self.relocation = Relocation::RipRelative { target };
+
+    // RIP-relative operands are always encoded with a 32-bit displacement,
+    // so the size of the instruction does not depend on its address.
+    let instruction = *instruction;
+    let size = encode(&[instruction], instruction.ip() as usize)
+      .map_err(|_| self.unsupported(&instruction, instruction_bytes))?
+      .len();
 
     Ok(Box::new(pic::UnsafeThunk::new(
       move |offset| {
-        let mut bytes = instruction_bytes.clone();
-
-        // Calculate the new relative displacement for the operand. The
+        // The encoder calculates the new displacement for the operand. The
         // instruction is relative so the offset (i.e where the trampoline is
         // allocated), must be within a range of +/- 2GB.
-        let adjusted_displacement = instruction_address
-          .wrapping_sub(offset as isize)
-          .wrapping_add(displacement);
-        assert!(crate::arch::is_within_range(adjusted_displacement));
-
-        // The displacement value is placed at (instruction - disp32 - imm)
-        let index = instruction_bytes.len() - mem::size_of::<u32>() - immediate_size;
-
-        // Write the adjusted displacement offset to the operand
-        let as_bytes: [u8; 4] = (adjusted_displacement as u32).to_ne_bytes();
-        bytes[index..index+as_bytes.len()].copy_from_slice(&as_bytes);
-        bytes
+        encode(&[instruction], offset).map_err(|_| Error::DisplacementOutOfRange {
+          source: offset,
+          destination: target,
+        })
       },
-      instruction.len(),
+      size,
     )))
   }
 
+  /// Replaces RIP-relative operands with absolute addresses, so the
+  /// instruction can be executed at any distance from its operand:
+  ///
+  /// ```asm
+  /// lea rax, [rip+0x10]   ; mov rax, 0x7FF600001000
+  ///
+  /// mov eax, [rip+0x10]   ; lea rsp, [rsp-0x80]
+  ///                       ; push r11
+  ///                       ; mov r11, 0x7FF600001000
+  ///                       ; mov eax, [r11]
+  ///                       ; pop r11
+  ///                       ; lea rsp, [rsp+0x80]
+  /// ```
+  ///
+  /// The stack pointer is moved past the red zone before the scratch register
+  /// is saved, and none of the instructions affect the flags.
+  unsafe fn handle_far_rip_relative_instruction(
+    &mut self,
+    instruction: &Instruction,
+    instruction_bytes: &[u8],
+    target: usize,
+  ) -> Result<Box<dyn pic::Thunkable>> {
+    let sequence = if instruction.code() == Code::Lea_r64_m {
+      Instruction::with2(Code::Mov_r64_imm64, instruction.op0_register(), target as u64)
+        .map(|instruction| vec![instruction])
+    } else if instruction.is_jmp_near_indirect() {
+      Self::absolute_indirect_jump(target)
+    } else {
+      let used_registers = InstructionInfoFactory::new()
+        .info(instruction)
+        .used_registers()
+        .iter()
+        .map(|used| used.register().full_register())
+        .collect::<Vec<_>>();
+
+      // The stack is used to save the scratch register
+      if used_registers.contains(&Register::RSP) {
+        self.relocation = Relocation::Rejected {
+          reason: "the instruction uses the stack, and its RIP-relative operand is out of range",
+        };
+        Err(self.unsupported(instruction, instruction_bytes))?;
+      }
+
+      let scratch = [
+        Register::R11,
+        Register::R10,
+        Register::R9,
+        Register::R8,
+        Register::RAX,
+        Register::RCX,
+        Register::RDX,
+        Register::RBX,
+        Register::RSI,
+        Register::RDI,
+      ]
+      .iter()
+      .copied()
+      .find(|register| !used_registers.contains(register))
+      .ok_or_else(|| self.unsupported(instruction, instruction_bytes))?;
+
+      let mut absolute = *instruction;
+      absolute.set_memory_base(scratch);
+      absolute.set_memory_displacement64(0);
+      absolute.set_memory_displ_size(0);
+      Self::with_scratch_register(scratch, target, absolute)
+    };
+
+    let code = sequence
+      .and_then(|sequence| encode(&sequence, 0))
+      .map_err(|_| self.unsupported(instruction, instruction_bytes))?;
+
+    self.relocation = Relocation::RipToAbsolute { target };
+    Ok(Box::new(code))
+  }
+
+  /// Returns an instruction surrounded by the loading of an address into a
+  /// scratch register, which is saved beyond the red zone.
+  fn with_scratch_register(
+    scratch: Register,
+    address: usize,
+    instruction: Instruction,
+  ) -> std::result::Result<Vec<Instruction>, IcedError> {
+    Ok(vec![
+      Instruction::with2(
+        Code::Lea_r64_m,
+        Register::RSP,
+        MemoryOperand::with_base_displ(Register::RSP, -RED_ZONE),
+      )?,
+      Instruction::with1(Code::Push_r64, scratch)?,
+      Instruction::with2(Code::Mov_r64_imm64, scratch, address as u64)?,
+      instruction,
+      Instruction::with1(Code::Pop_r64, scratch)?,
+      Instruction::with2(
+        Code::Lea_r64_m,
+        Register::RSP,
+        MemoryOperand::with_base_displ(Register::RSP, RED_ZONE),
+      )?,
+    ])
+  }
+
+  /// Returns a jump to the address stored at `address`, which is pushed
+  /// beyond the red zone and returned to.
+  fn absolute_indirect_jump(address: usize) -> std::result::Result<Vec<Instruction>, IcedError> {
+    Ok(vec![
+      Instruction::with2(
+        Code::Lea_r64_m,
+        Register::RSP,
+        MemoryOperand::with_base_displ(Register::RSP, -RED_ZONE),
+      )?,
+      Instruction::with1(Code::Push_r64, Register::RAX)?,
+      Instruction::with1(Code::Push_r64, Register::RAX)?,
+      Instruction::with2(Code::Mov_r64_imm64, Register::RAX, address as u64)?,
+      Instruction::with2(
+        Code::Mov_r64_rm64,
+        Register::RAX,
+        MemoryOperand::with_base(Register::RAX),
+      )?,
+      Instruction::with2(
+        Code::Mov_rm64_r64,
+        MemoryOperand::with_base_displ(Register::RSP, 8),
+        Register::RAX,
+      )?,
+      Instruction::with1(Code::Pop_r64, Register::RAX)?,
+      // Releases the red zone along with the destination
+      Instruction::with1(Code::Retnq_imm16, RED_ZONE as u32)?,
+    ])
+  }
+
   /// Processes relative branches (e.g `call`, `loop`, `jne`).
   unsafe fn handle_relative_branch(
     &mut self,
     instruction: &Instruction,
     instruction_bytes: &[u8],
     destination_address_abs: usize,
-  ) -> Result<Box<dyn pic::Thunkable>> {
+  ) -> Result<Relocated> {
+    let destination = destination_address_abs;
     if instruction.is_call() {
-      // Calls are not an issue since they return to the original address
-      return Ok(thunk::call(destination_address_abs));
+      // Calls return into the trampoline, which is therefore never released
+      self.relocation = Relocation::CallToAbsolute { destination };
+      return Ok(Relocated::Thunk(thunk::call(destination_address_abs)));
     }
 
     let prolog_range = (self.target as usize)..(self.target as usize + self.margin);
 
-    // If the relative jump is internal, and short enough to
-    // fit within the copied function prolog (i.e `margin`),
-    // the jump instruction can be copied indiscriminately.
+    // If the relative jump is internal, and short enough to fit within the
+    // copied function prolog (i.e `margin`), it branches to the relocated
+    // destination instead.
     if prolog_range.contains(&destination_address_abs) {
       // Keep track of the jump's destination address
-      self.branch_address = Some(destination_address_abs);
-      Ok(Box::new(instruction_bytes.to_vec()))
-    } else if instruction.is_loop() {
-      // Loops (e.g 'loopnz', 'jecxz') to the outside are not supported
-      Err(Error::UnsupportedInstruction)
+      self.branch_addresses.push(destination_address_abs);
+      self.relocation = Relocation::InternalBranch { destination };
+
+      Branch::new(*instruction, destination, self.report.len() - 1)
+        .map(Relocated::Branch)
+        .map_err(|_| self.unsupported(instruction, instruction_bytes))
+    } else if instruction.is_counter_branch() {
+      // Loops (e.g 'loopnz', 'jecxz') have no long form, so they branch to
+      // an absolute jump instead.
+      self.relocation = Relocation::LoopToAbsolute { destination };
+      thunk::counter_branch(instruction, destination)
+        .map(Relocated::Thunk)
+        .map_err(|_| self.unsupported(instruction, instruction_bytes))
     } else if instruction.is_unconditional_jump() {
       // If the function is not in a branch, and it unconditionally jumps
       // a distance larger than the prolog, it's the same as if it terminates.
       self.finished = !self.is_instruction_in_branch(instruction);
-      Ok(thunk::jmp(destination_address_abs))
+      self.relocation = Relocation::JumpToAbsolute { destination };
+      Ok(Relocated::Thunk(thunk::jmp(destination_address_abs)))
+    } else if instruction.is_jcc_short_or_near() {
+      self.relocation = Relocation::JccToAbsolute { destination };
+      thunk::jcc(instruction, destination)
+        .map(Relocated::Thunk)
+        .map_err(|_| self.unsupported(instruction, instruction_bytes))
     } else {
-      // Conditional jumps (Jcc)
-      // To extract the condition, the primary opcode is required. Short
-      // jumps are only one byte, but long jccs are prefixed with 0x0F.
-      let primary_opcode = instruction_bytes
-        .iter()
-        .find(|op| **op != 0x0F)
-        .expect("retrieving conditional jump primary op code");
+      // Other relative branches (e.g 'xbegin') have no condition
+      Err(self.unsupported(instruction, instruction_bytes))
+    }
+  }
 
-      // Extract the condition (i.e 0x74 is [jz rel8] ⟶ 0x74 & 0x0F == 4)
-      let condition = primary_opcode & 0x0F;
-      Ok(thunk::jcc(destination_address_abs, condition))
+  /// Returns an error describing an instruction that cannot be relocated.
+  fn unsupported(&self, instruction: &Instruction, instruction_bytes: &[u8]) -> Error {
+    Error::UnsupportedInstruction {
+      target: self.target as usize,
+      offset: instruction.ip() as usize - self.target as usize,
+      bytes: instruction_bytes.to_vec(),
+      mnemonic: format!("{:?}", instruction.mnemonic()).to_lowercase(),
     }
   }
 
   /// Returns whether the current instruction is inside a branch or not.
   fn is_instruction_in_branch(&self, instruction: &Instruction) -> bool {
     self
-      .branch_address
-      .map_or(false, |offset| instruction.ip() < offset as u64)
+      .branch_addresses
+      .iter()
+      .any(|&offset| instruction.ip() < offset as u64)
   }
 }
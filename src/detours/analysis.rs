@@ -0,0 +1,145 @@
+use crate::arch;
+use crate::error::Error;
+use std::ops::Range;
+
+/// How a prolog instruction is relocated to the trampoline.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Relocation {
+  /// The instruction is copied as is.
+  Copy,
+  /// The instruction's RIP-relative operand is adjusted for the trampoline.
+  RipRelative { target: usize },
+  /// The instruction's RIP-relative operand is replaced by an absolute
+  /// address, since the trampoline is out of its range.
+  RipToAbsolute { target: usize },
+  /// The branch remains within the prolog, and is adjusted for the
+  /// relocated destination.
+  InternalBranch { destination: usize },
+  /// The relative call is replaced by an absolute call.
+  CallToAbsolute { destination: usize },
+  /// The relative jump is replaced by an absolute jump.
+  JumpToAbsolute { destination: usize },
+  /// The conditional jump is replaced by an absolute conditional jump.
+  JccToAbsolute { destination: usize },
+  /// The loop (or `jcxz`) is redirected to an absolute jump.
+  LoopToAbsolute { destination: usize },
+  /// The instruction cannot be relocated.
+  Rejected { reason: &'static str },
+}
+
+/// A decoded instruction of a target's prolog.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct PrologInstruction {
+  /// The address of the instruction.
+  pub address: usize,
+  /// The encoded instruction.
+  pub bytes: Vec<u8>,
+  /// The disassembled instruction.
+  pub text: String,
+  /// How the instruction is relocated.
+  pub relocation: Relocation,
+}
+
+/// The way a target's code is patched.
+///
+/// The patch is placed after any `endbr` instruction at the start of the
+/// target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum PatchStrategy {
+  /// The prolog is replaced by a relative jump (5 bytes). A relay is used for
+  /// detours out of its range.
+  Rel32,
+  /// The prolog is replaced by a short jump, to a relative jump placed in the
+  /// padding before the function (2 bytes).
+  HotPatch,
+  /// The prolog is replaced by an absolute jump through an address following
+  /// it, i.e `jmp [rip+0]; dq address` (14 bytes on x64, 10 on x86).
+  AbsoluteJump,
+  /// The prolog is replaced by an absolute jump that returns to a pushed
+  /// address (14 bytes on x64, 6 on x86). This uses a slot of the stack.
+  PushRet,
+  /// The prolog is replaced by a short jump, to a relative jump placed in
+  /// the closest run of `int3` padding within 127 bytes (2 bytes).
+  ShortJump,
+  /// The prolog is replaced by a breakpoint (1 byte), whose `SIGTRAP` is
+  /// redirected to the detour by a process-wide signal handler. Any other
+  /// `SIGTRAP` is passed on to the previously installed handler.
+  ///
+  /// This fits any function, but every call raises a signal. It is only
+  /// supported on Linux.
+  Breakpoint,
+  /// The target is left intact, and execute permission is removed from its
+  /// page instead. The resulting `SIGSEGV` is redirected to the detour when
+  /// the target is executed, whilst any other instruction of the page is
+  /// copied and single-stepped, so the page remains guarded for other threads.
+  ///
+  /// This fits any function and code that cannot be modified, but any code
+  /// sharing the page runs far slower. Functions within this library or the C
+  /// library, which the signal handlers execute, cannot be guarded. It is only
+  /// supported on Linux.
+  GuardPage,
+}
+
+/// A report of how a target is, or would be, detoured.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Analysis {
+  /// The address of the analysed function.
+  pub target: *const (),
+  /// The decoded prolog, up to and including any rejected instruction.
+  pub instructions: Vec<PrologInstruction>,
+  /// The number of prolog bytes relocated to the trampoline.
+  pub prolog_size: usize,
+  /// The patch used for the target, if it can be patched.
+  pub strategy: Option<PatchStrategy>,
+  /// The bytes replaced by the patch, if it can be patched.
+  pub patch_area: Option<Range<usize>>,
+  /// The padding holding the relative jump of a short jump, apart from the
+  /// patch area.
+  pub padding: Option<Range<usize>>,
+  /// Whether a relay is used to reach the detour. This is only known for
+  /// existing detours.
+  pub relay: bool,
+  /// The reason the target cannot be detoured, if any.
+  pub error: Option<Error>,
+}
+
+impl Analysis {
+  /// Returns whether the target can be detoured.
+  pub fn is_hookable(&self) -> bool {
+    self.error.is_none()
+  }
+}
+
+/// Analyses a target without modifying it.
+///
+/// The prolog is decoded and relocated, and the patch area is determined, the
+/// same way as when a detour is created. If the target is already detoured,
+/// the report of the applied patch is returned.
+///
+/// # Safety
+///
+/// The target must point to the start of an instruction. Its code is read
+/// without faulting, and no further than its readable memory.
+///
+/// # Example
+///
+/// ```rust
+/// use retour::{analyze, PatchStrategy};
+///
+/// #[inline(never)]
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// let analysis = unsafe { analyze(add5 as *const ()) };
+/// assert!(analysis.is_hookable());
+/// assert_eq!(analysis.strategy, Some(PatchStrategy::Rel32));
+/// assert!(!analysis.instructions.is_empty());
+/// ```
+pub unsafe fn analyze(target: *const ()) -> Analysis {
+  arch::analyze(target)
+}
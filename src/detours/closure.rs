@@ -0,0 +1,239 @@
+use super::transaction::private;
+use super::{Analysis, DetourOptions};
+use crate::arch::{self, ClosureThunk, Detour, WriteStrategy};
+use crate::error::Result;
+use crate::{alloc, Function};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+use std::{fmt, ptr};
+
+/// A type-safe detour to a closure, available on stable Rust.
+///
+/// Unlike a [StaticDetour](./struct.StaticDetour.html), no `static` has to be
+/// declared for each hook, so any number of targets determined at runtime
+/// can be detoured to capturing closures. Each hook allocates a small thunk
+/// that passes its closure to a shim generated for the function's signature.
+///
+/// The closure receives the original function (its trampoline) followed by
+/// the target's arguments. Due to being generated by a macro, the `new`,
+/// `with_options` and `call` methods are not exposed in the documentation:
+///
+/// ```c
+/// /// Creates a new hook given a target function and a compatible closure.
+/// unsafe fn new<F>(target: T, closure: F) -> Result<Self>
+///   where F: Fn(T, T::Arguments...) -> T::Output + Send + Sync + 'static
+///
+/// /// Calls the original function regardless of whether it's hooked or not.
+/// fn call(&self, T::Arguments) -> T::Output
+/// ```
+///
+/// Dropping the detour disables it, but the closure is only released once no
+/// thread is executing it.
+///
+/// # Example
+///
+/// ```rust
+/// # use retour::Result;
+/// use retour::ClosureDetour;
+///
+/// #[inline(never)]
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let offset = 10;
+/// let hook = unsafe {
+///   ClosureDetour::<fn(i32) -> i32>::new(add5, move |original, val| original(val) + offset)?
+/// };
+///
+/// unsafe { hook.enable()? };
+/// assert_eq!(add5(5), 20);
+/// assert_eq!(hook.call(5), 10);
+///
+/// unsafe { hook.disable()? };
+/// assert_eq!(add5(5), 10);
+/// # Ok(())
+/// # }
+/// ```
+pub struct ClosureDetour<T: Function> {
+  // The detour is disabled before its thunk and closure are retired
+  detour: Detour,
+  /// The thunk, to which the closure is attached.
+  thunk: ClosureThunk,
+  phantom: PhantomData<T>,
+}
+
+/// The closure of a detour, as passed to its shim.
+#[doc(hidden)]
+pub struct ClosureContext<C> {
+  pub closure: C,
+  /// The trampoline of the detour.
+  pub original: *const (),
+  /// The number of calls that entered the thunk and have yet to return.
+  callers: AtomicUsize,
+}
+
+unsafe impl<C: Send> Send for ClosureContext<C> {}
+unsafe impl<C: Sync> Sync for ClosureContext<C> {}
+
+impl<C> ClosureContext<C> {
+  /// Returns the context passed by the thunk that entered the shim.
+  ///
+  /// # Safety
+  ///
+  /// This must be called by a shim before executing anything else, and only
+  /// with the closure type the thunk was created for.
+  #[doc(hidden)]
+  pub unsafe fn current<'a>() -> ClosureCall<'a, C> {
+    ClosureCall(&*(arch::closure::take_context() as *const Self))
+  }
+}
+
+impl<C: Send + Sync> alloc::Attachment for ClosureContext<C> {
+  fn is_in_use(&self) -> bool {
+    self.callers.load(Ordering::SeqCst) != 0
+  }
+}
+
+/// A call of a closure, which was counted by its thunk.
+#[doc(hidden)]
+pub struct ClosureCall<'a, C>(&'a ClosureContext<C>);
+
+impl<'a, C> Deref for ClosureCall<'a, C> {
+  type Target = ClosureContext<C>;
+
+  fn deref(&self) -> &Self::Target {
+    self.0
+  }
+}
+
+impl<'a, C> Drop for ClosureCall<'a, C> {
+  fn drop(&mut self) {
+    self.0.callers.fetch_sub(1, Ordering::SeqCst);
+  }
+}
+
+impl<T: Function> ClosureDetour<T> {
+  /// Creates a new hook, detouring the target to a shim of the closure.
+  ///
+  /// # Safety
+  ///
+  /// The shim must be generated for the closure's type, and share the
+  /// target's signature.
+  #[doc(hidden)]
+  #[track_caller]
+  pub unsafe fn __new<C>(
+    target: T,
+    closure: C,
+    shim: *const (),
+    options: &DetourOptions,
+  ) -> Result<Self>
+  where
+    C: Send + Sync + 'static,
+  {
+    let mut context = Box::new(ClosureContext {
+      closure,
+      original: ptr::null(),
+      callers: AtomicUsize::new(0),
+    });
+    let mut thunk = ClosureThunk::new(
+      &*context as *const _ as *const (),
+      &context.callers as *const _ as *const (),
+      shim,
+    )?;
+    let detour = Detour::with_options(target.to_ptr(), thunk.as_ptr(), options)?;
+    context.original = detour.trampoline() as *const ();
+    thunk.attach(context);
+
+    // A tracked call is counted by the relay before it reaches the thunk
+    if let Some(in_flight) = detour.in_flight() {
+      thunk.attach(in_flight);
+    }
+
+    Ok(ClosureDetour {
+      detour,
+      thunk,
+      phantom: PhantomData,
+    })
+  }
+
+  /// Enables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must remain valid whilst the detour is enabled, and the
+  /// closure must handle calls of the target from any thread.
+  pub unsafe fn enable(&self) -> Result<()> {
+    self.detour.enable()
+  }
+
+  /// Disables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must still be valid.
+  pub unsafe fn disable(&self) -> Result<()> {
+    self.detour.disable()
+  }
+
+  /// Disables the detour and waits until no other thread is executing the
+  /// trampoline, or until `timeout` has elapsed.
+  ///
+  /// The detour remains disabled even if the wait times out.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for disabling the detour apply.
+  #[cfg(target_os = "linux")]
+  #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+  pub unsafe fn disable_and_wait(&self, timeout: Duration) -> Result<()> {
+    self.detour.disable_and_wait(timeout)
+  }
+
+  /// Returns whether the detour is enabled or not.
+  pub fn is_enabled(&self) -> bool {
+    self.detour.is_enabled()
+  }
+
+  /// Returns how the target's code is replaced when the detour is toggled.
+  pub fn write_strategy(&self) -> WriteStrategy {
+    self.detour.write_strategy()
+  }
+
+  /// Returns the analysis of the target's patch.
+  pub fn analysis(&self) -> Analysis {
+    self.detour.analysis()
+  }
+
+  /// Assigns a label to the detour, as reported by [live_detours](crate::live_detours).
+  pub fn set_label(&self, label: &str) {
+    self.detour.set_label(label)
+  }
+
+  /// Returns a reference to the generated trampoline.
+  pub fn trampoline(&self) -> &() {
+    self.detour.trampoline()
+  }
+}
+
+impl<T: Function> private::Sealed for ClosureDetour<T> {
+  fn detour(&self) -> Option<&Detour> {
+    Some(&self.detour)
+  }
+}
+
+impl<T: Function> fmt::Debug for ClosureDetour<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("ClosureDetour")
+      .field("detour", &self.detour)
+      .field("thunk", &self.thunk.as_ptr())
+      .finish()
+  }
+}
+
+unsafe impl<T: Function> Send for ClosureDetour<T> {}
+unsafe impl<T: Function> Sync for ClosureDetour<T> {}
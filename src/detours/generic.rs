@@ -1,7 +1,11 @@
-use crate::arch::Detour;
+use super::transaction::private;
+use super::{Analysis, DetourOptions};
+use crate::arch::{Detour, WriteStrategy};
 use crate::error::Result;
 use crate::{Function, HookableWith};
 use std::marker::PhantomData;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
 
 /// A type-safe detour.
 ///
@@ -11,6 +15,8 @@ use std::marker::PhantomData;
 ///
 /// ```c
 /// /// Calls the original function regardless of whether it's hooked or not.
+/// /// If the target has other enabled detours created before this one, the
+/// /// most recent of them is called instead.
 /// fn call(&self, T::Arguments) -> T::Output
 /// ```
 ///
@@ -54,6 +60,12 @@ pub struct GenericDetour<T: Function> {
 impl<T: Function> GenericDetour<T> {
   /// Create a new hook given a target function and a compatible detour
   /// function.
+  ///
+  /// # Safety
+  ///
+  /// The target and detour must remain valid for as long as the detour
+  /// exists.
+  #[track_caller]
   pub unsafe fn new<D>(target: T, detour: D) -> Result<Self>
   where
     T: HookableWith<D>,
@@ -65,25 +77,98 @@ impl<T: Function> GenericDetour<T> {
     })
   }
 
+  /// Create a new hook given a target function, a compatible detour function
+  /// and custom options.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for `new` apply.
+  #[track_caller]
+  pub unsafe fn with_options<D>(target: T, detour: D, options: &DetourOptions) -> Result<Self>
+  where
+    T: HookableWith<D>,
+    D: Function,
+  {
+    Detour::with_options(target.to_ptr(), detour.to_ptr(), options).map(|detour| GenericDetour {
+      phantom: PhantomData,
+      detour,
+    })
+  }
+
   /// Enables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must remain valid whilst the detour is enabled, and the
+  /// detour must handle calls of the target from any thread.
   pub unsafe fn enable(&self) -> Result<()> {
     self.detour.enable()
   }
 
   /// Disables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must still be valid.
   pub unsafe fn disable(&self) -> Result<()> {
     self.detour.disable()
   }
 
+  /// Disables the detour and waits until no other thread is executing the
+  /// trampoline, or until `timeout` has elapsed.
+  ///
+  /// The detour remains disabled even if the wait times out.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for disabling the detour apply.
+  #[cfg(target_os = "linux")]
+  #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+  pub unsafe fn disable_and_wait(&self, timeout: Duration) -> Result<()> {
+    self.detour.disable_and_wait(timeout)
+  }
+
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
     self.detour.is_enabled()
   }
 
+  /// Returns how the target's code is replaced when the detour is toggled.
+  pub fn write_strategy(&self) -> WriteStrategy {
+    self.detour.write_strategy()
+  }
+
+  /// Returns the analysis of the target's patch.
+  pub fn analysis(&self) -> Analysis {
+    self.detour.analysis()
+  }
+
+  /// Assigns a label to the detour, as reported by [live_detours](crate::live_detours).
+  pub fn set_label(&self, label: &str) {
+    self.detour.set_label(label)
+  }
+
   /// Returns a reference to the generated trampoline.
   pub fn trampoline(&self) -> &() {
     self.detour.trampoline()
   }
+
+  /// Returns the generated trampoline as a function, invoking the original
+  /// target's code.
+  ///
+  /// # Safety
+  ///
+  /// The function is not tied to the detour, and must not be called once the
+  /// detour has been dropped.
+  pub unsafe fn original(&self) -> T {
+    T::from_ptr(self.detour.trampoline() as *const ())
+  }
+}
+
+impl<T: Function> private::Sealed for GenericDetour<T> {
+  fn detour(&self) -> Option<&Detour> {
+    Some(&self.detour)
+  }
 }
 
 unsafe impl<T: Function> Send for GenericDetour<T> {}
@@ -1,15 +1,17 @@
-use cfg_if::cfg_if;
-
+mod analysis;
+mod closure;
 mod generic;
+mod options;
 mod raw;
+mod registry;
+mod statik;
+mod transaction;
 
+pub use self::analysis::*;
+pub use self::closure::*;
 pub use self::generic::*;
+pub use self::options::*;
 pub use self::raw::*;
-
-cfg_if! {
-    if #[cfg(feature = "static-detour")] {
-        #[cfg_attr(docsrs, doc(cfg(feature = "static-detour")))]
-        mod statik;
-        pub use self::statik::*;
-    }
-}
+pub use self::registry::*;
+pub use self::statik::*;
+pub use self::transaction::*;
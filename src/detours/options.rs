@@ -0,0 +1,98 @@
+use super::PatchStrategy;
+
+/// Settings used when creating a detour.
+///
+/// A target's patch is created along with its first detour, so the settings
+/// affecting the patch only apply to that detour.
+///
+/// # Example
+///
+/// ```rust
+/// # use retour::Result;
+/// use retour::{DetourOptions, RawDetour};
+///
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// fn add10(val: i32) -> i32 {
+///   val + 10
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let options = DetourOptions::new().scan_body(true);
+/// let hook = unsafe { RawDetour::with_options(add5 as *const (), add10 as *const (), &options)? };
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DetourOptions {
+  /// Whether the body of the target is scanned for branches into its prolog.
+  pub scan_body: bool,
+  /// The patches attempted for the target, in order of preference.
+  pub strategies: Vec<PatchStrategy>,
+  /// Whether the calls of the detour are counted until they return.
+  pub track_returns: bool,
+}
+
+impl Default for DetourOptions {
+  fn default() -> Self {
+    // A breakpoint fits any target, so it is only used when nothing else does
+    let mut strategies = vec![PatchStrategy::Rel32, PatchStrategy::HotPatch];
+    if crate::trap::is_supported() {
+      strategies.push(PatchStrategy::Breakpoint);
+    }
+
+    DetourOptions {
+      scan_body: false,
+      strategies,
+      track_returns: false,
+    }
+  }
+}
+
+impl DetourOptions {
+  /// Returns the default options.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Sets whether the body of the target is scanned for branches into the
+  /// bytes replaced by the patch, which would otherwise execute a partially
+  /// overwritten instruction. If any are found, the detour is refused with
+  /// [Error::BranchIntoProlog](crate::Error::BranchIntoProlog).
+  ///
+  /// The body is scanned until its first return or jump that is not
+  /// branched over, or until the end of its memory region. This is disabled
+  /// by default.
+  pub fn scan_body(mut self, scan_body: bool) -> Self {
+    self.scan_body = scan_body;
+    self
+  }
+
+  /// Sets the patches attempted for the target, in order of preference. The
+  /// first one that fits the target is used.
+  ///
+  /// The default is [Rel32](PatchStrategy::Rel32), followed by
+  /// [HotPatch](PatchStrategy::HotPatch) and, where supported,
+  /// [Breakpoint](PatchStrategy::Breakpoint).
+  pub fn strategies(mut self, strategies: &[PatchStrategy]) -> Self {
+    self.strategies = strategies.to_vec();
+    self
+  }
+
+  /// Sets whether each call of the detour is counted until it returns, by
+  /// replacing its return address. A dropped detour is then only released
+  /// once none of its calls remain in flight, rather than once no thread is
+  /// executing its code (which is only known with thread suspension).
+  ///
+  /// The detour must then neither unwind (e.g panic), nor `longjmp` past its
+  /// caller. Its caller's return address is hidden from stack traces and
+  /// `_ReturnAddress`, and shadow stacks (CET) are not supported. This only
+  /// applies to the detour being created, and is disabled by default.
+  pub fn track_returns(mut self, track_returns: bool) -> Self {
+    self.track_returns = track_returns;
+    self
+  }
+}
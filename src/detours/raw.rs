@@ -1,5 +1,10 @@
-use crate::arch::Detour;
+use super::transaction::private;
+use super::{Analysis, DetourOptions};
+use crate::arch::{Detour, WriteStrategy};
 use crate::error::Result;
+use crate::Function;
+#[cfg(target_os = "linux")]
+use std::time::Duration;
 
 /// A raw detour.
 ///
@@ -8,7 +13,6 @@ use crate::error::Result;
 /// ```rust
 /// # use retour::Result;
 /// use retour::RawDetour;
-/// use std::mem;
 ///
 /// fn add5(val: i32) -> i32 {
 ///   val + 5
@@ -27,7 +31,7 @@ use crate::error::Result;
 /// unsafe { hook.enable()? };
 /// assert!(hook.is_enabled());
 ///
-/// let original: fn(i32) -> i32 = unsafe { mem::transmute(hook.trampoline()) };
+/// let original = unsafe { hook.original_as::<fn(i32) -> i32>() };
 ///
 /// assert_eq!(add5(5), 15);
 /// assert_eq!(original(5), 10);
@@ -40,7 +44,6 @@ use crate::error::Result;
 #[derive(Debug)]
 pub struct RawDetour(Detour);
 
-// TODO: stop all threads in target during patch?
 impl RawDetour {
   /// Constructs a new inline detour patcher.
   ///
@@ -49,27 +52,107 @@ impl RawDetour {
   /// when the target function gets called. An invocation of the target
   /// function might for example get inlined in which case it is impossible to
   /// hook at runtime.
+  ///
+  /// # Safety
+  ///
+  /// The target and detour must be functions with the same signature and
+  /// calling convention, that remain valid for as long as the detour exists.
+  #[track_caller]
   pub unsafe fn new(target: *const (), detour: *const ()) -> Result<Self> {
     Detour::new(target, detour).map(RawDetour)
   }
 
+  /// Constructs a new inline detour patcher, with custom options.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for `new` apply.
+  #[track_caller]
+  pub unsafe fn with_options(
+    target: *const (),
+    detour: *const (),
+    options: &DetourOptions,
+  ) -> Result<Self> {
+    Detour::with_options(target, detour, options).map(RawDetour)
+  }
+
   /// Enables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must remain valid whilst the detour is enabled, and the
+  /// detour must handle calls of the target from any thread.
   pub unsafe fn enable(&self) -> Result<()> {
     self.0.enable()
   }
 
   /// Disables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must still be valid.
   pub unsafe fn disable(&self) -> Result<()> {
     self.0.disable()
   }
 
+  /// Disables the detour and waits until no other thread is executing the
+  /// trampoline, or until `timeout` has elapsed.
+  ///
+  /// The detour remains disabled even if the wait times out.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for disabling the detour apply.
+  #[cfg(target_os = "linux")]
+  #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+  pub unsafe fn disable_and_wait(&self, timeout: Duration) -> Result<()> {
+    self.0.disable_and_wait(timeout)
+  }
+
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
     self.0.is_enabled()
   }
 
+  /// Returns how the target's code is replaced when the detour is toggled.
+  pub fn write_strategy(&self) -> WriteStrategy {
+    self.0.write_strategy()
+  }
+
+  /// Returns the analysis of the target's patch.
+  pub fn analysis(&self) -> Analysis {
+    self.0.analysis()
+  }
+
+  /// Assigns a label to the detour, as reported by [live_detours](crate::live_detours).
+  pub fn set_label(&self, label: &str) {
+    self.0.set_label(label)
+  }
+
   /// Returns a reference to the generated trampoline.
   pub fn trampoline(&self) -> &() {
     self.0.trampoline()
   }
+
+  /// Returns a pointer to the generated trampoline.
+  pub fn trampoline_ptr(&self) -> *const () {
+    self.0.trampoline() as *const ()
+  }
+
+  /// Returns the generated trampoline as a function, invoking the original
+  /// target's code.
+  ///
+  /// # Safety
+  ///
+  /// The function type must match the target's signature, and it must not be
+  /// called once the detour has been dropped.
+  pub unsafe fn original_as<F: Function>(&self) -> F {
+    F::from_ptr(self.trampoline_ptr())
+  }
+}
+
+impl private::Sealed for RawDetour {
+  fn detour(&self) -> Option<&Detour> {
+    Some(&self.0)
+  }
 }
@@ -0,0 +1,69 @@
+use crate::arch;
+use std::ops::Range;
+use std::panic::Location;
+
+/// A snapshot of a live detour, as returned by [live_detours].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct DetourInfo {
+  /// The address of the hooked function.
+  pub target: *const (),
+  /// The address of the detour function.
+  pub detour: *const (),
+  /// The detour's own trampoline, which leads to the next detour in the
+  /// chain or to the original function.
+  pub trampoline: Range<usize>,
+  /// The relocated prolog of the target, shared by all of its detours.
+  pub original: Range<usize>,
+  /// The relay used to reach the detour (or to count its calls), if any.
+  pub relay: Option<Range<usize>>,
+  /// The bytes that are replaced when the target is patched.
+  pub patch_area: Range<usize>,
+  /// Whether the detour is enabled.
+  pub enabled: bool,
+  /// The label assigned to the detour, if any.
+  pub label: Option<String>,
+  /// Where the detour was created.
+  pub location: &'static Location<'static>,
+  /// Targets of other detours whose patch areas overlap with this one's,
+  /// e.g a hot patch extending into the end of the previous function.
+  pub overlapping: Vec<*const ()>,
+}
+
+unsafe impl Send for DetourInfo {}
+unsafe impl Sync for DetourInfo {}
+
+/// Returns all live detours of the process.
+///
+/// Detours are ordered by target, and detours sharing a target by creation.
+///
+/// # Example
+///
+/// ```rust
+/// # use retour::Result;
+/// use retour::{live_detours, RawDetour};
+///
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// fn add10(val: i32) -> i32 {
+///   val + 10
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let hook = unsafe { RawDetour::new(add5 as *const (), add10 as *const ())? };
+/// hook.set_label("add5");
+///
+/// let info = live_detours()
+///   .into_iter()
+///   .find(|info| info.target == add5 as *const ())
+///   .unwrap();
+/// assert_eq!(info.label.as_deref(), Some("add5"));
+/// assert!(!info.enabled);
+/// # Ok(())
+/// # }
+/// ```
+pub fn live_detours() -> Vec<DetourInfo> {
+  arch::live_detours()
+}
@@ -1,18 +1,52 @@
+use super::transaction::private;
+use super::Analysis;
+use crate::arch::{Detour, WriteStrategy};
 use crate::error::{Error, Result};
-use crate::{Function, GenericDetour};
-use std::marker::Tuple;
-use std::sync::atomic::{AtomicPtr, Ordering};
-use std::{mem, ptr};
+use crate::{DetourFn, Function, GenericDetour};
+use std::cell::Cell;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+#[cfg(target_os = "linux")]
+use std::time::Duration;
+use std::{fmt, mem, ptr};
 
 /// A type-safe static detour.
 ///
-/// Due to being generated by a macro, the `StaticDetour::call` method is not
-/// exposed in the documentation.  
+/// Due to being generated by a macro, the `initialize`, `set_detour` and
+/// `call` methods (along with the `_with_original` variants) are not exposed
+/// in the documentation. They accept closures with the same arguments as `T`,
+/// sharing its result type:
 ///
 /// ```c
+/// /// Creates a new hook given a target function and a compatible detour
+/// /// closure.
+/// ///
+/// /// This method can only be called once per static instance, until it is
+/// /// `reset`. Multiple calls will error with `AlreadyInitialized`. It returns
+/// /// `&self` to allow chaining initialization and activation.
+/// unsafe fn initialize<D>(&self, target: T, closure: D) -> Result<&Self>
+///   where D: Fn(T::Arguments...) -> T::Output + Send + 'static
+///
+/// /// Changes the detour, regardless of whether the hook is enabled or not.
+/// fn set_detour<C>(&self, closure: C)
+///   where C: Fn(T::Arguments...) -> T::Output + Send + 'static
+///
+/// /// Same as `initialize`, but the closure also receives a handle to the
+/// /// original function, preceding the target's arguments.
+/// unsafe fn initialize_with_original<D>(&'static self, target: T, closure: D) -> Result<&'static Self>
+///   where D: Fn(Original<T>, T::Arguments...) -> T::Output + Send + 'static
+///
+/// /// Same as `set_detour`, but the closure also receives a handle to the
+/// /// original function, preceding the target's arguments.
+/// fn set_detour_with_original<C>(&'static self, closure: C)
+///   where C: Fn(Original<T>, T::Arguments...) -> T::Output + Send + 'static
+///
 /// /// Calls the original function regardless of whether it's hooked or not.
+/// /// If the target has other enabled detours created before this one, the
+/// /// most recent of them is called instead.
 /// ///
-/// /// Panics if called when the static detour has not yet been initialized.
+/// /// Once reset, the restored target is called instead. Panics if called
+/// /// when the static detour has not yet been initialized.
 /// fn call(&self, T::Arguments) -> T::Output
 /// ```
 ///
@@ -63,142 +97,547 @@ use std::{mem, ptr};
 /// }
 /// ```
 pub struct StaticDetour<T: Function> {
-  closure: AtomicPtr<Box<dyn Fn<T::Arguments, Output = T::Output>>>,
+  closure: AtomicPtr<Closure<T>>,
+  /// The current epoch, which advances once all calls registered in the
+  /// previous one have completed.
+  epoch: AtomicUsize,
+  /// The number of calls using the detour or its closure, striped to reduce
+  /// contention between threads.
+  stripes: [Stripe; STRIPES],
+  /// Replaced closures and reset detours, that may still be used by other
+  /// threads.
+  retired: AtomicPtr<Retired<T>>,
+  /// Retired entries that are no longer used, awaiting their release outside
+  /// of any call.
+  expired: AtomicPtr<Retired<T>>,
   detour: AtomicPtr<GenericDetour<T>>,
+  /// The most recently initialized target, which is called once its detour
+  /// has been reset.
+  target: AtomicUsize,
   ffi: T,
 }
 
+/// A handle to the original function of a static detour.
+///
+/// It is passed to the closures of `initialize_with_original` and
+/// `set_detour_with_original`, allowing them to call the original function
+/// without naming the static. This enables generic helpers to install the same
+/// detour on many statics. Due to being generated by a macro, the `call`
+/// method is not exposed in the documentation:
+///
+/// ```c
+/// /// Calls the original function, as done by `StaticDetour::call`.
+/// fn call(&self, T::Arguments) -> T::Output
+/// ```
+///
+/// # Example
+///
+/// ```rust
+/// # use retour::Result;
+/// use retour::{static_detour, StaticDetour};
+///
+/// static_detour! {
+///   static Test: fn(i32) -> i32;
+/// }
+///
+/// #[inline(never)]
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// /// Doubles the result of any target.
+/// unsafe fn double(detour: &'static StaticDetour<fn(i32) -> i32>, target: fn(i32) -> i32) -> Result<()> {
+///   detour
+///     .initialize_with_original(target, |original, val| original.call(val) * 2)?
+///     .enable()
+/// }
+///
+/// # fn main() -> Result<()> {
+/// unsafe { double(&Test, add5)? };
+/// assert_eq!(add5(1), 12);
+/// # Ok(())
+/// # }
+/// ```
+pub struct Original<T: Function> {
+  detour: &'static StaticDetour<T>,
+}
+
+impl<T: Function> Original<T> {
+  /// Creates a handle to the original function of a static detour.
+  #[doc(hidden)]
+  pub fn __new(detour: &'static StaticDetour<T>) -> Self {
+    Original { detour }
+  }
+
+  /// Returns the static detour of the original function.
+  pub fn detour(&self) -> &'static StaticDetour<T> {
+    self.detour
+  }
+}
+
+impl<T: Function> Clone for Original<T> {
+  fn clone(&self) -> Self {
+    *self
+  }
+}
+
+impl<T: Function> Copy for Original<T> {}
+
+impl<T: Function> fmt::Debug for Original<T> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_struct("Original")
+      .field("detour", &(self.detour as *const StaticDetour<T>))
+      .finish()
+  }
+}
+
+/// The detour closure of a `StaticDetour<T>`.
+type Callable<T> =
+  dyn DetourFn<<T as Function>::Arguments, Output = <T as Function>::Output> + Send;
+
+/// The boxed detour closure of a `StaticDetour<T>`.
+type Closure<T> = Box<Callable<T>>;
+
+/// The number of stripes of the caller counts.
+const STRIPES: usize = 8;
+
+/// The caller counts of a stripe, indexed by the parity of the epoch the
+/// calls were registered in. Each stripe occupies its own cache line.
+#[repr(align(64))]
+struct Stripe {
+  callers: [AtomicUsize; 2],
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_STRIPE: Stripe = Stripe {
+  callers: [AtomicUsize::new(0), AtomicUsize::new(0)],
+};
+
+/// The source of the stripes assigned to threads.
+static NEXT_STRIPE: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+  /// The stripe used by the current thread, once assigned.
+  static STRIPE: Cell<usize> = const { Cell::new(usize::MAX) };
+}
+
+/// Returns the stripe used by the current thread.
+fn stripe() -> usize {
+  STRIPE.with(|stripe| {
+    if stripe.get() == usize::MAX {
+      stripe.set(NEXT_STRIPE.fetch_add(1, Ordering::Relaxed) % STRIPES);
+    }
+    stripe.get()
+  })
+}
+
+/// A replaced closure or reset detour, awaiting the completion of all calls
+/// using it.
+struct Retired<T: Function> {
+  // These are only held until released
+  #[allow(dead_code)]
+  closure: Option<Box<Closure<T>>>,
+  #[allow(dead_code)]
+  detour: Option<Box<GenericDetour<T>>>,
+  /// The epoch when it was replaced.
+  epoch: usize,
+  next: *mut Retired<T>,
+}
+
+/// A call using a static detour, or its closure.
+///
+/// Neither is released until all calls using them are dropped.
+#[doc(hidden)]
+pub struct ActiveCall<'a, T: Function, V> {
+  detour: &'a StaticDetour<T>,
+  /// The count the call is registered in.
+  callers: &'a AtomicUsize,
+  value: V,
+}
+
+impl<'a, T: Function> ActiveCall<'a, T, ()> {
+  /// Returns the same call, holding a value loaded after it was registered.
+  fn with<V>(self, value: V) -> ActiveCall<'a, T, V> {
+    let (detour, callers) = (self.detour, self.callers);
+    mem::forget(self);
+    ActiveCall {
+      detour,
+      callers,
+      value,
+    }
+  }
+}
+
+impl<'a, T: Function, V> Deref for ActiveCall<'a, T, V> {
+  type Target = V;
+
+  fn deref(&self) -> &Self::Target {
+    &self.value
+  }
+}
+
+impl<'a, T: Function, V> Drop for ActiveCall<'a, T, V> {
+  fn drop(&mut self) {
+    self.detour.leave(self.callers);
+  }
+}
+
 impl<T: Function> StaticDetour<T> {
   /// Create a new static detour.
   #[doc(hidden)]
   pub const fn __new(ffi: T) -> Self {
     StaticDetour {
       closure: AtomicPtr::new(ptr::null_mut()),
+      epoch: AtomicUsize::new(0),
+      stripes: [UNUSED_STRIPE; STRIPES],
+      retired: AtomicPtr::new(ptr::null_mut()),
+      expired: AtomicPtr::new(ptr::null_mut()),
       detour: AtomicPtr::new(ptr::null_mut()),
+      target: AtomicUsize::new(0),
       ffi,
     }
   }
 
-  /// Create a new hook given a target function and a compatible detour
-  /// closure.
-  ///
-  /// This method can only be called once per static instance. Multiple calls
-  /// will error with `AlreadyExisting`.
-  ///
-  /// It returns `&self` to allow chaining initialization and activation:
-  ///
-  /// ```rust
-  /// # use retour::{Result, static_detour};
-  /// # static_detour! {
-  /// #   static Test: fn(i32) -> i32;
-  /// # }
-  /// #
-  /// # fn add5(val: i32) -> i32 {
-  /// #   val + 5
-  /// # }
-  /// #
-  /// # fn main() -> Result<()> {
-  /// unsafe { Test.initialize(add5, |x| x - 5)?.enable()? };
-  /// # Ok(())
-  /// # }
-  /// ```
-  pub unsafe fn initialize<D>(&self, target: T, closure: D) -> Result<&Self>
-  where
-    D: Fn<T::Arguments, Output = T::Output> + Send + 'static,
-    <T as Function>::Arguments: Tuple,
-  {
-    let mut detour = Box::new(GenericDetour::new(target, self.ffi)?);
+  /// Create a new hook given a target function and a boxed detour closure,
+  /// as done by the generated `initialize` method.
+  #[doc(hidden)]
+  #[track_caller]
+  pub unsafe fn __initialize(&self, target: T, closure: Closure<T>) -> Result<&Self> {
+    let detour = Box::into_raw(Box::new(GenericDetour::new(target, self.ffi)?));
     if self
       .detour
-      .compare_exchange(
-        ptr::null_mut(),
-        &mut *detour,
-        Ordering::SeqCst,
-        Ordering::SeqCst,
-      )
+      .compare_exchange(ptr::null_mut(), detour, Ordering::SeqCst, Ordering::SeqCst)
       .is_err()
     {
+      drop(Box::from_raw(detour));
       Err(Error::AlreadyInitialized)?;
     }
 
-    self.set_detour(closure);
-    mem::forget(detour);
+    self
+      .target
+      .store(target.to_ptr() as usize, Ordering::SeqCst);
+    self.__set_detour(closure);
     Ok(self)
   }
 
+  /// Disables the detour, and releases it along with its closure. The
+  /// static detour can then be initialized again, e.g with another target.
+  ///
+  /// Other threads may still be using the detour or its closure (through
+  /// `call`, or by calling the target), in which case they are released by
+  /// the first `initialize`, `set_detour` or `reset` after the last of these
+  /// calls has completed. Calls of the target that are
+  /// made after the reset, but were detoured before it, invoke the original
+  /// function.
+  ///
+  /// # Safety
+  ///
+  /// The detour must not be enabled by another thread whilst it is reset,
+  /// and any reference returned by `trampoline` becomes invalid.
+  pub unsafe fn reset(&self) -> Result<()> {
+    self.with_detour(|detour| detour.disable())??;
+
+    let detour = self.detour.swap(ptr::null_mut(), Ordering::SeqCst);
+    if detour.is_null() {
+      // Another thread reset the detour concurrently
+      Err(Error::NotInitialized)?;
+    }
+
+    let closure = self.closure.swap(ptr::null_mut(), Ordering::SeqCst);
+    self.retire(Retired {
+      closure: (!closure.is_null()).then(|| Box::from_raw(closure)),
+      detour: Some(Box::from_raw(detour)),
+      epoch: 0,
+      next: ptr::null_mut(),
+    });
+    self.reclaim();
+    Ok(())
+  }
+
+  /// Disables and releases the detour, as done by `reset`.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for `reset` apply.
+  pub unsafe fn deinitialize(&self) -> Result<()> {
+    self.reset()
+  }
+
+  /// Returns whether the static detour has been initialized, and not reset.
+  pub fn is_initialized(&self) -> bool {
+    !self.detour.load(Ordering::SeqCst).is_null()
+  }
+
   /// Enables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must remain valid whilst the detour is enabled.
   pub unsafe fn enable(&self) -> Result<()> {
-    self
-      .detour
-      .load(Ordering::SeqCst)
-      .as_ref()
-      .ok_or(Error::NotInitialized)?
-      .enable()
+    self.with_detour(|detour| detour.enable())?
   }
 
   /// Disables the detour.
+  ///
+  /// # Safety
+  ///
+  /// The target must still be valid.
   pub unsafe fn disable(&self) -> Result<()> {
-    self
-      .detour
-      .load(Ordering::SeqCst)
-      .as_ref()
-      .ok_or(Error::NotInitialized)?
-      .disable()
+    self.with_detour(|detour| detour.disable())?
+  }
+
+  /// Disables the detour and waits until no other thread is executing the
+  /// trampoline, or until `timeout` has elapsed.
+  ///
+  /// The detour remains disabled even if the wait times out.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for disabling the detour apply.
+  #[cfg(target_os = "linux")]
+  #[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+  pub unsafe fn disable_and_wait(&self, timeout: Duration) -> Result<()> {
+    self.with_detour(|detour| detour.disable_and_wait(timeout))?
   }
 
   /// Returns whether the detour is enabled or not.
   pub fn is_enabled(&self) -> bool {
-    unsafe { self.detour.load(Ordering::SeqCst).as_ref() }
-      .map(|detour| detour.is_enabled())
+    self
+      .with_detour(|detour| detour.is_enabled())
       .unwrap_or(false)
   }
 
-  /// Changes the detour, regardless of whether the hook is enabled or not.
-  pub fn set_detour<C>(&self, closure: C)
-  where
-    C: Fn<T::Arguments, Output = T::Output> + Send + 'static,
-    <T as Function>::Arguments: Tuple,
-  {
+  /// Changes the detour to a boxed closure, as done by the generated
+  /// `set_detour` method.
+  ///
+  /// The previous closure is released by the first `initialize`,
+  /// `set_detour` or `reset` once no other thread is executing it.
+  #[doc(hidden)]
+  pub fn __set_detour(&self, closure: Closure<T>) {
     let previous = self
       .closure
-      .swap(Box::into_raw(Box::new(Box::new(closure))), Ordering::SeqCst);
+      .swap(Box::into_raw(Box::new(closure)), Ordering::SeqCst);
     if !previous.is_null() {
-      mem::drop(unsafe { Box::from_raw(previous) });
+      self.retire(Retired {
+        closure: Some(unsafe { Box::from_raw(previous) }),
+        detour: None,
+        epoch: 0,
+        next: ptr::null_mut(),
+      });
     }
+    self.reclaim();
+  }
+
+  /// Returns how the target's code is replaced when the detour is toggled.
+  pub fn write_strategy(&self) -> Result<WriteStrategy> {
+    self.with_detour(|detour| detour.write_strategy())
+  }
+
+  /// Returns the analysis of the target's patch.
+  pub fn analysis(&self) -> Result<Analysis> {
+    self.with_detour(|detour| detour.analysis())
+  }
+
+  /// Assigns a label to the detour, as reported by [live_detours](crate::live_detours).
+  pub fn set_label(&self, label: &str) -> Result<()> {
+    self.with_detour(|detour| detour.set_label(label))
   }
 
   /// Returns a reference to the generated trampoline.
+  ///
+  /// The reference is invalidated once the detour is reset.
   pub fn trampoline(&self) -> Result<&()> {
-    Ok(
-      unsafe { self.detour.load(Ordering::SeqCst).as_ref() }
-        .ok_or(Error::NotInitialized)?
-        .trampoline(),
-    )
+    let trampoline = self.with_detour(|detour| detour.trampoline() as *const ())?;
+    Ok(unsafe { &*trampoline })
   }
 
-  /// Returns a transient reference to the active detour.
+  /// Returns the generated trampoline as a function, invoking the original
+  /// target's code.
+  ///
+  /// # Safety
+  ///
+  /// The function is not tied to the detour, and must not be called once the
+  /// detour has been reset. Unlike `call`, this does not prevent a concurrent
+  /// reset from releasing it. Since `T` may be safe to call, obtaining it is
+  /// unsafe instead.
+  pub unsafe fn original(&self) -> Result<T> {
+    self.with_detour(|detour| detour.original())
+  }
+
+  /// Returns the active detour, if any, which is kept alive until the
+  /// returned call is dropped.
+  #[doc(hidden)]
+  pub fn __detour(&self) -> Option<ActiveCall<'_, T, &Callable<T>>> {
+    let call = self.enter();
+    let closure = unsafe { self.closure.load(Ordering::SeqCst).as_ref() }?;
+    Some(call.with(&**closure))
+  }
+
+  /// Returns the original function, which is kept alive until the returned
+  /// call is dropped.
+  ///
+  /// Once the detour has been reset, this is the (restored) target itself.
   #[doc(hidden)]
-  pub fn __detour(&self) -> &dyn Fn<T::Arguments, Output = T::Output>
-  where
-    <T as Function>::Arguments: Tuple,
-  {
-    // TODO: This is not 100% thread-safe in case the thread is stopped
-    unsafe { self.closure.load(Ordering::SeqCst).as_ref() }
-      .ok_or(Error::NotInitialized)
-      .expect("retrieving detour closure")
+  pub fn __original(&self) -> Option<ActiveCall<'_, T, T>> {
+    let call = self.enter();
+    let original = match unsafe { self.detour.load(Ordering::SeqCst).as_ref() } {
+      Some(detour) => unsafe { detour.original() },
+      None => match self.target.load(Ordering::SeqCst) {
+        0 => return None,
+        target => unsafe { T::from_ptr(target as *const ()) },
+      },
+    };
+
+    Some(call.with(original))
+  }
+
+  /// Calls a function with the detour, if initialized.
+  fn with_detour<R>(&self, function: impl FnOnce(&GenericDetour<T>) -> R) -> Result<R> {
+    let _call = self.enter();
+    let detour = unsafe { self.detour.load(Ordering::SeqCst).as_ref() };
+    detour.map(function).ok_or(Error::NotInitialized)
+  }
+
+  /// Registers a call in the current epoch, before the detour or its closure
+  /// is loaded.
+  fn enter(&self) -> ActiveCall<'_, T, ()> {
+    let stripe = &self.stripes[stripe()];
+    loop {
+      let epoch = self.epoch.load(Ordering::SeqCst);
+      let callers = &stripe.callers[epoch % 2];
+      callers.fetch_add(1, Ordering::SeqCst);
+
+      // The call must be counted before the epoch advances past it
+      if self.epoch.load(Ordering::SeqCst) == epoch {
+        return ActiveCall {
+          detour: self,
+          callers,
+          value: (),
+        };
+      }
+      callers.fetch_sub(1, Ordering::SeqCst);
+    }
+  }
+
+  /// Unregisters a call, expiring anything retired it was the last to use.
+  ///
+  /// Nothing is released here, since the call may be made within a hook (e.g
+  /// whilst the crate's locks are held, or by a signal handler).
+  fn leave(&self, callers: &AtomicUsize) {
+    callers.fetch_sub(1, Ordering::SeqCst);
+    if !self.retired.load(Ordering::SeqCst).is_null() {
+      self.expire();
+    }
+  }
+
+  /// Advances the epoch if no calls registered in the previous one remain,
+  /// and returns the current epoch.
+  fn advance(&self) -> usize {
+    let epoch = self.epoch.load(Ordering::SeqCst);
+    let previous = epoch.wrapping_add(1) % 2;
+    if self
+      .stripes
+      .iter()
+      .all(|stripe| stripe.callers[previous].load(Ordering::SeqCst) == 0)
+    {
+      // Another thread may have advanced it concurrently
+      let _ = self.epoch.compare_exchange(
+        epoch,
+        epoch.wrapping_add(1),
+        Ordering::SeqCst,
+        Ordering::SeqCst,
+      );
+    }
+    self.epoch.load(Ordering::SeqCst)
+  }
+
+  /// Adds a replaced closure or reset detour to the retired list.
+  fn retire(&self, mut retired: Retired<T>) {
+    // The epoch is observed after it has been replaced
+    retired.epoch = self.epoch.load(Ordering::SeqCst);
+    Self::push(&self.retired, Box::into_raw(Box::new(retired)));
+  }
+
+  /// Pushes an entry onto a list of retired entries.
+  fn push(list: &AtomicPtr<Retired<T>>, entry: *mut Retired<T>) {
+    let mut head = list.load(Ordering::SeqCst);
+    loop {
+      unsafe { (*entry).next = head };
+      match list.compare_exchange_weak(head, entry, Ordering::SeqCst, Ordering::SeqCst) {
+        Ok(_) => break,
+        Err(current) => head = current,
+      }
+    }
+  }
+
+  /// Moves everything retired that is no longer being used to the expired
+  /// list, without allocating or releasing anything.
+  ///
+  /// A closure or detour can only be used by calls registered in (or before)
+  /// the epoch it was replaced in. These have all completed once the epoch
+  /// has advanced twice since, regardless of any calls registered later.
+  fn expire(&self) {
+    self.advance();
+    let epoch = self.advance();
+    let mut retired = self.retired.swap(ptr::null_mut(), Ordering::SeqCst);
+
+    while !retired.is_null() {
+      let entry = retired;
+      retired = unsafe { (*entry).next };
+
+      if epoch.wrapping_sub(unsafe { (*entry).epoch }) < 2 {
+        Self::push(&self.retired, entry);
+      } else {
+        Self::push(&self.expired, entry);
+      }
+    }
+  }
+
+  /// Releases everything retired that is no longer being used.
+  ///
+  /// This is only done by `initialize`, `set_detour` and `reset`, since
+  /// releasing a detour locks and suspends threads.
+  fn reclaim(&self) {
+    self.expire();
+    let mut expired = self.expired.swap(ptr::null_mut(), Ordering::SeqCst);
+
+    while !expired.is_null() {
+      let entry = unsafe { Box::from_raw(expired) };
+      expired = entry.next;
+    }
+  }
+}
+
+impl<T: Function> private::Sealed for StaticDetour<T> {
+  /// Returns the detour, which must not be reset whilst it is referenced.
+  fn detour(&self) -> Option<&Detour> {
+    unsafe { self.detour.load(Ordering::SeqCst).as_ref() }.and_then(private::Sealed::detour)
   }
 }
 
 impl<T: Function> Drop for StaticDetour<T> {
+  /// Disables and releases the detour, along with all closures.
+  ///
+  /// Statics are never dropped, so a static detour is only released by
+  /// `reset`. Since any calls borrow the static detour, none remain once it
+  /// is dropped.
   fn drop(&mut self) {
-    let previous = self.closure.swap(ptr::null_mut(), Ordering::Relaxed);
-    if !previous.is_null() {
-      mem::drop(unsafe { Box::from_raw(previous) });
+    let closure = self.closure.swap(ptr::null_mut(), Ordering::Relaxed);
+    if !closure.is_null() {
+      drop(unsafe { Box::from_raw(closure) });
     }
 
-    let previous = self.detour.swap(ptr::null_mut(), Ordering::Relaxed);
-    if !previous.is_null() {
-      unsafe { let _ = Box::from_raw(previous); };
+    let detour = self.detour.swap(ptr::null_mut(), Ordering::Relaxed);
+    if !detour.is_null() {
+      drop(unsafe { Box::from_raw(detour) });
+    }
+
+    for list in [&self.retired, &self.expired] {
+      let mut retired = list.swap(ptr::null_mut(), Ordering::Relaxed);
+      while !retired.is_null() {
+        let entry = unsafe { Box::from_raw(retired) };
+        retired = entry.next;
+      }
     }
   }
 }
@@ -0,0 +1,110 @@
+use crate::arch::Detour;
+use crate::error::{Error, Result};
+
+/// A batch of detour state changes that are applied together.
+///
+/// Staged detours are enabled or disabled in a single pass once the
+/// transaction is committed. All affected pages are made writable up front,
+/// once per page, so a failure leaves every detour in its previous state
+/// instead of a partially hooked process.
+///
+/// # Example
+///
+/// ```rust
+/// # use retour::Result;
+/// use retour::{DetourTransaction, GenericDetour, RawDetour};
+///
+/// fn add5(val: i32) -> i32 {
+///   val + 5
+/// }
+///
+/// fn add10(val: i32) -> i32 {
+///   val + 10
+/// }
+///
+/// fn sub5(val: i32) -> i32 {
+///   val - 5
+/// }
+///
+/// fn sub10(val: i32) -> i32 {
+///   val - 10
+/// }
+///
+/// # fn main() -> Result<()> {
+/// let add = unsafe { GenericDetour::<fn(i32) -> i32>::new(add5, add10)? };
+/// let sub = unsafe { RawDetour::new(sub5 as *const (), sub10 as *const ())? };
+///
+/// let mut transaction = DetourTransaction::new();
+/// transaction.enable(&add).enable(&sub);
+/// unsafe { transaction.commit()? };
+///
+/// assert!(add.is_enabled() && sub.is_enabled());
+/// assert_eq!(add5(5), 15);
+/// assert_eq!(sub5(15), 5);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct DetourTransaction<'a> {
+  operations: Vec<(&'a dyn Transactable, bool)>,
+}
+
+impl<'a> DetourTransaction<'a> {
+  /// Creates an empty transaction.
+  pub fn new() -> Self {
+    DetourTransaction {
+      operations: Vec::new(),
+    }
+  }
+
+  /// Stages a detour to be enabled.
+  pub fn enable(&mut self, detour: &'a dyn Transactable) -> &mut Self {
+    self.operations.push((detour, true));
+    self
+  }
+
+  /// Stages a detour to be disabled.
+  pub fn disable(&mut self, detour: &'a dyn Transactable) -> &mut Self {
+    self.operations.push((detour, false));
+    self
+  }
+
+  /// Applies all staged changes.
+  ///
+  /// If a detour is staged more than once, the last change is used. Detours
+  /// already in their requested state are left untouched.
+  ///
+  /// # Safety
+  ///
+  /// The same requirements as for enabling or disabling each detour apply.
+  pub unsafe fn commit(self) -> Result<()> {
+    let mut operations: Vec<(&Detour, bool)> = Vec::with_capacity(self.operations.len());
+
+    for (detour, enabled) in self.operations {
+      let detour = detour.detour().ok_or(Error::NotInitialized)?;
+
+      // Only the most recent change of a detour is retained
+      operations.retain(|(staged, _)| !std::ptr::eq(*staged, detour));
+      operations.push((detour, enabled));
+    }
+
+    Detour::toggle_all(&operations)
+  }
+}
+
+/// A detour that can be staged in a [DetourTransaction].
+///
+/// This trait is sealed and implemented by all detour types.
+pub trait Transactable: private::Sealed {}
+
+impl<T: private::Sealed> Transactable for T {}
+
+pub(crate) mod private {
+  use crate::arch::Detour;
+
+  /// Provides access to the underlying detour.
+  pub trait Sealed {
+    /// Returns the detour, if it has been initialized.
+    fn detour(&self) -> Option<&Detour>;
+  }
+}
@@ -2,19 +2,33 @@
 
 use std::error::Error as StdError;
 use std::fmt;
+use std::sync::Arc;
 
 /// The result of a detour operation.
 pub type Result<T> = ::std::result::Result<T, Error>;
 
 /// A representation of all possible errors.
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
 pub enum Error {
   /// The address for the target and detour are identical
   SameAddress,
   /// The address does not contain valid instructions.
-  InvalidCode,
+  InvalidCode {
+    /// The address of the function.
+    target: usize,
+    /// The offset of the invalid code within the function.
+    offset: usize,
+  },
   /// The address has no available area for patching.
-  NoPatchArea,
+  NoPatchArea {
+    /// The address of the function.
+    target: usize,
+    /// The size of the function's prolog.
+    available: usize,
+    /// The size required by the patch.
+    required: usize,
+  },
   /// The address is not executable memory.
   NotExecutable,
   /// The detour is not initialized.
@@ -24,15 +38,59 @@ pub enum Error {
   /// The system is out of executable memory.
   OutOfMemory,
   /// The address contains an instruction that prevents detouring.
-  UnsupportedInstruction,
+  UnsupportedInstruction {
+    /// The address of the function.
+    target: usize,
+    /// The offset of the instruction within the function.
+    offset: usize,
+    /// The encoded instruction.
+    bytes: Vec<u8>,
+    /// The instruction's mnemonic.
+    mnemonic: String,
+  },
+  /// The other threads of the process could not be suspended.
+  SuspendFailed,
+  /// Other threads did not leave the detour in time.
+  Timeout,
+  /// A relative branch or operand cannot reach its destination.
+  DisplacementOutOfRange {
+    /// The address of the instruction.
+    source: usize,
+    /// The address it refers to.
+    destination: usize,
+  },
+  /// Generated code did not have its expected size.
+  InvalidThunkSize {
+    /// The expected size of the code.
+    expected: usize,
+    /// The size of the generated code.
+    actual: usize,
+  },
+  /// The memory at the address cannot be read.
+  UnreadableAddress {
+    /// The address that was read.
+    address: usize,
+  },
+  /// The function's body branches into the bytes replaced by its patch.
+  BranchIntoProlog {
+    /// The address of the function.
+    target: usize,
+    /// The address of the branch.
+    source: usize,
+    /// The address it branches to.
+    destination: usize,
+  },
+  /// A breakpoint cannot be installed, since its signal handler is not
+  /// supported or no more breakpoints are available.
+  TrapFailed,
   /// A memory operation failed.
-  RegionFailure(region::Error),
+  RegionFailure(RegionError),
 }
 
 impl StdError for Error {
   fn source(&self) -> Option<&(dyn StdError + 'static)> {
     if let Error::RegionFailure(error) = self {
-      Some(error)
+      Some(error.inner())
     } else {
       None
     }
@@ -43,13 +101,69 @@ impl fmt::Display for Error {
   fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
     match self {
       Error::SameAddress => write!(f, "Target and detour address is the same"),
-      Error::InvalidCode => write!(f, "Address contains invalid assembly"),
-      Error::NoPatchArea => write!(f, "Cannot find an inline patch area"),
+      Error::InvalidCode { target, offset } => write!(
+        f,
+        "Address {:#x} contains invalid assembly at offset {}",
+        target, offset
+      ),
+      Error::NoPatchArea {
+        target,
+        available,
+        required,
+      } => write!(
+        f,
+        "Cannot find an inline patch area at {:#x} ({} bytes available, {} required)",
+        target, available, required
+      ),
       Error::NotExecutable => write!(f, "Address is not executable"),
       Error::NotInitialized => write!(f, "Detour is not initialized"),
       Error::AlreadyInitialized => write!(f, "Detour is already initialized"),
       Error::OutOfMemory => write!(f, "Cannot allocate memory"),
-      Error::UnsupportedInstruction => write!(f, "Address contains an unsupported instruction"),
+      Error::UnsupportedInstruction {
+        target,
+        offset,
+        bytes,
+        mnemonic,
+      } => {
+        write!(
+          f,
+          "Address {:#x} contains an unsupported instruction at offset {}: {} (",
+          target, offset, mnemonic
+        )?;
+        for (index, byte) in bytes.iter().enumerate() {
+          let separator = if index == 0 { "" } else { " " };
+          write!(f, "{}{:02x}", separator, byte)?;
+        }
+        write!(f, ")")
+      },
+      Error::SuspendFailed => write!(f, "Cannot suspend other threads"),
+      Error::Timeout => write!(f, "Detour is still being executed"),
+      Error::DisplacementOutOfRange {
+        source,
+        destination,
+      } => write!(
+        f,
+        "Destination {:#x} is out of range from {:#x}",
+        destination, source
+      ),
+      Error::InvalidThunkSize { expected, actual } => write!(
+        f,
+        "Generated {} bytes of code instead of {}",
+        actual, expected
+      ),
+      Error::UnreadableAddress { address } => {
+        write!(f, "Address {:#x} cannot be read", address)
+      },
+      Error::BranchIntoProlog {
+        target,
+        source,
+        destination,
+      } => write!(
+        f,
+        "Branch at {:#x} leads into the prolog of {:#x}, at {:#x}",
+        source, target, destination
+      ),
+      Error::TrapFailed => write!(f, "Cannot install a breakpoint"),
       Error::RegionFailure(ref error) => write!(f, "{}", error),
     }
   }
@@ -57,6 +171,32 @@ impl fmt::Display for Error {
 
 impl From<region::Error> for Error {
   fn from(error: region::Error) -> Self {
-    Error::RegionFailure(error)
+    Error::RegionFailure(RegionError(Arc::new(error)))
+  }
+}
+
+/// A failed memory operation.
+///
+/// The underlying error is shared, so that it can be cloned. Two errors are
+/// equal if they share the same error, or have the same description.
+#[derive(Debug, Clone)]
+pub struct RegionError(Arc<region::Error>);
+
+impl RegionError {
+  /// Returns the underlying error.
+  pub fn inner(&self) -> &region::Error {
+    &self.0
+  }
+}
+
+impl PartialEq for RegionError {
+  fn eq(&self, other: &Self) -> bool {
+    Arc::ptr_eq(&self.0, &other.0) || self.0.to_string() == other.0.to_string()
+  }
+}
+
+impl fmt::Display for RegionError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "{}", self.0)
   }
 }
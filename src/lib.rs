@@ -1,11 +1,7 @@
 #![recursion_limit = "1024"]
 #![cfg_attr(docsrs, feature(doc_cfg))]
 #![cfg_attr(
-  feature = "static-detour",
-  feature(unboxed_closures, tuple_trait)
-)]
-#![cfg_attr(
-  all(feature = "static-detour", test),
+  all(feature = "nightly", test),
   feature(naked_functions)
 )]
 
@@ -22,19 +18,37 @@
 //! cases:
 //!
 //! - Relative branches.
-//! - RIP relative operands.
+//! - RIP relative operands, even when the trampoline is out of their range.
 //! - Detects NOP-padding.
 //! - Relay for large offsets (>2GB).
 //! - Supports hot patching.
+//! - Configurable patch strategies, including absolute jumps that need no
+//!   relay, and short jumps into nearby padding.
+//! - Falls back to breakpoint hooks for functions too small to patch
+//!   (Linux).
+//! - Guard page hooks, which leave the target's code untouched (Linux).
+//! - Keeps `endbr` instructions (CET) in place, and prefers patching
+//!   compiler-provided NOP sleds.
+//! - Chains multiple detours of the same target.
+//! - Optionally detects branches from the function body into the patched
+//!   prolog.
+//! - Replaces live code atomically, whenever the patch's alignment permits.
+//! - Optionally suspends all other threads whilst patching (Linux).
+//! - Releases a dropped detour once no thread executes it, or optionally
+//!   once none of its calls remain in flight.
 //!
 //! ## Detours
 //!
-//! Three different types of detours are provided:
+//! Four different types of detours are provided:
 //!
 //! - [Static](./struct.StaticDetour.html): A static & type-safe interface.
 //!   Thanks to its static nature it can accept a closure as its detour, but is
 //!   required to be statically defined at compile time.
 //!
+//! - [Closure](./struct.ClosureDetour.html): A type-safe interface that
+//!   accepts a capturing closure as its detour, without a `static` per hook.
+//!   It is available on stable Rust, at the cost of a small thunk per hook.
+//!
 //! - [Generic](./struct.GenericDetour.html): A type-safe interface — the same
 //!   prototype is enforced for both the target and the detour. It is also
 //!   enforced when invoking the original target.
@@ -43,6 +57,20 @@
 //!   others types abstract upon. It has no type-safety and interacts with raw
 //!   pointers. It should be avoided unless any types are references, or not
 //!   known until runtime.
+//!
+//! Any number of detours can be enabled or disabled together using a
+//! [DetourTransaction](./struct.DetourTransaction.html), which either applies
+//! every change or none of them.
+//!
+//! Detours sharing a target form a chain: the target calls the most recently
+//! created enabled detour, and each detour's trampoline calls the next one.
+//! Detours may be enabled, disabled or dropped in any order. All live detours
+//! of the process can be listed using [live_detours](./fn.live_detours.html).
+//!
+//! The calls of a detour can be counted until they return, by replacing their
+//! return addresses (see [DetourOptions](./struct.DetourOptions.html)), so a
+//! dropped detour is only released once no call uses it. Such a detour must
+//! not unwind (e.g panic) or `longjmp` past its caller.
 //! 
 //! ## Supported Versions
 //! This crate, with default features, will support the MSRV in `Cargo.toml` 
@@ -52,9 +80,10 @@
 //!
 //! ## Features
 //!
-//! - **static-detour**: Required for static detours, due to usage
-//!   of *unboxed_closures* and *tuple_trait*. The feature also enables a more
-//!   extensive test suite. *Requires nightly compiler*
+//! - **static-detour**: *Deprecated*. Static detours are available on stable
+//!   Rust, so the feature has no effect (and no longer enables **nightly**).
+//! - **nightly**: Enables a more extensive test suite. *Requires nightly
+//!   compiler*
 //! - **thiscall-abi**: Required for hooking functions that use the "thiscall" ABI. *Requires 1.73.0 or greater*
 //! - **28-args**: Allows for detouring functions up to 28 arguments (default is 14)
 //! - **42-args**: Allows for detouring functions up to 42 arguments
@@ -106,9 +135,14 @@
 //! For various injection methods, see the [README in the GitHub repo](https://github.com/Hpmason/retour-rs)
 
 // Re-exports
+pub use arch::WriteStrategy;
 pub use detours::*;
-pub use error::{Error, Result};
-pub use traits::{Function, HookableWith};
+pub use error::{Error, RegionError, Result};
+pub use traits::{DetourFn, Function, HookableWith};
+
+#[cfg(target_os = "linux")]
+#[cfg_attr(docsrs, doc(cfg(target_os = "linux")))]
+pub use suspend::{set_thread_suspension, thread_suspension};
 
 #[macro_use]
 mod macros;
@@ -119,7 +153,9 @@ mod arch;
 mod detours;
 mod error;
 mod pic;
+mod suspend;
 mod traits;
+mod trap;
 mod util;
 
 #[cfg(test)]
@@ -160,6 +196,159 @@ mod tests {
     Ok(())
   }
 
+  #[test]
+  fn read_unmapped_memory() -> Result<()> {
+    let address = region::alloc(region::page::size(), region::Protection::READ)?.as_ptr::<u8>()
+      as usize;
+
+    // The allocation is unmapped once dropped
+    let error = unsafe { util::read_memory(address, 16) }.unwrap_err();
+    assert_eq!(error, Error::UnreadableAddress { address });
+    Ok(())
+  }
+
+  #[test]
+  fn detours_removed_out_of_order() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn add(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    extern "C" fn sub(x: i32, y: i32) -> i32 {
+      x - y
+    }
+
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      x * y
+    }
+
+    extern "C" fn div(x: i32, y: i32) -> i32 {
+      x / y
+    }
+
+    type FnAdd = extern "C" fn(i32, i32) -> i32;
+    let hook1 = unsafe { GenericDetour::<FnAdd>::new(add, sub)? };
+    let hook2 = unsafe { GenericDetour::<FnAdd>::new(add, mul)? };
+    let hook3 = unsafe { GenericDetour::<FnAdd>::new(add, div)? };
+    unsafe {
+      hook1.enable()?;
+      hook2.enable()?;
+      hook3.enable()?;
+    }
+
+    // Each hook calls the next hook in the chain
+    assert_eq!(add(10, 5), 2);
+    assert_eq!(hook3.call(10, 5), 50);
+    assert_eq!(hook2.call(10, 5), 5);
+    assert_eq!(hook1.call(10, 5), 15);
+
+    // Disabling a hook in the middle skips it
+    unsafe { hook2.disable()? };
+    assert_eq!(hook3.call(10, 5), 5);
+    unsafe { hook2.enable()? };
+
+    // Removing the first hook leaves the others intact
+    drop(hook1);
+    assert_eq!(add(10, 5), 2);
+    assert_eq!(hook3.call(10, 5), 50);
+    assert_eq!(hook2.call(10, 5), 15);
+
+    // Removing the top hook redirects the target to the next one
+    drop(hook3);
+    assert_eq!(add(10, 5), 50);
+    assert_eq!(hook2.call(10, 5), 15);
+
+    drop(hook2);
+    assert_eq!(add(10, 5), 15);
+    Ok(())
+  }
+
+  #[test]
+  fn registry_lists_live_detours() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn add(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    extern "C" fn sub(x: i32, y: i32) -> i32 {
+      x - y
+    }
+
+    let find = || {
+      live_detours()
+        .into_iter()
+        .filter(|info| info.target == add as *const ())
+        .collect::<Vec<_>>()
+    };
+
+    let line = line!() + 1;
+    let hook = unsafe { GenericDetour::<extern "C" fn(i32, i32) -> i32>::new(add, sub)? };
+    hook.set_label("add");
+    unsafe { hook.enable()? };
+
+    let detours = find();
+    assert_eq!(detours.len(), 1);
+
+    let info = &detours[0];
+    assert_eq!(info.detour, sub as *const ());
+    assert_eq!(info.trampoline.start, hook.trampoline() as *const () as usize);
+    assert!(info.patch_area.contains(&(add as *const () as usize)));
+    assert!(info.enabled);
+    assert_eq!(info.label.as_deref(), Some("add"));
+    assert_eq!(info.location.file(), file!());
+    assert_eq!(info.location.line(), line);
+    assert!(info.overlapping.is_empty());
+
+    drop(hook);
+    assert!(find().is_empty());
+    Ok(())
+  }
+
+  #[test]
+  fn analysis_matches_detour() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn add(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    extern "C" fn sub(x: i32, y: i32) -> i32 {
+      x - y
+    }
+
+    let before = unsafe { analyze(add as *const ()) };
+    assert!(before.is_hookable());
+    assert!(before.prolog_size >= 5);
+    assert!(before.strategy.is_some());
+    assert!(before
+      .instructions
+      .iter()
+      .all(|instruction| !instruction.text.is_empty()));
+
+    let hook = unsafe { GenericDetour::<extern "C" fn(i32, i32) -> i32>::new(add, sub)? };
+    unsafe { hook.enable()? };
+
+    // The patched target reports the analysis of the applied patch
+    for after in [hook.analysis(), unsafe { analyze(add as *const ()) }] {
+      assert!(after.is_hookable());
+      assert_eq!(after.prolog_size, before.prolog_size);
+      assert_eq!(after.strategy, before.strategy);
+      assert_eq!(after.patch_area, before.patch_area);
+      assert_eq!(
+        after
+          .instructions
+          .iter()
+          .map(|instruction| &instruction.bytes)
+          .collect::<Vec<_>>(),
+        before
+          .instructions
+          .iter()
+          .map(|instruction| &instruction.bytes)
+          .collect::<Vec<_>>()
+      );
+    }
+    Ok(())
+  }
+
   #[test]
   fn same_detour_and_target() {
     #[inline(never)]
@@ -30,8 +30,6 @@
 /// }
 /// # fn main() { }
 /// ```
-#[cfg(feature = "static-detour")]
-#[cfg_attr(docsrs, doc(cfg(feature = "static-detour")))]
 #[macro_export]
 // Inspired by: https://github.com/Jascha-N/minhook-rs
 macro_rules! static_detour {
@@ -129,8 +127,12 @@ macro_rules! static_detour {
         #[allow(unused_unsafe)]
         $($modifier) * fn __ffi_detour(
             $($argument_name: $argument_type),*) -> $return_type {
+          // Without a closure, the original function is called instead
           #[allow(unused_unsafe)]
-          ($name.__detour())($($argument_name),*)
+          match $name.__detour() {
+            Some(detour) => $crate::DetourFn::call_detour(&**detour, ($($argument_name,)*)),
+            None => unsafe { $name.call($($argument_name),*) },
+          }
         }
 
         $crate::StaticDetour::__new(__ffi_detour)
@@ -180,8 +182,13 @@ macro_rules! impl_hookable {
 
   (@impl_all ($($nm:ident : $ty:ident),*)) => {
     impl_hookable!(@impl_pair ($($nm : $ty),*) (                  fn($($ty),*) -> Ret));
+
+    // These ABIs are only accepted on x86, and on Windows (as "C")
+    #[cfg(any(target_arch = "x86", windows))]
     impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "cdecl"    fn($($ty),*) -> Ret));
+    #[cfg(any(target_arch = "x86", windows))]
     impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "stdcall"  fn($($ty),*) -> Ret));
+    #[cfg(any(target_arch = "x86", windows))]
     impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "fastcall" fn($($ty),*) -> Ret));
     impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "win64"    fn($($ty),*) -> Ret));
     impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "C"        fn($($ty),*) -> Ret));
@@ -190,10 +197,121 @@ macro_rules! impl_hookable {
     #[cfg(feature = "thiscall-abi")]
     #[cfg_attr(docsrs, doc(cfg(feature = "thiscall-abi")))]
     impl_hookable!(@impl_pair ($($nm : $ty),*) (extern "thiscall" fn($($ty),*) -> Ret));
+
+    impl_hookable!(@impl_detour_fn ($($nm : $ty),*));
+  };
+
+  (@impl_detour_fn ($($nm:ident : $ty:ident),*)) => {
+    impl<Closure, Ret, $($ty),*> $crate::DetourFn<($($ty,)*)> for Closure
+    where
+      Closure: Fn($($ty),*) -> Ret,
+    {
+      type Output = Ret;
+
+      #[inline]
+      fn call_detour(&self, ($($nm,)*): ($($ty,)*)) -> Ret {
+        self($($nm),*)
+      }
+    }
   };
 
   (@impl_pair ($($nm:ident : $ty:ident),*) ($($fn_t:tt)*)) => {
     impl_hookable!(@impl_fun ($($nm : $ty),*) ($($fn_t)*) (unsafe $($fn_t)*));
+    impl_hookable!(@impl_closure ($($nm : $ty),*) ($($fn_t)*));
+  };
+
+  (@impl_closure ($($nm:ident : $ty:ident),*) (extern "cdecl" fn $($sig:tt)*)) => {
+    // A shim cannot be defined with an ABI that is unsupported by the target
+    #[cfg(target_arch = "x86")]
+    impl_hookable!(@impl_closure_abi ($($nm : $ty),*) ("cdecl"));
+  };
+
+  (@impl_closure ($($nm:ident : $ty:ident),*) (extern "stdcall" fn $($sig:tt)*)) => {
+    #[cfg(target_arch = "x86")]
+    impl_hookable!(@impl_closure_abi ($($nm : $ty),*) ("stdcall"));
+  };
+
+  (@impl_closure ($($nm:ident : $ty:ident),*) (extern "fastcall" fn $($sig:tt)*)) => {
+    #[cfg(target_arch = "x86")]
+    impl_hookable!(@impl_closure_abi ($($nm : $ty),*) ("fastcall"));
+  };
+
+  (@impl_closure ($($nm:ident : $ty:ident),*) (extern "thiscall" fn $($sig:tt)*)) => {
+    #[cfg(target_arch = "x86")]
+    impl_hookable!(@impl_closure_abi ($($nm : $ty),*) ("thiscall"));
+  };
+
+  (@impl_closure ($($nm:ident : $ty:ident),*) (extern "win64" fn $($sig:tt)*)) => {
+    #[cfg(target_arch = "x86_64")]
+    impl_hookable!(@impl_closure_abi ($($nm : $ty),*) ("win64"));
+  };
+
+  (@impl_closure ($($nm:ident : $ty:ident),*) ($(extern $abi:literal)? fn $($sig:tt)*)) => {
+    impl_hookable!(@impl_closure_abi ($($nm : $ty),*) ($($abi)?));
+  };
+
+  (@impl_closure_abi ($($nm:ident : $ty:ident),*) ($($abi:literal)?)) => {
+    impl_hookable!(@impl_closure_for ($($nm : $ty),*) ($($abi)?) ($(extern $abi)? fn($($ty),*) -> Ret) ());
+    impl_hookable!(@impl_closure_for ($($nm : $ty),*) ($($abi)?) (unsafe $(extern $abi)? fn($($ty),*) -> Ret) (unsafe));
+  };
+
+  (@impl_closure_for ($($nm:ident : $ty:ident),*) ($($abi:literal)?) ($fn_type:ty) ($($unsafety:ident)?)) => {
+    impl<Ret: 'static, $($ty: 'static),*> $crate::ClosureDetour<$fn_type> {
+      /// Creates a new hook given a target function and a compatible closure.
+      ///
+      /// The closure receives the original function, followed by the
+      /// target's arguments.
+      ///
+      /// # Safety
+      ///
+      /// The target must remain valid whilst the detour exists, and the same
+      /// requirements as for [GenericDetour::new]($crate::GenericDetour::new)
+      /// apply.
+      #[track_caller]
+      pub unsafe fn new<Closure>(target: $fn_type, closure: Closure) -> $crate::Result<Self>
+      where
+        Closure: Fn($fn_type, $($ty),*) -> Ret + Send + Sync + 'static,
+      {
+        Self::with_options(target, closure, &$crate::DetourOptions::default())
+      }
+
+      /// Creates a new hook given a target function, a compatible closure and
+      /// custom options.
+      ///
+      /// # Safety
+      ///
+      /// The same requirements as for `new` apply.
+      #[track_caller]
+      pub unsafe fn with_options<Closure>(
+        target: $fn_type,
+        closure: Closure,
+        options: &$crate::DetourOptions,
+      ) -> $crate::Result<Self>
+      where
+        Closure: Fn($fn_type, $($ty),*) -> Ret + Send + Sync + 'static,
+      {
+        Self::__new(target, closure, Self::__shim::<Closure> as *const (), options)
+      }
+
+      #[doc(hidden)]
+      pub $($unsafety)? fn call(&self, $($nm : $ty),*) -> Ret {
+        #[allow(unused_unsafe)]
+        unsafe {
+          let original: $fn_type = $crate::Function::from_ptr(self.trampoline() as *const ());
+          original($($nm),*)
+        }
+      }
+
+      /// Invokes the closure of the thunk that entered it.
+      $(extern $abi)? fn __shim<Closure>($($nm : $ty),*) -> Ret
+      where
+        Closure: Fn($fn_type, $($ty),*) -> Ret + Send + Sync + 'static,
+      {
+        let context = unsafe { $crate::ClosureContext::<Closure>::current() };
+        let original = unsafe { $crate::Function::from_ptr(context.original) };
+        (context.closure)(original, $($nm),*)
+      }
+    }
   };
 
   (@impl_fun ($($nm:ident : $ty:ident),*) ($safe_type:ty) ($unsafe_type:ty)) => {
@@ -202,46 +320,103 @@ macro_rules! impl_hookable {
 
     impl_hookable!(@impl_unsafe ($($nm : $ty),*) ($unsafe_type) ($safe_type));
     impl_hookable!(@impl_safe ($($nm : $ty),*) ($safe_type));
+
+    impl_hookable!(@impl_static ($($nm : $ty),*) ($safe_type));
+    impl_hookable!(@impl_static ($($nm : $ty),*) ($unsafe_type));
+  };
+
+  (@impl_static ($($nm:ident : $ty:ident),*) ($fn_type:ty)) => {
+    impl<Ret: 'static, $($ty: 'static),*> $crate::StaticDetour<$fn_type> {
+      #[doc(hidden)]
+      #[track_caller]
+      pub unsafe fn initialize<Closure>(&self, target: $fn_type, closure: Closure) -> $crate::Result<&Self>
+      where
+        Closure: Fn($($ty),*) -> Ret + Send + 'static,
+      {
+        self.__initialize(target, Box::new(closure))
+      }
+
+      #[doc(hidden)]
+      pub fn set_detour<Closure>(&self, closure: Closure)
+      where
+        Closure: Fn($($ty),*) -> Ret + Send + 'static,
+      {
+        self.__set_detour(Box::new(closure))
+      }
+
+      #[doc(hidden)]
+      #[track_caller]
+      pub unsafe fn initialize_with_original<Closure>(
+        &'static self,
+        target: $fn_type,
+        closure: Closure,
+      ) -> $crate::Result<&'static Self>
+      where
+        Closure: Fn($crate::Original<$fn_type>, $($ty),*) -> Ret + Send + 'static,
+      {
+        let original = $crate::Original::__new(self);
+        self.__initialize(target, Box::new(move |$($nm : $ty),*| closure(original, $($nm),*)))
+      }
+
+      #[doc(hidden)]
+      pub fn set_detour_with_original<Closure>(&'static self, closure: Closure)
+      where
+        Closure: Fn($crate::Original<$fn_type>, $($ty),*) -> Ret + Send + 'static,
+      {
+        let original = $crate::Original::__new(self);
+        self.__set_detour(Box::new(move |$($nm : $ty),*| closure(original, $($nm),*)))
+      }
+    }
   };
 
   (@impl_unsafe ($($nm:ident : $ty:ident),*) ($target:ty) ($detour:ty)) => {
-    #[cfg(feature = "static-detour")]
     impl<Ret: 'static, $($ty: 'static),*> $crate::StaticDetour<$target> {
       #[doc(hidden)]
       pub unsafe fn call(&self, $($nm : $ty),*) -> Ret {
-        let original: $target = ::std::mem::transmute(self.trampoline().expect("calling detour trampoline"));
-        original($($nm),*)
+        // Only a detour that was never initialized has no original function,
+        // which is documented to panic
+        let original = self.__original().expect("static detour should be initialized");
+        (*original)($($nm),*)
+      }
+    }
+
+    impl<Ret: 'static, $($ty: 'static),*> $crate::Original<$target> {
+      #[doc(hidden)]
+      pub unsafe fn call(&self, $($nm : $ty),*) -> Ret {
+        self.detour().call($($nm),*)
       }
     }
 
     impl<Ret: 'static, $($ty: 'static),*> $crate::GenericDetour<$target> {
       #[doc(hidden)]
       pub unsafe fn call(&self, $($nm : $ty),*) -> Ret {
-        let original: $target = ::std::mem::transmute(self.trampoline());
-        original($($nm),*)
+        self.original()($($nm),*)
       }
     }
   };
 
   (@impl_safe ($($nm:ident : $ty:ident),*) ($fn_type:ty)) => {
-    #[cfg(feature = "static-detour")]
     impl<Ret: 'static, $($ty: 'static),*> $crate::StaticDetour<$fn_type> {
       #[doc(hidden)]
       pub fn call(&self, $($nm : $ty),*) -> Ret {
-        unsafe {
-          let original: $fn_type = ::std::mem::transmute(self.trampoline().expect("calling detour trampoline"));
-          original($($nm),*)
-        }
+        // Only a detour that was never initialized has no original function,
+        // which is documented to panic
+        let original = self.__original().expect("static detour should be initialized");
+        (*original)($($nm),*)
+      }
+    }
+
+    impl<Ret: 'static, $($ty: 'static),*> $crate::Original<$fn_type> {
+      #[doc(hidden)]
+      pub fn call(&self, $($nm : $ty),*) -> Ret {
+        self.detour().call($($nm),*)
       }
     }
 
     impl<Ret: 'static, $($ty: 'static),*> $crate::GenericDetour<$fn_type> {
       #[doc(hidden)]
       pub fn call(&self, $($nm : $ty),*) -> Ret {
-        unsafe {
-          let original: $fn_type = ::std::mem::transmute(self.trampoline());
-          original($($nm),*)
-        }
+        unsafe { self.original()($($nm),*) }
       }
     }
   };
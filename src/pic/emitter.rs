@@ -1,4 +1,5 @@
 use super::Thunkable;
+use crate::error::{Error, Result};
 
 /// An interface for generating PIC.
 pub struct CodeEmitter {
@@ -13,21 +14,26 @@ impl CodeEmitter {
   }
 
   /// Generates code for use at the specified address.
-  pub fn emit(&self, base: *const ()) -> Vec<u8> {
+  pub fn emit(&self, base: *const ()) -> Result<Vec<u8>> {
     let mut result = Vec::with_capacity(self.len());
     let mut base = base as usize;
 
     for thunk in &self.thunks {
       // Retrieve the code for the segment
-      let code = thunk.generate(base);
-      assert_eq!(code.len(), thunk.len());
+      let code = thunk.generate(base)?;
+      if code.len() != thunk.len() {
+        Err(Error::InvalidThunkSize {
+          expected: thunk.len(),
+          actual: code.len(),
+        })?;
+      }
 
       // Advance the current EIP address
       base += thunk.len();
       result.extend(code);
     }
 
-    result
+    Ok(result)
   }
 
   /// Adds a position-independant code segment.
@@ -40,3 +46,39 @@ impl CodeEmitter {
     self.thunks.iter().fold(0, |sum, thunk| sum + thunk.len())
   }
 }
+
+/// Allows code segments to be combined into a single thunk.
+impl Thunkable for CodeEmitter {
+  /// Generates the combined code at the specified address.
+  fn generate(&self, address: usize) -> Result<Vec<u8>> {
+    self.emit(address as *const ())
+  }
+
+  /// Returns the total size of all code segments.
+  fn len(&self) -> usize {
+    CodeEmitter::len(self)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::pic::UnsafeThunk;
+
+  #[test]
+  fn emit_rejects_invalid_thunk_size() {
+    let mut emitter = CodeEmitter::new();
+    emitter.add_thunk(Box::new(vec![0x90]));
+    emitter.add_thunk(Box::new(unsafe {
+      UnsafeThunk::new(|_| Ok(vec![0x90; 2]), 3)
+    }));
+
+    assert_eq!(
+      emitter.emit(std::ptr::null()),
+      Err(Error::InvalidThunkSize {
+        expected: 3,
+        actual: 2,
+      })
+    );
+  }
+}
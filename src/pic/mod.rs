@@ -1,5 +1,6 @@
 pub use self::emitter::CodeEmitter;
 pub use self::thunk::{FixedThunk, UnsafeThunk};
+use crate::error::Result;
 
 mod emitter;
 mod thunk;
@@ -7,7 +8,7 @@ mod thunk;
 /// An interface for generating PIC thunks.
 pub trait Thunkable {
   /// Generates the code at the specified address.
-  fn generate(&self, address: usize) -> Vec<u8>;
+  fn generate(&self, address: usize) -> Result<Vec<u8>>;
 
   /// Returns the size of a generated thunk.
   fn len(&self) -> usize;
@@ -16,8 +17,8 @@ pub trait Thunkable {
 /// Thunkable implementation for static data
 impl Thunkable for Vec<u8> {
   /// Generates a static thunk assumed to be PIC
-  fn generate(&self, _address: usize) -> Vec<u8> {
-    self.clone()
+  fn generate(&self, _address: usize) -> Result<Vec<u8>> {
+    Ok(self.clone())
   }
 
   /// Returns the size of a generated thunk
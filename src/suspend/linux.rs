@@ -0,0 +1,382 @@
+use crate::error::{Error, Result};
+use libc::{c_void, pid_t};
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicPtr, AtomicU8, AtomicUsize, Ordering};
+use std::sync::Once;
+use std::time::{Duration, Instant};
+use std::{mem, ptr, thread};
+
+/// The index of the instruction pointer in `mcontext_t::gregs`.
+#[cfg(target_arch = "x86_64")]
+const REG_IP: usize = 16;
+#[cfg(target_arch = "x86")]
+const REG_IP: usize = 14;
+
+/// The longest time to wait for all threads to be parked.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The thread has been signalled, but has not yet been parked.
+const SIGNALLED: u8 = 0;
+/// The thread is parked within the signal handler.
+const PARKED: u8 = 1;
+/// The thread has been released.
+const RELEASED: u8 = 2;
+/// The thread exited before it could be parked.
+const EXITED: u8 = 3;
+
+/// Whether other threads are suspended whilst patching.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The active suspension, if any.
+static ROUND: AtomicPtr<Round> = AtomicPtr::new(ptr::null_mut());
+
+/// The number of threads currently executing the signal handler.
+static ENTERED: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the signal handler was successfully installed.
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static INSTALL: Once = Once::new();
+
+/// Enables or disables suspension of all other threads whilst detours are
+/// enabled or disabled.
+///
+/// Each thread is parked using the real-time signal `SIGRTMIN + 7`, which
+/// must not be blocked by, nor be in use by, any other part of the process.
+/// If another handler is already installed for it, suspending fails with
+/// [Error::SuspendFailed](crate::Error::SuspendFailed).
+/// Any thread that is interrupted within a patched prolog is relocated to the
+/// equivalent instruction in the trampoline.
+///
+/// The same signal is always used when a detour is released, to determine
+/// whether its trampoline is still being executed.
+pub fn set_thread_suspension(enabled: bool) {
+  ENABLED.store(enabled, Ordering::SeqCst);
+}
+
+/// Returns whether other threads are suspended whilst patching.
+pub fn thread_suspension() -> bool {
+  ENABLED.load(Ordering::SeqCst)
+}
+
+/// A thread that has been signalled to park.
+struct Thread {
+  tid: AtomicI32,
+  state: AtomicU8,
+  /// The instruction pointer at which the thread was interrupted.
+  ip: AtomicUsize,
+}
+
+/// The state shared between the suspending thread and the parked threads.
+struct Round {
+  threads: Box<[Thread]>,
+  count: AtomicUsize,
+  released: AtomicBool,
+  relocations: UnsafeCell<*const [(usize, usize)]>,
+}
+
+impl Round {
+  /// Returns the threads that have been signalled so far.
+  fn threads(&self) -> &[Thread] {
+    &self.threads[..self.count.load(Ordering::SeqCst)]
+  }
+}
+
+/// A set of parked threads.
+pub struct Suspension(*mut Round);
+
+impl Suspension {
+  /// Returns the instruction pointers of all parked threads.
+  pub fn instruction_pointers(&self) -> impl Iterator<Item = usize> + '_ {
+    unsafe { &*self.0 }
+      .threads()
+      .iter()
+      .filter(|thread| thread.state.load(Ordering::SeqCst) == PARKED)
+      .map(|thread| thread.ip.load(Ordering::SeqCst))
+  }
+
+  /// Resumes all threads, applying any instruction pointer relocations.
+  pub fn resume(self, relocations: &[(usize, usize)]) {
+    // The relocations are read by the parked threads once released
+    unsafe { *(*self.0).relocations.get() = relocations };
+    mem::drop(self);
+  }
+}
+
+impl Drop for Suspension {
+  fn drop(&mut self) {
+    unsafe { (*self.0).released.store(true, Ordering::SeqCst) };
+
+    // Wait for all parked threads to leave the handler before releasing
+    // the round (and the relocations referenced by it).
+    ROUND.store(ptr::null_mut(), Ordering::SeqCst);
+    while ENTERED.load(Ordering::SeqCst) != 0 {
+      thread::yield_now();
+    }
+
+    mem::drop(unsafe { Box::from_raw(self.0) });
+  }
+}
+
+/// Parks all other threads.
+pub fn suspend() -> Result<Option<Suspension>> {
+  INSTALL.call_once(|| INSTALLED.store(unsafe { install_handler() }, Ordering::SeqCst));
+  if !INSTALLED.load(Ordering::SeqCst) {
+    Err(Error::SuspendFailed)?;
+  }
+
+  // Threads may be spawned whilst others are being parked, so leave room
+  let mut tasks = 0;
+  for_each_task(|_| tasks += 1)?;
+  let capacity = tasks * 2 + 64;
+
+  let round = Box::into_raw(Box::new(Round {
+    threads: (0..capacity)
+      .map(|_| Thread {
+        tid: AtomicI32::new(0),
+        state: AtomicU8::new(SIGNALLED),
+        ip: AtomicUsize::new(0),
+      })
+      .collect(),
+    count: AtomicUsize::new(0),
+    released: AtomicBool::new(false),
+    relocations: UnsafeCell::new(&[] as *const [(usize, usize)]),
+  }));
+
+  if ROUND
+    .compare_exchange(ptr::null_mut(), round, Ordering::SeqCst, Ordering::SeqCst)
+    .is_err()
+  {
+    mem::drop(unsafe { Box::from_raw(round) });
+    Err(Error::SuspendFailed)?;
+  }
+
+  // From here on, no memory may be allocated until all threads are resumed
+  let suspension = Suspension(round);
+  let round = unsafe { &*round };
+  let (pid, own_tid) = unsafe { (libc::getpid(), gettid()) };
+  let deadline = Instant::now() + TIMEOUT;
+
+  loop {
+    let mut signalled = false;
+    let mut overflow = false;
+
+    for_each_task(|tid| {
+      if tid == own_tid
+        || round
+          .threads()
+          .iter()
+          .any(|thread| thread.tid.load(Ordering::SeqCst) == tid)
+      {
+        return;
+      }
+
+      let index = round.count.load(Ordering::SeqCst);
+      if index == capacity {
+        overflow = true;
+        return;
+      }
+
+      let thread = &round.threads[index];
+      thread.tid.store(tid, Ordering::SeqCst);
+      round.count.store(index + 1, Ordering::SeqCst);
+      signalled = true;
+
+      if !unsafe { tgkill(pid, tid, signal()) } {
+        thread.state.store(EXITED, Ordering::SeqCst);
+      }
+    })?;
+
+    if overflow {
+      Err(Error::SuspendFailed)?;
+    }
+
+    // Once no new threads are found, all threads are parked
+    if !signalled {
+      break;
+    }
+
+    wait_until_parked(round, pid, deadline)?;
+  }
+
+  Ok(Some(suspension))
+}
+
+/// Waits until all signalled threads have either been parked or exited.
+fn wait_until_parked(round: &Round, pid: pid_t, deadline: Instant) -> Result<()> {
+  loop {
+    let mut pending = false;
+
+    for thread in round.threads() {
+      if thread.state.load(Ordering::SeqCst) != SIGNALLED {
+        continue;
+      }
+
+      // A thread may exit before it handles the signal
+      let tid = thread.tid.load(Ordering::SeqCst);
+      if !unsafe { tgkill(pid, tid, 0) } {
+        let _ =
+          thread
+            .state
+            .compare_exchange(SIGNALLED, EXITED, Ordering::SeqCst, Ordering::SeqCst);
+      } else {
+        pending = true;
+      }
+    }
+
+    if !pending {
+      return Ok(());
+    } else if Instant::now() > deadline {
+      return Err(Error::SuspendFailed);
+    }
+
+    thread::yield_now();
+  }
+}
+
+/// Parks the current thread until the round is released.
+extern "C" fn handler(_signal: i32, _info: *mut libc::siginfo_t, context: *mut c_void) {
+  ENTERED.fetch_add(1, Ordering::SeqCst);
+  let errno = unsafe { *libc::__errno_location() };
+
+  if let Some(round) = unsafe { ROUND.load(Ordering::SeqCst).as_ref() } {
+    let tid = unsafe { gettid() };
+    let thread = round
+      .threads()
+      .iter()
+      .find(|thread| thread.tid.load(Ordering::SeqCst) == tid);
+
+    if let Some(thread) = thread {
+      thread
+        .ip
+        .store(unsafe { instruction_pointer(context) }, Ordering::SeqCst);
+
+      if thread
+        .state
+        .compare_exchange(SIGNALLED, PARKED, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+      {
+        while !round.released.load(Ordering::SeqCst) {
+          unsafe { libc::sched_yield() };
+        }
+
+        unsafe { relocate(context, &**round.relocations.get()) };
+        thread.state.store(RELEASED, Ordering::SeqCst);
+      }
+    }
+  }
+
+  unsafe { *libc::__errno_location() = errno };
+  ENTERED.fetch_sub(1, Ordering::SeqCst);
+}
+
+/// Returns the interrupted instruction pointer.
+unsafe fn instruction_pointer(context: *mut c_void) -> usize {
+  (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs[REG_IP] as usize
+}
+
+/// Moves the interrupted instruction pointer if it matches a relocation.
+unsafe fn relocate(context: *mut c_void, relocations: &[(usize, usize)]) {
+  let context = &mut *(context as *mut libc::ucontext_t);
+  let ip = &mut context.uc_mcontext.gregs[REG_IP];
+
+  if let Some(&(_, to)) = relocations.iter().find(|(from, _)| *from == *ip as usize) {
+    *ip = to as libc::greg_t;
+  }
+}
+
+/// Installs the signal handler used for parking threads, unless another
+/// handler is already installed for the signal (which is then left in place).
+unsafe fn install_handler() -> bool {
+  let mut action: libc::sigaction = mem::zeroed();
+  action.sa_sigaction = handler as extern "C" fn(i32, *mut libc::siginfo_t, *mut c_void) as usize;
+  action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+  libc::sigemptyset(&mut action.sa_mask);
+
+  let mut previous: libc::sigaction = mem::zeroed();
+  if libc::sigaction(signal(), &action, &mut previous) != 0 {
+    return false;
+  }
+
+  if previous.sa_sigaction != libc::SIG_DFL && previous.sa_sigaction != libc::SIG_IGN {
+    libc::sigaction(signal(), &previous, ptr::null_mut());
+    return false;
+  }
+  true
+}
+
+/// Returns the signal used for parking threads.
+fn signal() -> i32 {
+  libc::SIGRTMIN() + 7
+}
+
+/// Invokes `callback` with the ID of each thread in the process.
+///
+/// The directory is read using a fixed buffer, since this is also used whilst
+/// other threads are parked.
+fn for_each_task<F: FnMut(pid_t)>(mut callback: F) -> Result<()> {
+  let path = b"/proc/self/task\0";
+  let fd = unsafe {
+    libc::open(
+      path.as_ptr() as *const _,
+      libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+    )
+  };
+
+  if fd < 0 {
+    Err(Error::SuspendFailed)?;
+  }
+
+  let mut buffer = [0u64; 512];
+  let result = loop {
+    let read = unsafe {
+      libc::syscall(
+        libc::SYS_getdents64,
+        fd,
+        buffer.as_mut_ptr(),
+        mem::size_of_val(&buffer),
+      )
+    };
+
+    if read <= 0 {
+      break if read == 0 {
+        Ok(())
+      } else {
+        Err(Error::SuspendFailed)
+      };
+    }
+
+    // Each entry is a `linux_dirent64`: d_ino, d_off, d_reclen, d_type, d_name
+    let bytes = buffer.as_ptr() as *const u8;
+    let mut offset = 0;
+    while offset < read as usize {
+      let entry = unsafe { bytes.add(offset) };
+      let length = unsafe { ptr::read_unaligned(entry.add(16) as *const u16) };
+      let mut name = unsafe { entry.add(19) };
+      let mut tid: pid_t = 0;
+
+      // Entries other than thread IDs (i.e `.` and `..`) are ignored
+      while let Some(digit) = unsafe { (*name as char).to_digit(10) } {
+        tid = tid * 10 + digit as pid_t;
+        name = unsafe { name.add(1) };
+      }
+
+      if tid != 0 && unsafe { *name } == 0 {
+        callback(tid);
+      }
+      offset += length as usize;
+    }
+  };
+
+  unsafe { libc::close(fd) };
+  result
+}
+
+/// Returns the ID of the calling thread.
+unsafe fn gettid() -> pid_t {
+  libc::syscall(libc::SYS_gettid) as pid_t
+}
+
+/// Sends a signal to a thread within the process.
+unsafe fn tgkill(pid: pid_t, tid: pid_t, signal: i32) -> bool {
+  libc::syscall(libc::SYS_tgkill, pid, tid, signal) == 0
+}
@@ -0,0 +1,63 @@
+//! Suspension of other threads whilst code is being patched.
+//!
+//! When enabled, every other thread of the process is parked for the duration
+//! of a patch. Threads that were interrupted within a modified prolog are
+//! moved to the equivalent instruction in the trampoline (and back again when
+//! a detour is disabled), so no thread can execute a partially written
+//! instruction.
+
+use crate::error::Result;
+use cfg_if::cfg_if;
+use std::ops::Range;
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        mod linux;
+        pub use self::linux::{set_thread_suspension, thread_suspension};
+        use self::linux as os;
+    } else {
+        mod unsupported;
+        use self::unsupported as os;
+
+        /// Thread suspension is never enabled on this platform.
+        fn thread_suspension() -> bool {
+            false
+        }
+    }
+}
+
+/// A set of parked threads.
+///
+/// The threads are released when the suspension is resumed or dropped.
+pub struct Suspension(os::Suspension);
+
+impl Suspension {
+  /// Returns true if any parked thread was interrupted within `range`.
+  pub fn is_executing(&self, range: &Range<usize>) -> bool {
+    self.0.instruction_pointers().any(|ip| range.contains(&ip))
+  }
+
+  /// Resumes all threads, relocating the instruction pointer of any thread
+  /// matching the source address of a `(from, to)` pair.
+  ///
+  /// No memory may be allocated whilst threads are suspended (a thread may be
+  /// parked whilst holding the allocator's lock), hence the relocations must
+  /// be prepared before suspending.
+  pub fn resume(self, relocations: &[(usize, usize)]) {
+    self.0.resume(relocations)
+  }
+}
+
+/// Parks all other threads if thread suspension is enabled.
+pub fn suspend() -> Result<Option<Suspension>> {
+  if thread_suspension() {
+    suspend_all()
+  } else {
+    Ok(None)
+  }
+}
+
+/// Parks all other threads, if supported by the platform.
+pub fn suspend_all() -> Result<Option<Suspension>> {
+  os::suspend().map(|suspension| suspension.map(Suspension))
+}
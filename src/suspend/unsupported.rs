@@ -0,0 +1,19 @@
+use crate::error::Result;
+
+/// Thread suspension is not available on this platform.
+pub enum Suspension {}
+
+impl Suspension {
+  pub fn instruction_pointers(&self) -> std::iter::Empty<usize> {
+    match *self {}
+  }
+
+  pub fn resume(self, _relocations: &[(usize, usize)]) {
+    match self {}
+  }
+}
+
+/// Threads cannot be suspended on this platform.
+pub fn suspend() -> Result<Option<Suspension>> {
+  Ok(None)
+}
@@ -5,6 +5,11 @@
 
 /// Trait representing a function that can be used as a target or detour for
 /// detouring.
+///
+/// # Safety
+///
+/// It must only be implemented for function pointers, of which the
+/// arguments and return type are described by `Arguments` and `Output`.
 pub unsafe trait Function: Sized + Copy + Sync + 'static {
   /// The argument types as a tuple.
   type Arguments;
@@ -13,6 +18,10 @@ pub unsafe trait Function: Sized + Copy + Sync + 'static {
   type Output;
 
   /// Constructs a `Function` from an untyped pointer.
+  ///
+  /// # Safety
+  ///
+  /// The pointer must refer to a function of this type.
   unsafe fn from_ptr(ptr: *const ()) -> Self;
 
   /// Returns an untyped pointer for this function.
@@ -20,10 +29,29 @@ pub unsafe trait Function: Sized + Copy + Sync + 'static {
 }
 
 /// Trait indicating that `Self` can be detoured by the given function `D`.
+///
+/// # Safety
+///
+/// It must only be implemented if `D` accepts the arguments of `Self` and
+/// returns its output, using the same calling convention.
 pub unsafe trait HookableWith<D: Function>: Function {}
 
 unsafe impl<T: Function> HookableWith<T> for T {}
 
+/// Trait representing a closure that can be used as a detour for functions
+/// accepting `Args`.
+///
+/// It is implemented for all closures (`Fn(A, B, ...) -> R`) at each
+/// supported arity, allowing closures to be invoked with a tuple of
+/// arguments on stable Rust.
+pub trait DetourFn<Args> {
+  /// The return type.
+  type Output;
+
+  /// Invokes the closure with a tuple of arguments.
+  fn call_detour(&self, arguments: Args) -> Self::Output;
+}
+
 #[cfg(not(feature = "28-args"))]
 impl_hookable! {
   __arg_0:  A, __arg_1:  B, __arg_2:  C, __arg_3:  D, __arg_4:  E, __arg_5:  F, __arg_6:  G,
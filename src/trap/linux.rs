@@ -0,0 +1,744 @@
+use crate::alloc;
+use crate::error::{Error, Result};
+use iced_x86::{Decoder, DecoderError, DecoderOptions, FlowControl, Mnemonic};
+use libc::{c_void, greg_t};
+use once_cell::sync::Lazy;
+use std::cell::UnsafeCell;
+use std::convert::TryFrom;
+use std::mem::{self, MaybeUninit};
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Mutex, Once};
+use std::{hint, ptr};
+
+/// The index of the instruction pointer in `mcontext_t::gregs`.
+#[cfg(target_arch = "x86_64")]
+const REG_IP: usize = 16;
+#[cfg(target_arch = "x86")]
+const REG_IP: usize = 14;
+
+/// The index of the stack pointer in `mcontext_t::gregs`.
+#[cfg(target_arch = "x86_64")]
+const REG_SP: usize = 15;
+#[cfg(target_arch = "x86")]
+const REG_SP: usize = 7;
+
+/// The index of the flags register in `mcontext_t::gregs`.
+#[cfg(target_arch = "x86_64")]
+const REG_FLAGS: usize = 17;
+#[cfg(target_arch = "x86")]
+const REG_FLAGS: usize = 16;
+
+/// The trap flag, which raises `SIGTRAP` after the next instruction.
+const TRAP_FLAG: greg_t = 0x100;
+
+/// The maximum number of breakpoints, and of guarded targets.
+const CAPACITY: usize = 256;
+
+/// The maximum number of threads stepping through guarded pages at once.
+const STEP_CAPACITY: usize = 256;
+
+/// The longest instruction, which may extend into a guarded page.
+const MAX_INSTRUCTION_SIZE: usize = 15;
+
+/// The space for a copy of an instruction, with one slot per step entry.
+const SLOT_SIZE: usize = 16;
+
+/// The instruction filling the remainder of a slot.
+const NOP: u8 = 0x90;
+
+/// The maximum distance of slots from their page, within reach of a
+/// RIP-relative operand.
+const SLOT_RANGE: usize = 0x8000_0000;
+
+/// The bitness of instructions decoded for a step.
+#[cfg(target_arch = "x86_64")]
+const BITNESS: u32 = 64;
+#[cfg(target_arch = "x86")]
+const BITNESS: u32 = 32;
+
+/// A breakpoint, and where execution continues once it is hit.
+struct Breakpoint {
+  /// The address of the `int3` instruction, or zero if the entry is unused.
+  address: AtomicUsize,
+  destination: AtomicUsize,
+}
+
+/// A target whose page is made non-executable whilst it is detoured.
+struct Guard {
+  /// The address of the target, or zero if the entry is unused.
+  address: AtomicUsize,
+  /// Where execution continues, or zero if the target is not detoured.
+  destination: AtomicUsize,
+  /// The original protection (`PROT_*`) of the target's page.
+  protection: AtomicI32,
+}
+
+/// The slots for copies of the instructions of a guarded page.
+struct Area {
+  /// The guarded page, or zero if the entry is unused.
+  page: AtomicUsize,
+  /// The address of the first slot, within range of the page.
+  base: AtomicUsize,
+}
+
+/// A thread executing a single instruction of a guarded page.
+struct Step {
+  /// The ID of the thread, or zero if the entry is unused.
+  tid: AtomicI32,
+  /// The number of steps of the thread already in progress (i.e when a
+  /// signal handler executes a guarded page during a step).
+  depth: AtomicUsize,
+  /// The address of the instruction.
+  origin: AtomicUsize,
+  /// The address of its copy.
+  copy: AtomicUsize,
+  /// The length of the instruction.
+  len: AtomicUsize,
+  /// Whether the instruction pointer is moved back from the copy once
+  /// stepped (i.e unless the instruction branched to an absolute address).
+  is_relative: AtomicBool,
+  /// Whether the instruction pushes its return address.
+  is_call: AtomicBool,
+  /// Whether the instruction enters the kernel, which restores the trap flag
+  /// an instruction late.
+  is_system: AtomicBool,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_BREAKPOINT: Breakpoint = Breakpoint {
+  address: AtomicUsize::new(0),
+  destination: AtomicUsize::new(0),
+};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_GUARD: Guard = Guard {
+  address: AtomicUsize::new(0),
+  destination: AtomicUsize::new(0),
+  protection: AtomicI32::new(0),
+};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_AREA: Area = Area {
+  page: AtomicUsize::new(0),
+  base: AtomicUsize::new(0),
+};
+
+#[allow(clippy::declare_interior_mutable_const)]
+const UNUSED_STEP: Step = Step {
+  tid: AtomicI32::new(0),
+  depth: AtomicUsize::new(0),
+  origin: AtomicUsize::new(0),
+  copy: AtomicUsize::new(0),
+  len: AtomicUsize::new(0),
+  is_relative: AtomicBool::new(false),
+  is_call: AtomicBool::new(false),
+  is_system: AtomicBool::new(false),
+};
+
+/// All breakpoints, which the signal handler reads without locking.
+static BREAKPOINTS: [Breakpoint; CAPACITY] = [UNUSED_BREAKPOINT; CAPACITY];
+
+/// All guarded targets, which the signal handler reads without locking.
+static GUARDS: [Guard; CAPACITY] = [UNUSED_GUARD; CAPACITY];
+
+/// The slots of all pages that have been guarded. These are never released,
+/// since a page may be guarded again.
+static AREAS: [Area; CAPACITY] = [UNUSED_AREA; CAPACITY];
+
+/// All threads stepping through a guarded page.
+static STEPS: [Step; STEP_CAPACITY] = [UNUSED_STEP; STEP_CAPACITY];
+
+/// The allocator for slots, apart from the pool (which is locked whilst
+/// targets are guarded).
+static SLOTS: Lazy<Mutex<alloc::ThreadAllocator>> =
+  Lazy::new(|| Mutex::new(alloc::ThreadAllocator::new(SLOT_RANGE)));
+
+/// The size of a page, assigned before any target is guarded.
+static PAGE_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// A signal handler installed before ours.
+struct Previous(UnsafeCell<MaybeUninit<libc::sigaction>>);
+
+unsafe impl Sync for Previous {}
+
+static PREVIOUS_TRAP: Previous = Previous(UnsafeCell::new(MaybeUninit::uninit()));
+static PREVIOUS_SEGV: Previous = Previous(UnsafeCell::new(MaybeUninit::uninit()));
+
+/// Whether the signal handlers were successfully installed.
+static INSTALLED_TRAP: AtomicBool = AtomicBool::new(false);
+static INSTALLED_SEGV: AtomicBool = AtomicBool::new(false);
+static INSTALL_TRAP: Once = Once::new();
+static INSTALL_SEGV: Once = Once::new();
+
+/// Registers a breakpoint, which initially resumes execution at its own
+/// address (i.e once the original code has been restored).
+///
+/// Breakpoints and guards must only be registered and released by one thread
+/// at a time.
+pub fn reserve(address: usize) -> Result<()> {
+  install_trap()?;
+  if find(address).is_some() {
+    return Ok(());
+  }
+
+  let breakpoint = BREAKPOINTS
+    .iter()
+    .find(|breakpoint| breakpoint.address.load(Ordering::SeqCst) == 0)
+    .ok_or(Error::TrapFailed)?;
+  breakpoint.destination.store(address, Ordering::SeqCst);
+  breakpoint.address.store(address, Ordering::SeqCst);
+  Ok(())
+}
+
+/// Sets where execution continues once a breakpoint is hit.
+pub fn redirect(address: usize, destination: usize) {
+  if let Some(breakpoint) = find(address) {
+    breakpoint.destination.store(destination, Ordering::SeqCst);
+  }
+}
+
+/// Unregisters a breakpoint.
+pub fn release(address: usize) {
+  if let Some(breakpoint) = find(address) {
+    breakpoint.address.store(0, Ordering::SeqCst);
+  }
+}
+
+/// Registers a guarded target, which is not detoured until redirected.
+pub fn reserve_guard(address: usize) -> Result<()> {
+  install_trap()?;
+  INSTALL_SEGV.call_once(|| {
+    let installed = unsafe { install_handler(libc::SIGSEGV, segv_handler, &PREVIOUS_SEGV) };
+    INSTALLED_SEGV.store(installed, Ordering::SeqCst);
+  });
+  if !INSTALLED_SEGV.load(Ordering::SeqCst) {
+    Err(Error::TrapFailed)?;
+  }
+
+  PAGE_SIZE.store(region::page::size(), Ordering::SeqCst);
+  if find_guard(address).is_some() {
+    return Ok(());
+  }
+
+  // The handlers cannot step through their own code, nor the C library
+  let page = page_of(address);
+  if is_handler_code(page) {
+    Err(Error::TrapFailed)?;
+  }
+
+  // A page with a detoured target is no longer executable, so the protection
+  // of any other guard within it is shared
+  let flags = match guards_of(page).next() {
+    Some(guard) => guard.protection.load(Ordering::SeqCst),
+    None => {
+      let protection = region::query(page as *const u8)?.protection();
+      [
+        (region::Protection::READ, libc::PROT_READ),
+        (region::Protection::WRITE, libc::PROT_WRITE),
+        (region::Protection::EXECUTE, libc::PROT_EXEC),
+      ]
+      .iter()
+      .filter(|(protection_flag, _)| protection.contains(*protection_flag))
+      .fold(libc::PROT_NONE, |flags, (_, flag)| flags | flag)
+    },
+  };
+
+  // Instructions are read to be copied into a slot
+  if flags & libc::PROT_READ == 0 {
+    Err(Error::TrapFailed)?;
+  }
+  reserve_area(page)?;
+
+  let guard = GUARDS
+    .iter()
+    .find(|guard| guard.address.load(Ordering::SeqCst) == 0)
+    .ok_or(Error::TrapFailed)?;
+  guard.destination.store(0, Ordering::SeqCst);
+  guard.protection.store(flags, Ordering::SeqCst);
+  guard.address.store(address, Ordering::SeqCst);
+  Ok(())
+}
+
+/// Sets where execution continues once a guarded target is executed, and
+/// removes (or restores) execute permission from its page accordingly.
+pub fn redirect_guard(address: usize, destination: Option<usize>) {
+  if let Some(guard) = find_guard(address) {
+    guard
+      .destination
+      .store(destination.unwrap_or(0), Ordering::SeqCst);
+    update_protection(page_of(address));
+  }
+}
+
+/// Unregisters a guarded target, restoring its page's protection.
+pub fn release_guard(address: usize) {
+  if let Some(guard) = find_guard(address) {
+    redirect_guard(address, None);
+    guard.address.store(0, Ordering::SeqCst);
+  }
+}
+
+/// Returns whether the page of an address is guarded.
+pub fn is_guarded(address: usize) -> bool {
+  PAGE_SIZE.load(Ordering::SeqCst) != 0 && is_page_guarded(page_of(address))
+}
+
+/// Allocates the slots of a page, unless it has been guarded before.
+fn reserve_area(page: usize) -> Result<()> {
+  if area_of(page).is_some() {
+    return Ok(());
+  }
+
+  let area = AREAS
+    .iter()
+    .find(|area| area.page.load(Ordering::SeqCst) == 0)
+    .ok_or(Error::TrapFailed)?;
+  let mut slots = crate::util::lock(&SLOTS);
+  let memory = slots.allocate(page as *const (), STEP_CAPACITY * SLOT_SIZE)?;
+  area.base.store(memory.as_ptr() as usize, Ordering::SeqCst);
+  area.page.store(page, Ordering::SeqCst);
+  slots.leak(memory);
+
+  // Symbols called by the handlers are bound before a page is guarded
+  unsafe { gettid() };
+  Ok(())
+}
+
+/// Returns the address of the first slot of a page.
+fn area_of(page: usize) -> Option<usize> {
+  AREAS
+    .iter()
+    .find(|area| area.page.load(Ordering::SeqCst) == page)
+    .map(|area| area.base.load(Ordering::SeqCst))
+}
+
+/// Returns whether a page overlaps the executable segments of this library,
+/// or of the C library, which the handlers execute.
+fn is_handler_code(page: usize) -> bool {
+  struct Search {
+    /// An address within each object executed by the handlers.
+    objects: [usize; 2],
+    pages: Range<usize>,
+    overlaps: bool,
+  }
+
+  unsafe extern "C" fn visit(
+    info: *mut libc::dl_phdr_info,
+    _size: usize,
+    data: *mut c_void,
+  ) -> i32 {
+    let search = &mut *(data as *mut Search);
+    let info = &*info;
+    let segments = (0..info.dlpi_phnum as usize)
+      .map(|index| &*info.dlpi_phdr.add(index))
+      .filter(|header| header.p_type == libc::PT_LOAD && header.p_flags & libc::PF_X != 0)
+      .map(|header| {
+        let start = info.dlpi_addr as usize + header.p_vaddr as usize;
+        start..start + header.p_memsz as usize
+      })
+      .collect::<Vec<_>>();
+
+    let is_handler_object = segments
+      .iter()
+      .any(|segment| search.objects.iter().any(|object| segment.contains(object)));
+    if is_handler_object {
+      search.overlaps |= segments
+        .iter()
+        .any(|segment| segment.start < search.pages.end && search.pages.start < segment.end);
+    }
+    0
+  }
+
+  let mut search = Search {
+    objects: [
+      segv_handler as *const () as usize,
+      libc::mprotect as *const () as usize,
+    ],
+    pages: page..page + PAGE_SIZE.load(Ordering::SeqCst),
+    overlaps: false,
+  };
+  unsafe { libc::dl_iterate_phdr(Some(visit), &mut search as *mut Search as *mut c_void) };
+  search.overlaps
+}
+
+/// Returns the registered breakpoint at an address.
+fn find(address: usize) -> Option<&'static Breakpoint> {
+  BREAKPOINTS
+    .iter()
+    .find(|breakpoint| address != 0 && breakpoint.address.load(Ordering::SeqCst) == address)
+}
+
+/// Returns the registered guard of a target.
+fn find_guard(address: usize) -> Option<&'static Guard> {
+  GUARDS
+    .iter()
+    .find(|guard| address != 0 && guard.address.load(Ordering::SeqCst) == address)
+}
+
+/// Returns the registered guards within a page.
+fn guards_of(page: usize) -> impl Iterator<Item = &'static Guard> {
+  GUARDS.iter().filter(move |guard| {
+    let address = guard.address.load(Ordering::SeqCst);
+    address != 0 && page_of(address) == page
+  })
+}
+
+/// Returns whether any target within a page is detoured.
+fn is_page_guarded(page: usize) -> bool {
+  guards_of(page).any(|guard| guard.destination.load(Ordering::SeqCst) != 0)
+}
+
+/// Returns the start of the page containing an address.
+fn page_of(address: usize) -> usize {
+  address & !(PAGE_SIZE.load(Ordering::SeqCst) - 1)
+}
+
+/// Makes a page non-executable if any of its targets are detoured, otherwise
+/// restores its protection.
+fn update_protection(page: usize) {
+  let protection = match guards_of(page).next() {
+    Some(guard) => guard.protection.load(Ordering::SeqCst),
+    None => return,
+  };
+
+  let protection = if is_page_guarded(page) {
+    protection & !libc::PROT_EXEC
+  } else {
+    protection
+  };
+
+  let page_size = PAGE_SIZE.load(Ordering::SeqCst);
+  unsafe { libc::mprotect(page as *mut c_void, page_size, protection) };
+}
+
+/// Redirects a thread executing a guarded page.
+extern "C" fn segv_handler(signal: i32, info: *mut libc::siginfo_t, context: *mut c_void) {
+  unsafe { abort_step(context) };
+  let fault = unsafe { (*info).si_addr() } as usize;
+  let ip = unsafe { &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs[REG_IP] };
+
+  // Only the execution of a guarded page is handled
+  let page = page_of(fault);
+  let is_execution = fault.wrapping_sub(*ip as usize) < MAX_INSTRUCTION_SIZE;
+  if PAGE_SIZE.load(Ordering::SeqCst) == 0 || !is_execution || guards_of(page).next().is_none() {
+    return unsafe { forward(&PREVIOUS_SEGV, signal, info, context) };
+  }
+
+  let destination = find_guard(*ip as usize)
+    .map(|guard| guard.destination.load(Ordering::SeqCst))
+    .unwrap_or(0);
+
+  // A breakpoint within the page is hit without executing it
+  let destination = match find(*ip as usize) {
+    Some(breakpoint) if destination == 0 => breakpoint.destination.load(Ordering::SeqCst),
+    _ => destination,
+  };
+
+  if destination != 0 {
+    *ip = destination as greg_t;
+  } else if is_page_guarded(page) {
+    // Any other instruction is executed from a copy
+    if !unsafe { begin_step(page, context) } {
+      unsafe { forward(&PREVIOUS_SEGV, signal, info, context) };
+    }
+  }
+  // Otherwise the page has been restored since the fault, so it is retried
+}
+
+/// Moves a thread that hit a breakpoint to its destination, or completes a
+/// step through a guarded page.
+extern "C" fn trap_handler(signal: i32, info: *mut libc::siginfo_t, context: *mut c_void) {
+  // A step raises a trace trap, whereas an `int3` instruction is reported by
+  // the kernel, so a step ending right after a breakpoint is not mistaken for
+  // hitting it
+  let is_trace = unsafe { (*info).si_code } == libc::TRAP_TRACE;
+  if is_trace {
+    if !unsafe { end_step(context) } {
+      unsafe { forward(&PREVIOUS_TRAP, signal, info, context) };
+    }
+    return;
+  }
+
+  unsafe { abort_step(context) };
+  let ip = unsafe { &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs[REG_IP] };
+
+  // The instruction pointer follows the `int3` instruction
+  if let Some(breakpoint) = find((*ip as usize).wrapping_sub(1)) {
+    *ip = breakpoint.destination.load(Ordering::SeqCst) as greg_t;
+  } else {
+    unsafe { forward(&PREVIOUS_TRAP, signal, info, context) };
+  }
+}
+
+/// Executes a single instruction of a guarded page.
+///
+/// The instruction is copied to a slot of the thread, so the page remains
+/// non-executable for all other threads. Only an instruction with a
+/// RIP-relative operand out of range of the slot cannot be copied, in which
+/// case the fault is reported as is.
+unsafe fn begin_step(page: usize, context: *mut c_void) -> bool {
+  let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+  let origin = gregs[REG_IP] as usize;
+  let base = match area_of(page) {
+    Some(base) => base,
+    None => return false,
+  };
+
+  let tid = gettid();
+  let depth = STEPS
+    .iter()
+    .filter(|step| step.tid.load(Ordering::SeqCst) == tid)
+    .count();
+
+  // Each step only lasts a single instruction, so an entry is soon available
+  let (index, step) = loop {
+    let free = STEPS.iter().enumerate().find(|(_, step)| {
+      step
+        .tid
+        .compare_exchange(0, tid, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok()
+    });
+    match free {
+      Some(free) => break free,
+      None => hint::spin_loop(),
+    }
+  };
+
+  let slot = base + index * SLOT_SIZE;
+  let copied = match copy_instruction(page, origin, slot) {
+    Some(copied) => copied,
+    None => {
+      step.tid.store(0, Ordering::SeqCst);
+      return false;
+    },
+  };
+
+  step.depth.store(depth, Ordering::SeqCst);
+  step.origin.store(origin, Ordering::SeqCst);
+  step.copy.store(slot, Ordering::SeqCst);
+  step.len.store(copied.len, Ordering::SeqCst);
+  step.is_relative.store(copied.is_relative, Ordering::SeqCst);
+  step.is_call.store(copied.is_call, Ordering::SeqCst);
+  step.is_system.store(copied.is_system, Ordering::SeqCst);
+  gregs[REG_IP] = slot as greg_t;
+  gregs[REG_FLAGS] |= TRAP_FLAG;
+  true
+}
+
+/// An instruction copied to a slot.
+struct Copied {
+  len: usize,
+  is_relative: bool,
+  is_call: bool,
+  is_system: bool,
+}
+
+/// Copies an instruction to a slot, adjusting any RIP-relative operand.
+unsafe fn copy_instruction(page: usize, origin: usize, slot: usize) -> Option<Copied> {
+  // Only the remainder of the page is read, unless the instruction extends
+  // into the next one
+  let mut code = [0u8; MAX_INSTRUCTION_SIZE];
+  let available = (page + PAGE_SIZE.load(Ordering::SeqCst) - origin).min(MAX_INSTRUCTION_SIZE);
+  ptr::copy_nonoverlapping(origin as *const u8, code.as_mut_ptr(), available);
+
+  let decode = |code: &[u8]| {
+    let mut decoder = Decoder::with_ip(BITNESS, code, origin as u64, DecoderOptions::NONE);
+    let instruction = decoder.decode();
+    let offsets = decoder.get_constant_offsets(&instruction);
+    (instruction, offsets, decoder.last_error())
+  };
+
+  let (instruction, offsets) = match decode(&code[..available]) {
+    (_, _, DecoderError::NoMoreBytes) if available < MAX_INSTRUCTION_SIZE => {
+      ptr::copy_nonoverlapping(
+        (origin + available) as *const u8,
+        code[available..].as_mut_ptr(),
+        MAX_INSTRUCTION_SIZE - available,
+      );
+      let (instruction, offsets, _) = decode(&code);
+      (instruction, offsets)
+    },
+    (instruction, offsets, _) => (instruction, offsets),
+  };
+
+  // An instruction raising an exception (e.g an interrupt, or an invalid
+  // one) does so from its copy, and is moved back by `abort_step`
+  let len = instruction.len();
+  let flow = instruction.flow_control();
+  if instruction.is_ip_rel_memory_operand() {
+    if !offsets.has_displacement() || offsets.displacement_size() != 4 {
+      return None;
+    }
+
+    let offset = offsets.displacement_offset();
+    let mut displacement = [0u8; 4];
+    displacement.copy_from_slice(&code[offset..offset + 4]);
+    let displacement = i32::from_le_bytes(displacement) as i64 + (origin as i64 - slot as i64);
+    let displacement = i32::try_from(displacement).ok()?;
+    code[offset..offset + 4].copy_from_slice(&displacement.to_le_bytes());
+  }
+
+  let is_far = instruction.is_call_far() || instruction.is_call_far_indirect();
+  // A system call returns with the trap flag restored, so its step ends an
+  // instruction later, within the `nop`s following the copy
+  ptr::copy_nonoverlapping(code.as_ptr(), slot as *mut u8, len);
+  ptr::write_bytes((slot + len) as *mut u8, NOP, SLOT_SIZE - len);
+  Some(Copied {
+    len,
+    is_relative: !is_far
+      && !matches!(
+        flow,
+        FlowControl::Return | FlowControl::IndirectBranch | FlowControl::IndirectCall
+      ),
+    is_call: is_far || instruction.is_call_near() || instruction.is_call_near_indirect(),
+    is_system: flow == FlowControl::Interrupt
+      || matches!(
+        instruction.mnemonic(),
+        Mnemonic::Syscall | Mnemonic::Sysenter
+      ),
+  })
+}
+
+/// Completes the innermost step of a thread, moving it back to the guarded
+/// page (or wherever the instruction branched to).
+unsafe fn end_step(context: *mut c_void) -> bool {
+  let tid = gettid();
+  let step = match STEPS
+    .iter()
+    .filter(|step| step.tid.load(Ordering::SeqCst) == tid)
+    .max_by_key(|step| step.depth.load(Ordering::SeqCst))
+  {
+    Some(step) => step,
+    None => return false,
+  };
+
+  let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+  gregs[REG_FLAGS] &= !TRAP_FLAG;
+
+  let copy = step.copy.load(Ordering::SeqCst);
+  let origin = step.origin.load(Ordering::SeqCst);
+  let len = step.len.load(Ordering::SeqCst);
+  if step.is_call.load(Ordering::SeqCst) {
+    *(gregs[REG_SP] as usize as *mut usize) = origin + len;
+  }
+  if step.is_relative.load(Ordering::SeqCst) {
+    let offset = (gregs[REG_IP] as usize).wrapping_sub(copy);
+    let offset = if step.is_system.load(Ordering::SeqCst) && (len..SLOT_SIZE).contains(&offset) {
+      len
+    } else {
+      offset
+    };
+    gregs[REG_IP] = origin.wrapping_add(offset) as greg_t;
+  }
+
+  step.tid.store(0, Ordering::SeqCst);
+  true
+}
+
+/// Abandons the innermost step of a thread if its copied instruction raised
+/// an exception, moving the thread back to the instruction, so the signal is
+/// reported as if it had been executed in place.
+unsafe fn abort_step(context: *mut c_void) {
+  let tid = gettid();
+  let step = match STEPS
+    .iter()
+    .filter(|step| step.tid.load(Ordering::SeqCst) == tid)
+    .max_by_key(|step| step.depth.load(Ordering::SeqCst))
+  {
+    Some(step) => step,
+    None => return,
+  };
+
+  let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+  let copy = step.copy.load(Ordering::SeqCst);
+  let offset = (gregs[REG_IP] as usize).wrapping_sub(copy);
+  if offset > step.len.load(Ordering::SeqCst) {
+    return;
+  }
+
+  gregs[REG_FLAGS] &= !TRAP_FLAG;
+  gregs[REG_IP] = (step.origin.load(Ordering::SeqCst) + offset) as greg_t;
+  step.tid.store(0, Ordering::SeqCst);
+}
+
+/// Passes a signal on to the previously installed handler, as if the signal
+/// had been delivered to it.
+///
+/// The handler already runs on the alternate signal stack (if any), and with
+/// the signal blocked, so only the previous handler's own mask is added.
+unsafe fn forward(
+  previous: &Previous,
+  signal: i32,
+  info: *mut libc::siginfo_t,
+  context: *mut c_void,
+) {
+  let previous = &*(*previous.0.get()).as_ptr();
+
+  if previous.sa_sigaction == libc::SIG_DFL {
+    // The signal is blocked within the handler, so the default action is
+    // taken once it returns
+    libc::sigaction(signal, previous, ptr::null_mut());
+    libc::raise(signal);
+    return;
+  } else if previous.sa_sigaction == libc::SIG_IGN {
+    return;
+  }
+
+  if previous.sa_flags & libc::SA_RESETHAND != 0 {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = libc::SIG_DFL;
+    libc::sigaction(signal, &action, ptr::null_mut());
+  }
+
+  let mut mask = MaybeUninit::<libc::sigset_t>::uninit();
+  libc::pthread_sigmask(libc::SIG_BLOCK, &previous.sa_mask, mask.as_mut_ptr());
+
+  if previous.sa_flags & libc::SA_SIGINFO != 0 {
+    let handler: extern "C" fn(i32, *mut libc::siginfo_t, *mut c_void) =
+      mem::transmute(previous.sa_sigaction);
+    handler(signal, info, context);
+  } else {
+    let handler: extern "C" fn(i32) = mem::transmute(previous.sa_sigaction);
+    handler(signal);
+  }
+
+  libc::pthread_sigmask(libc::SIG_SETMASK, mask.as_ptr(), ptr::null_mut());
+}
+
+/// Installs the signal handler used for breakpoints and steps.
+fn install_trap() -> Result<()> {
+  INSTALL_TRAP.call_once(|| {
+    let installed = unsafe { install_handler(libc::SIGTRAP, trap_handler, &PREVIOUS_TRAP) };
+    INSTALLED_TRAP.store(installed, Ordering::SeqCst);
+  });
+  if INSTALLED_TRAP.load(Ordering::SeqCst) {
+    Ok(())
+  } else {
+    Err(Error::TrapFailed)
+  }
+}
+
+/// Installs a signal handler, saving the previous one.
+unsafe fn install_handler(
+  signal: i32,
+  handler: extern "C" fn(i32, *mut libc::siginfo_t, *mut c_void),
+  previous: &Previous,
+) -> bool {
+  if libc::sigaction(signal, ptr::null(), (*previous.0.get()).as_mut_ptr()) != 0 {
+    return false;
+  }
+
+  let mut action: libc::sigaction = mem::zeroed();
+  action.sa_sigaction = handler as usize;
+  // A fault is also handled on a thread that overflowed its stack, if it has
+  // an alternate signal stack
+  action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+  libc::sigemptyset(&mut action.sa_mask);
+  libc::sigaction(signal, &action, ptr::null_mut()) == 0
+}
+
+/// Returns the ID of the calling thread.
+unsafe fn gettid() -> i32 {
+  libc::syscall(libc::SYS_gettid) as i32
+}
@@ -0,0 +1,33 @@
+//! Redirection of execution by signal handlers.
+//!
+//! A target patched with a breakpoint (`int3`) raises `SIGTRAP` whenever it
+//! is executed. The handler moves the interrupted thread to the destination
+//! registered for the breakpoint, and passes any other signal on to the
+//! previously installed handler.
+//!
+//! A guarded target instead has execute permission removed from its page,
+//! raising `SIGSEGV` whenever the page is executed. A thread executing the
+//! target is moved to its destination, whilst any other instruction of the
+//! page is copied to a slot of the thread and single-stepped there (using the
+//! trap flag, i.e `SIGTRAP`), so the page remains guarded for other threads.
+
+use cfg_if::cfg_if;
+
+cfg_if! {
+    if #[cfg(target_os = "linux")] {
+        mod linux;
+        pub use self::linux::{
+          is_guarded, redirect, redirect_guard, release, release_guard, reserve, reserve_guard,
+        };
+    } else {
+        mod unsupported;
+        pub use self::unsupported::{
+          is_guarded, redirect, redirect_guard, release, release_guard, reserve, reserve_guard,
+        };
+    }
+}
+
+/// Returns whether breakpoints and guards are supported on this platform.
+pub fn is_supported() -> bool {
+  cfg!(target_os = "linux")
+}
@@ -0,0 +1,23 @@
+use crate::error::{Error, Result};
+
+/// Breakpoints cannot be handled on this platform.
+pub fn reserve(_address: usize) -> Result<()> {
+  Err(Error::TrapFailed)
+}
+
+pub fn redirect(_address: usize, _destination: usize) {}
+
+pub fn release(_address: usize) {}
+
+/// Pages cannot be guarded on this platform.
+pub fn reserve_guard(_address: usize) -> Result<()> {
+  Err(Error::TrapFailed)
+}
+
+pub fn redirect_guard(_address: usize, _destination: Option<usize>) {}
+
+pub fn release_guard(_address: usize) {}
+
+pub fn is_guarded(_address: usize) -> bool {
+  false
+}
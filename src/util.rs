@@ -1,10 +1,151 @@
-use crate::error::Result;
+use crate::error::{Error, Result};
+use std::sync::{Mutex, MutexGuard, PoisonError};
 
 /// Returns true if an address is executable.
+///
+/// A page guarded by a detour is executable, despite its protection.
 pub fn is_executable_address(address: *const ()) -> Result<bool> {
   Ok(
-    region::query(address as *const _)?
-      .protection()
-      .contains(region::Protection::EXECUTE),
+    crate::trap::is_guarded(address as usize)
+      || region::query(address as *const _)?
+        .protection()
+        .contains(region::Protection::EXECUTE),
   )
 }
+
+/// Locks a mutex, ignoring whether another thread panicked whilst holding it.
+///
+/// The guarded state is never left inconsistent by a panic, and panicking
+/// here would take down the host process of an injected library.
+pub fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+  mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Reads the code of a function, up to `len` bytes at an address within it.
+///
+/// The read stops at the end of the readable memory, and at the end of the
+/// function if its symbol is known.
+pub unsafe fn read_code(address: usize, len: usize) -> Result<Vec<u8>> {
+  let len = symbol_end(address).map_or(len, |end| len.min(end - address));
+  read_memory(address, len)
+}
+
+/// Reads up to `len` bytes at an address, stopping at the end of the readable
+/// memory. An error is returned if the address itself cannot be read.
+pub unsafe fn read_memory(address: usize, len: usize) -> Result<Vec<u8>> {
+  // Find the end of the contiguous readable regions
+  let mut end = address;
+  let regions = region::query_range(address as *const u8, len.max(1)).map_err(|error| {
+    if is_unmapped(&error) {
+      Error::UnreadableAddress { address }
+    } else {
+      error.into()
+    }
+  })?;
+  for region in regions {
+    let region = match region {
+      Ok(region) => region,
+      // The readable memory ends before an unmapped region
+      Err(error) if is_unmapped(&error) => break,
+      Err(error) => Err(error)?,
+    };
+    let range = region.as_range();
+    if range.start > end || !region.protection().contains(region::Protection::READ) {
+      break;
+    }
+    end = range.end;
+  }
+
+  if end <= address {
+    Err(Error::UnreadableAddress { address })?;
+  }
+
+  let mut buffer = vec![0; len.min(end - address)];
+  copy_memory(address, &mut buffer)?;
+  Ok(buffer)
+}
+
+/// Returns whether a memory query failed since the address is unmapped.
+fn is_unmapped(error: &region::Error) -> bool {
+  match error {
+    region::Error::UnmappedRegion => true,
+    region::Error::SystemCall(error) => error.raw_os_error() == Some(libc::ENOMEM),
+    _ => false,
+  }
+}
+
+/// Copies memory, returning an error instead of faulting if it is unmapped
+/// in the meantime.
+#[cfg(target_os = "linux")]
+unsafe fn copy_memory(address: usize, buffer: &mut [u8]) -> Result<()> {
+  let local = libc::iovec {
+    iov_base: buffer.as_mut_ptr() as *mut _,
+    iov_len: buffer.len(),
+  };
+  let remote = libc::iovec {
+    iov_base: address as *mut _,
+    iov_len: buffer.len(),
+  };
+
+  let read = libc::process_vm_readv(libc::getpid(), &local, 1, &remote, 1, 0);
+  if read == buffer.len() as isize {
+    return Ok(());
+  }
+
+  // The system call may be unavailable or forbidden (e.g by seccomp), in
+  // which case the memory is known to be readable from its protection.
+  match std::io::Error::last_os_error().raw_os_error() {
+    Some(libc::ENOSYS) | Some(libc::EPERM) if read < 0 => {
+      std::ptr::copy_nonoverlapping(address as *const u8, buffer.as_mut_ptr(), buffer.len());
+      Ok(())
+    },
+    _ => Err(Error::UnreadableAddress { address }),
+  }
+}
+
+/// Copies memory known to be readable from its protection.
+#[cfg(not(target_os = "linux"))]
+unsafe fn copy_memory(address: usize, buffer: &mut [u8]) -> Result<()> {
+  std::ptr::copy_nonoverlapping(address as *const u8, buffer.as_mut_ptr(), buffer.len());
+  Ok(())
+}
+
+/// Returns the end of the function containing an address, if its symbol is
+/// known to the dynamic linker.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+pub fn symbol_end(address: usize) -> Option<usize> {
+  use std::{mem, ptr};
+
+  // Requests the symbol's ELF entry from `dladdr1`
+  const RTLD_DL_SYMENT: i32 = 1;
+  #[cfg(target_pointer_width = "64")]
+  type Symbol = libc::Elf64_Sym;
+  #[cfg(target_pointer_width = "32")]
+  type Symbol = libc::Elf32_Sym;
+
+  let mut info = mem::MaybeUninit::<libc::Dl_info>::zeroed();
+  let mut symbol: *mut Symbol = ptr::null_mut();
+  let found = unsafe {
+    libc::dladdr1(
+      address as *const _,
+      info.as_mut_ptr(),
+      &mut symbol as *mut *mut Symbol as *mut *mut libc::c_void,
+      RTLD_DL_SYMENT,
+    )
+  };
+  if found == 0 || symbol.is_null() {
+    return None;
+  }
+
+  // The closest symbol does not necessarily contain the address
+  let start = unsafe { info.assume_init() }.dli_saddr as usize;
+  let size = unsafe { (*symbol).st_size } as usize;
+  Some(start + size).filter(|&end| size > 0 && (start..end).contains(&address))
+}
+
+/// Returns the end of the function containing an address, if its symbol is
+/// known to the dynamic linker.
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+pub fn symbol_end(_address: usize) -> Option<usize> {
+  None
+}
@@ -48,6 +48,112 @@ mod raw {
     }
     Ok(())
   }
+
+  #[test]
+  fn original_as() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+    }
+
+    unsafe {
+      let hook = RawDetour::new(mul as *const (), sub_detour as *const ())?;
+      assert_eq!(hook.trampoline_ptr(), hook.trampoline() as *const ());
+
+      hook.enable()?;
+      let original = hook.original_as::<FnAdd>();
+      assert_eq!(original(10, 5), 50);
+      assert_eq!(mul(10, 5), 5);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn drop_while_executing() -> Result<()> {
+    use retour::DetourOptions;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    static TRAMPOLINE: AtomicUsize = AtomicUsize::new(0);
+    static STAGE: AtomicUsize = AtomicUsize::new(0);
+
+    #[inline(never)]
+    extern "C" fn div(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) / y }
+    }
+
+    #[inline(never)]
+    extern "C" fn rem(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) % y }
+    }
+
+    /// Calls the original function only once the hook has been dropped.
+    extern "C" fn div_detour(x: i32, y: i32) -> i32 {
+      STAGE.store(1, Ordering::SeqCst);
+      while STAGE.load(Ordering::SeqCst) != 2 {
+        thread::yield_now();
+      }
+
+      let original: FnAdd = unsafe { mem::transmute(TRAMPOLINE.load(Ordering::SeqCst)) };
+      original(x, y) + 100
+    }
+
+    // The call is tracked until it returns, rather than whilst executing the
+    // trampoline
+    let options = DetourOptions::new().track_returns(true);
+    let hook =
+      unsafe { RawDetour::with_options(div as *const (), div_detour as *const (), &options)? };
+    TRAMPOLINE.store(hook.trampoline_ptr() as usize, Ordering::SeqCst);
+    unsafe { hook.enable()? };
+
+    let caller = thread::spawn(|| div(10, 5));
+    while STAGE.load(Ordering::SeqCst) != 1 {
+      thread::yield_now();
+    }
+
+    // The trampoline is kept whilst the call is in flight, so another detour
+    // cannot reuse its memory
+    drop(hook);
+    let other = unsafe { RawDetour::new(rem as *const (), sub_detour as *const ())? };
+    unsafe { other.enable()? };
+
+    STAGE.store(2, Ordering::SeqCst);
+    assert_eq!(caller.join().unwrap(), 102);
+    assert_eq!(div(10, 5), 2);
+    assert_eq!(rem(10, 5), 5);
+    Ok(())
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn track_deep_recursion() -> Result<()> {
+    use retour::DetourOptions;
+    use std::time::Duration;
+
+    #[inline(never)]
+    extern "C" fn countdown(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    /// Recurses through the target, beyond the tracked depth.
+    extern "C" fn countdown_detour(x: i32, y: i32) -> i32 {
+      if x == 0 {
+        y
+      } else {
+        countdown(x - 1, y + 1)
+      }
+    }
+
+    let options = DetourOptions::new().track_returns(true);
+    let hook = unsafe {
+      RawDetour::with_options(countdown as *const (), countdown_detour as *const (), &options)?
+    };
+    unsafe { hook.enable()? };
+    assert_eq!(countdown(100, 0), 100);
+
+    // Calls beyond the tracked depth are not left in flight
+    unsafe { hook.disable_and_wait(Duration::from_secs(1)) }
+  }
 }
 
 mod generic {
@@ -78,12 +184,270 @@ mod generic {
     }
     Ok(())
   }
+
+  #[test]
+  fn original() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+    }
+
+    unsafe {
+      let hook = GenericDetour::<FnAdd>::new(mul, sub_detour)?;
+      hook.enable()?;
+
+      let original = hook.original();
+      assert_eq!(original(10, 5), 50);
+      assert_eq!(mul(10, 5), 5);
+    }
+    Ok(())
+  }
+}
+
+mod closure {
+  use super::*;
+  use retour::ClosureDetour;
+
+  #[test]
+  fn test() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn add(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    #[inline(never)]
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+    }
+
+    #[inline(never)]
+    extern "C" fn scale(x: f64, y: f64, z: f64, factor: i32) -> f64 {
+      unsafe { std::ptr::read_volatile(&x as *const f64) * y * z * f64::from(factor) }
+    }
+
+    unsafe {
+      // Any number of targets can be hooked with capturing closures
+      let hooks = [(add as FnAdd, 100), (mul as FnAdd, 1000)]
+        .iter()
+        .map(|&(target, offset)| {
+          ClosureDetour::<FnAdd>::new(target, move |original, x, y| original(x, y) + offset)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+      assert_eq!(add(10, 5), 15);
+      for hook in &hooks {
+        hook.enable()?;
+      }
+      assert_eq!(add(10, 5), 115);
+      assert_eq!(mul(10, 5), 1050);
+      assert_eq!(hooks[0].call(10, 5), 15);
+      assert_eq!(hooks[1].call(10, 5), 50);
+
+      // Floating-point arguments are passed on intact
+      let hook = ClosureDetour::<extern "C" fn(f64, f64, f64, i32) -> f64>::new(
+        scale,
+        |original, x, y, z, factor| original(x, y, z, factor) + 1.0,
+      )?;
+      hook.enable()?;
+      assert_eq!(scale(2.0, 3.0, 4.0, 5), 121.0);
+
+      drop(hooks);
+      assert_eq!(add(10, 5), 15);
+      assert_eq!(mul(10, 5), 50);
+    }
+    Ok(())
+  }
+
+  #[test]
+  fn drop_while_executing() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Barrier};
+
+    #[inline(never)]
+    extern "C" fn sub(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) - y }
+    }
+
+    /// Records when the closure is released.
+    struct Released(Arc<AtomicBool>);
+
+    impl Drop for Released {
+      fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+      }
+    }
+
+    let released = Arc::new(AtomicBool::new(false));
+    let barrier = Arc::new(Barrier::new(2));
+
+    let hook = unsafe {
+      let marker = Released(released.clone());
+      let barrier = barrier.clone();
+      ClosureDetour::<FnAdd>::new(sub, move |original, x, y| {
+        let _ = &marker;
+        barrier.wait();
+        barrier.wait();
+        original(x, y) + 100
+      })?
+    };
+    unsafe { hook.enable()? };
+
+    let caller = std::thread::spawn(|| sub(10, 5));
+    barrier.wait();
+
+    // The closure is kept whilst the other thread executes it
+    drop(hook);
+    assert!(!released.load(Ordering::SeqCst));
+    barrier.wait();
+    assert_eq!(caller.join().unwrap(), 105);
+    assert_eq!(sub(10, 5), 5);
+
+    // It is released by a subsequent reclamation
+    let hook = unsafe { ClosureDetour::<FnAdd>::new(sub, |original, x, y| original(x, y))? };
+    drop(hook);
+    assert!(released.load(Ordering::SeqCst));
+    Ok(())
+  }
+
+  #[test]
+  fn drop_tracked() -> Result<()> {
+    use retour::DetourOptions;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    #[inline(never)]
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+    }
+
+    /// Records when the closure is released.
+    struct Released(Arc<AtomicBool>);
+
+    impl Drop for Released {
+      fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+      }
+    }
+
+    let released = Arc::new(AtomicBool::new(false));
+    let options = DetourOptions::new().track_returns(true);
+    let hook = unsafe {
+      let marker = Released(released.clone());
+      ClosureDetour::<FnAdd>::with_options(
+        mul,
+        move |original, x, y| {
+          let _ = &marker;
+          original(x, y) + 1
+        },
+        &options,
+      )?
+    };
+    unsafe { hook.enable()? };
+    assert_eq!(mul(10, 5), 51);
+
+    // No tracked call remains once the target has returned
+    drop(hook);
+    assert!(released.load(Ordering::SeqCst));
+    Ok(())
+  }
+}
+
+mod transaction {
+  use super::*;
+  use retour::{DetourTransaction, GenericDetour, RawDetour};
+
+  #[test]
+  fn test() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn add(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    #[inline(never)]
+    extern "C" fn mul(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) * y }
+    }
+
+    unsafe {
+      let add_hook = GenericDetour::<FnAdd>::new(add, sub_detour)?;
+      let mul_hook = RawDetour::new(mul as *const (), sub_detour as *const ())?;
+
+      let mut transaction = DetourTransaction::new();
+      transaction.enable(&add_hook).enable(&mul_hook);
+      transaction.commit()?;
+      {
+        assert!(add_hook.is_enabled() && mul_hook.is_enabled());
+        assert_eq!(add(10, 5), 5);
+        assert_eq!(mul(10, 5), 5);
+      }
+
+      // Only the last staged change of a detour is applied
+      let mut transaction = DetourTransaction::new();
+      transaction
+        .disable(&add_hook)
+        .enable(&mul_hook)
+        .disable(&mul_hook);
+      transaction.commit()?;
+
+      assert!(!add_hook.is_enabled() && !mul_hook.is_enabled());
+      assert_eq!(add(10, 5), 15);
+      assert_eq!(mul(10, 5), 50);
+    }
+    Ok(())
+  }
+}
+
+#[cfg(target_os = "linux")]
+mod teardown {
+  use super::*;
+  use retour::GenericDetour;
+  use std::sync::atomic::{AtomicBool, Ordering};
+  use std::sync::Arc;
+  use std::thread;
+  use std::time::Duration;
+
+  #[test]
+  fn test() -> Result<()> {
+    #[inline(never)]
+    extern "C" fn add(x: i32, y: i32) -> i32 {
+      unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+    }
+
+    let running = Arc::new(AtomicBool::new(true));
+    let workers = (0..4)
+      .map(|_| {
+        let running = running.clone();
+        thread::spawn(move || {
+          while running.load(Ordering::SeqCst) {
+            let result = add(10, 5);
+            assert!(result == 15 || result == 5);
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+
+    // Hooks are released whilst the target is being called
+    let result = (0..25).try_for_each(|_| unsafe {
+      let hook = GenericDetour::<FnAdd>::new(add, sub_detour)?;
+      hook.enable()?;
+      assert_eq!(hook.call(10, 5), 15);
+      hook.disable_and_wait(Duration::from_secs(1))
+    });
+
+    running.store(false, Ordering::SeqCst);
+    for worker in workers {
+      worker.join().unwrap();
+    }
+
+    result?;
+    assert_eq!(add(10, 5), 15);
+    Ok(())
+  }
 }
 
-#[cfg(feature = "static-detour")]
 mod statik {
   use super::*;
-  use retour::static_detour;
+  use retour::{static_detour, StaticDetour};
 
   #[inline(never)]
   unsafe extern "C" fn add(x: i32, y: i32) -> i32 {
@@ -117,6 +481,247 @@ mod statik {
     }
     Ok(())
   }
+
+  #[inline(never)]
+  fn double(x: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) * 2 }
+  }
+
+  static_detour! {
+    static DetourDouble: fn(i32) -> i32;
+  }
+
+  #[test]
+  fn original() -> Result<()> {
+    unsafe {
+      assert!(DetourDouble.original().is_err());
+      DetourDouble.initialize(double, |x| x)?.enable()?;
+
+      let original = DetourDouble.original()?;
+      assert_eq!(original(5), 10);
+      assert_eq!(double(5), 5);
+
+      DetourDouble.reset()?;
+      assert!(DetourDouble.original().is_err());
+    }
+    Ok(())
+  }
+
+  #[inline(never)]
+  fn identity(x: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) }
+  }
+
+  static_detour! {
+    static DetourIdentity: fn(i32) -> i32;
+  }
+
+  #[test]
+  fn set_detour_concurrently() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::thread;
+
+    /// Counts the closures that have been released.
+    struct Release(Arc<AtomicUsize>);
+
+    impl Drop for Release {
+      fn drop(&mut self) {
+        self.0.fetch_add(1, Ordering::SeqCst);
+      }
+    }
+
+    let released = Arc::new(AtomicUsize::new(0));
+    let detour = |offset: i32| {
+      let release = Release(released.clone());
+      let offset = Box::new(offset);
+      move |x: i32| {
+        let _ = &release;
+        x + *offset
+      }
+    };
+
+    unsafe { DetourIdentity.initialize(identity, detour(1))?.enable()? };
+
+    // The closures are replaced whilst other threads are executing them
+    let stop = Arc::new(AtomicBool::new(false));
+    let callers = (0..4)
+      .map(|_| {
+        let stop = stop.clone();
+        thread::spawn(move || {
+          while !stop.load(Ordering::SeqCst) {
+            let result = identity(0);
+            assert!((1..=100).contains(&result));
+          }
+        })
+      })
+      .collect::<Vec<_>>();
+
+    for offset in 2..=100 {
+      DetourIdentity.set_detour(detour(offset));
+    }
+
+    stop.store(true, Ordering::SeqCst);
+    for caller in callers {
+      caller.join().unwrap();
+    }
+
+    // Without any calls, all replaced closures are released
+    DetourIdentity.set_detour(detour(1));
+    assert_eq!(released.load(Ordering::SeqCst), 100);
+    assert_eq!(identity(0), 1);
+
+    unsafe { DetourIdentity.disable()? };
+    Ok(())
+  }
+
+  #[inline(never)]
+  fn decrement(x: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) - 1 }
+  }
+
+  static_detour! {
+    static DetourDecrement: fn(i32) -> i32;
+  }
+
+  #[test]
+  fn reclaim_with_overlapping_calls() -> Result<()> {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Barrier};
+    use std::thread;
+
+    /// Records when a closure is released.
+    struct Released(Arc<AtomicBool>);
+
+    impl Drop for Released {
+      fn drop(&mut self) {
+        self.0.store(true, Ordering::SeqCst);
+      }
+    }
+
+    // Each closure waits until its call is released by the test
+    let detour = |offset: i32| {
+      let released = Arc::new(AtomicBool::new(false));
+      let barrier = Arc::new(Barrier::new(2));
+      let marker = Released(released.clone());
+      let gate = barrier.clone();
+      let closure = move |x: i32| {
+        let _ = &marker;
+        gate.wait();
+        gate.wait();
+        x + offset
+      };
+      (closure, released, barrier)
+    };
+
+    let (first, first_released, first_call) = detour(1);
+    let (second, second_released, second_call) = detour(2);
+    unsafe { DetourDecrement.initialize(decrement, first)?.enable()? };
+
+    let first_caller = thread::spawn(|| decrement(0));
+    first_call.wait();
+    DetourDecrement.set_detour(second);
+
+    let second_caller = thread::spawn(|| decrement(0));
+    second_call.wait();
+
+    // The replaced closure is not released within its last call
+    first_call.wait();
+    assert_eq!(first_caller.join().unwrap(), 1);
+    assert!(!first_released.load(Ordering::SeqCst));
+
+    // It is released by the next change, even though another call is still
+    // in progress
+    DetourDecrement.set_detour(|x| x + 3);
+    assert!(first_released.load(Ordering::SeqCst));
+    assert!(!second_released.load(Ordering::SeqCst));
+
+    second_call.wait();
+    assert_eq!(second_caller.join().unwrap(), 2);
+
+    unsafe { DetourDecrement.reset()? };
+    assert!(second_released.load(Ordering::SeqCst));
+    assert_eq!(decrement(0), -1);
+    Ok(())
+  }
+
+  #[inline(never)]
+  fn negate(x: i32) -> i32 {
+    unsafe { -std::ptr::read_volatile(&x as *const i32) }
+  }
+
+  static_detour! {
+    static DetourReset: fn(i32) -> i32;
+  }
+
+  #[test]
+  fn reset() -> Result<()> {
+    unsafe {
+      DetourReset.initialize(negate, |x| x + 1)?.enable()?;
+      assert_eq!(negate(5), 6);
+      assert!(DetourReset.initialize(negate, |x| x).is_err());
+
+      DetourReset.reset()?;
+      assert!(!DetourReset.is_initialized());
+      assert!(!DetourReset.is_enabled());
+      assert_eq!(negate(5), -5);
+      assert_eq!(DetourReset.call(5), -5);
+      assert!(DetourReset.reset().is_err());
+
+      // The same static can detour another target once reset
+      DetourReset.initialize(identity, |x| x * 2)?.enable()?;
+      assert_eq!(identity(5), 10);
+      assert_eq!(negate(5), -5);
+      assert_eq!(DetourReset.call(5), 5);
+
+      DetourReset.reset()?;
+      assert_eq!(identity(5), 5);
+    }
+    Ok(())
+  }
+
+  #[inline(never)]
+  fn square(x: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) * x }
+  }
+
+  #[inline(never)]
+  fn halve(x: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) / 2 }
+  }
+
+  static_detour! {
+    static DetourSquare: fn(i32) -> i32;
+    static DetourHalve: fn(i32) -> i32;
+  }
+
+  /// Installs the same detour on any static, without naming it.
+  unsafe fn increment(detour: &'static StaticDetour<fn(i32) -> i32>, target: fn(i32) -> i32) -> Result<()> {
+    detour
+      .initialize_with_original(target, |original, x| original.call(x) + 1)?
+      .enable()
+  }
+
+  #[test]
+  fn with_original() -> Result<()> {
+    unsafe {
+      increment(&DetourSquare, square)?;
+      increment(&DetourHalve, halve)?;
+    }
+
+    assert_eq!(square(3), 10);
+    assert_eq!(halve(8), 5);
+
+    DetourHalve.set_detour_with_original(|original, x| original.call(x) * original.call(x));
+    assert_eq!(halve(8), 16);
+    assert_eq!(square(3), 10);
+
+    unsafe {
+      DetourSquare.disable()?;
+      DetourHalve.disable()?;
+    }
+    Ok(())
+  }
 }
 
 #[cfg(feature = "28-args")]
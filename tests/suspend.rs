@@ -0,0 +1,54 @@
+//! Thread suspension is a process-wide setting, so it is tested in a binary of
+//! its own, apart from the tests it would otherwise affect.
+#![cfg(target_os = "linux")]
+
+use retour::{GenericDetour, Result};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+type FnAdd = extern "C" fn(i32, i32) -> i32;
+
+#[inline(never)]
+extern "C" fn sub_detour(x: i32, y: i32) -> i32 {
+  unsafe { std::ptr::read_volatile(&x as *const i32) - y }
+}
+
+#[test]
+fn test() -> Result<()> {
+  #[inline(never)]
+  extern "C" fn add(x: i32, y: i32) -> i32 {
+    unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+  }
+
+  let hook = unsafe { GenericDetour::<FnAdd>::new(add, sub_detour)? };
+  let running = Arc::new(AtomicBool::new(true));
+
+  // Call the target continuously whilst it is being patched
+  let workers = (0..4)
+    .map(|_| {
+      let running = running.clone();
+      thread::spawn(move || {
+        while running.load(Ordering::SeqCst) {
+          let result = add(10, 5);
+          assert!(result == 15 || result == 5);
+        }
+      })
+    })
+    .collect::<Vec<_>>();
+
+  retour::set_thread_suspension(true);
+  let result = (0..25).try_for_each(|_| unsafe {
+    hook.enable()?;
+    assert_eq!(hook.call(10, 5), 15);
+    hook.disable()
+  });
+  retour::set_thread_suspension(false);
+
+  running.store(false, Ordering::SeqCst);
+  for worker in workers {
+    worker.join().unwrap();
+  }
+
+  result
+}
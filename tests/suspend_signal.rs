@@ -0,0 +1,45 @@
+//! The signal used for thread suspension is installed once per process, so a
+//! conflicting handler is tested in a binary of its own.
+#![cfg(target_os = "linux")]
+
+use matches::assert_matches;
+use retour::{Error, GenericDetour, Result};
+use std::{mem, ptr};
+
+type FnAdd = extern "C" fn(i32, i32) -> i32;
+
+#[inline(never)]
+extern "C" fn add(x: i32, y: i32) -> i32 {
+  unsafe { std::ptr::read_volatile(&x as *const i32) + y }
+}
+
+#[inline(never)]
+extern "C" fn sub_detour(x: i32, y: i32) -> i32 {
+  unsafe { std::ptr::read_volatile(&x as *const i32) - y }
+}
+
+extern "C" fn existing_handler(_signal: i32) {}
+
+#[test]
+fn existing_handler_is_kept() -> Result<()> {
+  let signal = libc::SIGRTMIN() + 7;
+  unsafe {
+    let mut action: libc::sigaction = mem::zeroed();
+    action.sa_sigaction = existing_handler as extern "C" fn(i32) as usize;
+    libc::sigaction(signal, &action, ptr::null_mut());
+  }
+
+  let hook = unsafe { GenericDetour::<FnAdd>::new(add, sub_detour)? };
+  retour::set_thread_suspension(true);
+  let result = unsafe { hook.enable() };
+  retour::set_thread_suspension(false);
+  assert_matches!(result, Err(Error::SuspendFailed));
+
+  let mut action: libc::sigaction = unsafe { mem::zeroed() };
+  unsafe { libc::sigaction(signal, ptr::null(), &mut action) };
+  assert_eq!(
+    action.sa_sigaction,
+    existing_handler as extern "C" fn(i32) as usize
+  );
+  Ok(())
+}